@@ -0,0 +1,802 @@
+//! An assembler for the syntax the disassembler writes: Cowgod's mnemonics with `Vx`
+//! registers and `#`-prefixed hex, plus labels, data, constants and expressions.
+//!
+//! ```text
+//! SPEED = 2                   ; a constant, `SPEED equ 2` works too
+//! start:
+//!     LD V0, SPEED * 4
+//!     LD I, sprite
+//!     DRW V0, V1, sprite_end - sprite
+//!     JP start
+//! sprite:
+//!     db 0b11110000, #90, $90, 0x90, 240
+//! sprite_end:
+//! ```
+//!
+//! Numbers are decimal, hex with `#`, `$` or `0x`, or binary with `0b`. Expressions
+//! have the usual operators (`+ - * / % & | ^ << >> ~`) and parentheses, and can use
+//! labels and constants defined anywhere in the source. `db` writes bytes (and
+//! strings in double quotes), `dw` big endian words, and `org` skips ahead to an
+//! address. Lines of a disassembly listing assemble too, their address and bytes
+//! columns are skipped.
+
+pub mod octo;
+
+use crate::instruction::Instruction;
+use crate::symbols::Symbols;
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+
+// Programs are loaded here, so that's where the first byte goes.
+const PROGRAM_START: u32 = 0x200;
+
+// The most memory a machine has (XO-CHIP).
+const MEMORY_END: u32 = 0x10000;
+
+/// An assembled program.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Assembly {
+    pub bytes: Vec<u8>, // Loaded at 0x200
+    pub labels: BTreeMap<String, u16>,
+    pub breakpoints: Vec<(u16, String)>, // Octo's `:breakpoint`s, with their names
+    rows: Vec<Row>,
+}
+
+// One line of the listing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Row {
+    addr: Option<u16>, // None for lines that don't take up any space
+    bytes: Vec<u8>,
+    source: String,
+}
+
+impl Assembly {
+    /// The labels and breakpoints, for the symbol file.
+    pub fn symbols(&self) -> Symbols {
+        let mut symbols = Symbols::new();
+
+        for (name, &addr) in &self.labels {
+            symbols.add_label(name, addr);
+        }
+
+        for (addr, name) in &self.breakpoints {
+            symbols.add_breakpoint(name, *addr);
+        }
+
+        symbols
+    }
+
+    /// The source with the address and bytes of every line in front of it. Lines with
+    /// more than four bytes carry on below.
+    pub fn listing(&self) -> String {
+        let words = |chunk: &[u8]| -> String {
+            let words: Vec<String> = chunk.chunks(2)
+                .map(|word| word.iter().map(|byte| format!("{:02X}", byte)).collect())
+                .collect();
+            words.join(" ")
+        };
+
+        let mut out = String::new();
+
+        for row in &self.rows {
+            let addr = row.addr.map_or(String::from("    "), |addr| format!("{:04X}", addr));
+            let mut chunks = row.bytes.chunks(4);
+            let first = chunks.next().map(words).unwrap_or_default();
+
+            out.push_str(format!("{}  {:<9}  {}", addr, first, row.source).trim_end());
+            out.push('\n');
+
+            for (idx, chunk) in chunks.enumerate() {
+                let addr = row.addr.unwrap_or_default() as usize + 4 * (idx + 1);
+                out.push_str(&format!("{:04X}  {}\n", addr, words(chunk)));
+            }
+        }
+
+        out
+    }
+}
+
+/// Assembles the source into a program. Errors name the line they're on.
+pub fn assemble(source: &str) -> Result<Assembly, String> {
+    let mut assembler = Assembler { addr: PROGRAM_START, ..Default::default() };
+
+    for (idx, text) in source.lines().enumerate() {
+        assembler.read_line(idx + 1, text).map_err(|err| format!("{} on line {}", err, idx + 1))?;
+    }
+
+    assembler.finish()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Expr {
+    Number(i64),
+    Symbol(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Operand {
+    V(u8),
+    Range(u8, u8), // `V1 - V4`
+    I,
+    IndirectI, // `[I]`
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+    Long(Expr), // `LONG addr`, the word after `F000`
+    Value(Expr),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Item {
+    Value(Expr),
+    Text(Vec<u8>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    Nothing,
+    Instruction(String, Vec<Operand>),
+    Bytes(Vec<Item>),
+    Words(Vec<Expr>),
+}
+
+struct Statement {
+    line: usize,
+    addr: u32,
+    labelled: bool, // Shows its address in the listing even if it's empty
+    kind: Kind,
+    source: String,
+}
+
+#[derive(Default)]
+struct Assembler {
+    statements: Vec<Statement>,
+    labels: BTreeMap<String, u16>,
+    constants: HashMap<String, Expr>,
+    addr: u32,
+}
+
+impl Assembler {
+    // First pass: parses the line and works out its address, so every label is known
+    // before anything is encoded.
+    fn read_line(&mut self, line: usize, text: &str) -> Result<(), String> {
+        let code = strip_listing(strip_comment(text));
+        let tokens = tokenize(code)?;
+        let mut tokens = tokens.as_slice();
+        let mut labelled = false;
+
+        while let [name, colon, rest @ ..] = tokens {
+            if colon != ":" || !is_symbol(name) {
+                break;
+            }
+
+            let addr = u16::try_from(self.addr).map_err(|_| String::from("The program doesn't fit in memory"))?;
+            self.define(name)?;
+            self.labels.insert(name.clone(), addr);
+            labelled = true;
+            tokens = rest;
+        }
+
+        let kind = match tokens {
+            [] => Kind::Nothing,
+            [name, eq, value @ ..] if is_symbol(name) && (eq == "=" || eq.eq_ignore_ascii_case("equ")) => {
+                self.define(name)?;
+                self.constants.insert(name.clone(), parse_expr(value)?);
+                Kind::Nothing
+            }
+            [mnemonic, rest @ ..] => {
+                let operands = split_operands(rest);
+
+                match mnemonic.to_uppercase().as_str() {
+                    "DB" => Kind::Bytes(operands.iter().map(|tokens| parse_item(tokens)).collect::<Result<_, _>>()?),
+                    "DW" => Kind::Words(operands.iter().map(|tokens| parse_expr(tokens)).collect::<Result<_, _>>()?),
+                    "ORG" => {
+                        let target = match operands.as_slice() {
+                            [tokens] => self.eval(&parse_expr(tokens)?, 0)?,
+                            _ => return Err(String::from("org expects an address")),
+                        };
+
+                        if target < self.addr as i64 || target > MEMORY_END as i64 {
+                            return Err(format!("Can't org to {:#x} from {:#x}", target, self.addr));
+                        }
+
+                        self.addr = target as u32;
+                        Kind::Nothing
+                    }
+                    upper => {
+                        let operands = operands.iter().map(|tokens| parse_operand(tokens)).collect::<Result<_, _>>()?;
+                        Kind::Instruction(upper.to_string(), operands)
+                    }
+                }
+            }
+        };
+
+        let size = match &kind {
+            Kind::Nothing => 0,
+            Kind::Instruction(_, operands) if operands.iter().any(|op| matches!(op, Operand::Long(_))) => 4,
+            Kind::Instruction(..) => 2,
+            Kind::Bytes(items) => items.iter()
+                .map(|item| match item {
+                    Item::Value(_) => 1,
+                    Item::Text(text) => text.len() as u32,
+                })
+                .sum(),
+            Kind::Words(words) => 2 * words.len() as u32,
+        };
+
+        self.statements.push(Statement { line, addr: self.addr, labelled, kind, source: text.to_string() });
+        self.addr += size;
+
+        if self.addr > MEMORY_END {
+            return Err(String::from("The program doesn't fit in memory"));
+        }
+
+        Ok(())
+    }
+
+    fn define(&self, name: &str) -> Result<(), String> {
+        if self.labels.contains_key(name) || self.constants.contains_key(name) {
+            return Err(format!("'{}' is defined twice", name));
+        }
+
+        Ok(())
+    }
+
+    // Second pass: encodes every statement now that all the symbols are known.
+    fn finish(self) -> Result<Assembly, String> {
+        let mut memory = Vec::new();
+        let mut rows = Vec::new();
+
+        for statement in &self.statements {
+            let bytes = self.encode(&statement.kind)
+                .map_err(|err| format!("{} on line {}", err, statement.line))?;
+
+            let start = (statement.addr - PROGRAM_START) as usize;
+            if !bytes.is_empty() {
+                if memory.len() < start + bytes.len() {
+                    memory.resize(start + bytes.len(), 0);
+                }
+                memory[start..start + bytes.len()].copy_from_slice(&bytes);
+            }
+
+            let shows_addr = statement.labelled || !bytes.is_empty();
+            rows.push(Row {
+                addr: shows_addr.then_some(statement.addr as u16),
+                bytes,
+                source: statement.source.clone(),
+            });
+        }
+
+        Ok(Assembly { bytes: memory, labels: self.labels, breakpoints: Vec::new(), rows })
+    }
+
+    fn encode(&self, kind: &Kind) -> Result<Vec<u8>, String> {
+        match kind {
+            Kind::Nothing => Ok(Vec::new()),
+            Kind::Bytes(items) => {
+                let mut out = Vec::new();
+                for item in items {
+                    match item {
+                        Item::Value(expr) => out.push(self.byte(expr)?),
+                        Item::Text(text) => out.extend_from_slice(text),
+                    }
+                }
+                Ok(out)
+            }
+            Kind::Words(words) => {
+                let mut out = Vec::new();
+                for expr in words {
+                    out.extend_from_slice(&self.word(expr)?.to_be_bytes());
+                }
+                Ok(out)
+            }
+            Kind::Instruction(mnemonic, operands) => self.instruction(mnemonic, operands),
+        }
+    }
+
+    fn instruction(&self, mnemonic: &str, operands: &[Operand]) -> Result<Vec<u8>, String> {
+        use Operand::*;
+
+        let instruction = match (mnemonic, operands) {
+            ("CLS", []) => Instruction::Cls,
+            ("RET", []) => Instruction::Ret,
+            ("SCR", []) => Instruction::Scr,
+            ("SCL", []) => Instruction::Scl,
+            ("EXIT", []) => Instruction::Exit,
+            ("LOW", []) => Instruction::Low,
+            ("HIGH", []) => Instruction::High,
+            ("AUDIO", []) => Instruction::Audio,
+            ("SCD", [Value(n)]) => Instruction::Scd(self.nibble(n)?),
+            ("SCU", [Value(n)]) => Instruction::Scu(self.nibble(n)?),
+            ("SYS", [Value(nnn)]) => Instruction::Sys(self.addr(nnn)?),
+            ("JP", [Value(nnn)]) => Instruction::Jp(self.addr(nnn)?),
+            ("JP", [V(0), Value(nnn)]) => Instruction::JpV0(self.addr(nnn)?),
+            ("CALL", [Value(nnn)]) => Instruction::Call(self.addr(nnn)?),
+            ("SE", [V(x), V(y)]) => Instruction::SeReg(*x, *y),
+            ("SE", [V(x), Value(kk)]) => Instruction::SeByte(*x, self.byte(kk)?),
+            ("SNE", [V(x), V(y)]) => Instruction::SneReg(*x, *y),
+            ("SNE", [V(x), Value(kk)]) => Instruction::SneByte(*x, self.byte(kk)?),
+            ("LD", [V(x), V(y)]) => Instruction::LdReg(*x, *y),
+            ("LD", [V(x), Dt]) => Instruction::LdVxDt(*x),
+            ("LD", [V(x), K]) => Instruction::LdVxK(*x),
+            ("LD", [V(x), IndirectI]) => Instruction::LdVxI(*x),
+            ("LD", [V(x), R]) => Instruction::LdVxR(*x),
+            ("LD", [V(x), Value(kk)]) => Instruction::LdByte(*x, self.byte(kk)?),
+            ("LD", [Range(x, y), IndirectI]) => Instruction::LoadRange(*x, *y),
+            ("LD", [I, Long(addr)]) => {
+                let mut out = Instruction::LdILong.encode().to_be_bytes().to_vec();
+                out.extend_from_slice(&self.word(addr)?.to_be_bytes());
+                return Ok(out);
+            }
+            ("LD", [I, Value(nnn)]) => Instruction::LdI(self.addr(nnn)?),
+            ("LD", [Dt, V(x)]) => Instruction::LdDtVx(*x),
+            ("LD", [St, V(x)]) => Instruction::LdStVx(*x),
+            ("LD", [F, V(x)]) => Instruction::LdF(*x),
+            ("LD", [Hf, V(x)]) => Instruction::LdHf(*x),
+            ("LD", [B, V(x)]) => Instruction::LdB(*x),
+            ("LD", [IndirectI, V(x)]) => Instruction::LdIVx(*x),
+            ("LD", [IndirectI, Range(x, y)]) => Instruction::SaveRange(*x, *y),
+            ("LD", [R, V(x)]) => Instruction::LdRVx(*x),
+            ("ADD", [V(x), V(y)]) => Instruction::AddReg(*x, *y),
+            ("ADD", [V(x), Value(kk)]) => Instruction::AddByte(*x, self.byte(kk)?),
+            ("ADD", [I, V(x)]) => Instruction::AddI(*x),
+            ("OR", [V(x), V(y)]) => Instruction::Or(*x, *y),
+            ("AND", [V(x), V(y)]) => Instruction::And(*x, *y),
+            ("XOR", [V(x), V(y)]) => Instruction::Xor(*x, *y),
+            ("SUB", [V(x), V(y)]) => Instruction::Sub(*x, *y),
+            ("SUBN", [V(x), V(y)]) => Instruction::Subn(*x, *y),
+            // Without a second register the shift works on Vx alone, whatever the quirks.
+            ("SHR", [V(x)]) => Instruction::Shr(*x, *x),
+            ("SHR", [V(x), V(y)]) => Instruction::Shr(*x, *y),
+            ("SHL", [V(x)]) => Instruction::Shl(*x, *x),
+            ("SHL", [V(x), V(y)]) => Instruction::Shl(*x, *y),
+            ("RND", [V(x), Value(kk)]) => Instruction::Rnd(*x, self.byte(kk)?),
+            ("DRW", [V(x), V(y), Value(n)]) => Instruction::Drw(*x, *y, self.nibble(n)?),
+            ("SKP", [V(x)]) => Instruction::Skp(*x),
+            ("SKNP", [V(x)]) => Instruction::Sknp(*x),
+            ("PLANE", [Value(n)]) => Instruction::Plane(self.nibble(n)?),
+            ("PITCH", [V(x)]) => Instruction::Pitch(*x),
+            _ if MNEMONICS.contains(&mnemonic) => return Err(format!("Invalid operands for {}", mnemonic)),
+            _ => return Err(format!("Unknown instruction '{}'", mnemonic)),
+        };
+
+        Ok(instruction.encode().to_be_bytes().to_vec())
+    }
+
+    fn eval(&self, expr: &Expr, depth: usize) -> Result<i64, String> {
+        match expr {
+            Expr::Number(val) => Ok(*val),
+            Expr::Symbol(name) => {
+                if let Some(&addr) = self.labels.get(name) {
+                    return Ok(addr as i64);
+                }
+
+                match self.constants.get(name) {
+                    // Anything this deep is a constant defined in terms of itself.
+                    Some(_) if depth > 64 => Err(format!("'{}' is defined in terms of itself", name)),
+                    Some(value) => self.eval(value, depth + 1),
+                    None => Err(format!("Unknown symbol '{}'", name)),
+                }
+            }
+            Expr::Unary(op, value) => {
+                let value = self.eval(value, depth)?;
+                Ok(if *op == "-" { value.wrapping_neg() } else { !value })
+            }
+            Expr::Binary(op, left, right) => {
+                let (left, right) = (self.eval(left, depth)?, self.eval(right, depth)?);
+
+                let value = match *op {
+                    "+" => left.wrapping_add(right),
+                    "-" => left.wrapping_sub(right),
+                    "*" => left.wrapping_mul(right),
+                    "/" | "%" if right == 0 => return Err(String::from("Division by zero")),
+                    "/" => left.wrapping_div(right),
+                    "%" => left.wrapping_rem(right),
+                    "&" => left & right,
+                    "|" => left | right,
+                    "^" => left ^ right,
+                    "<<" => left.wrapping_shl(right as u32),
+                    _ => left.wrapping_shr(right as u32),
+                };
+
+                Ok(value)
+            }
+        }
+    }
+
+    fn fit(&self, expr: &Expr, min: i64, max: i64, what: &str) -> Result<i64, String> {
+        let value = self.eval(expr, 0)?;
+
+        if (min..=max).contains(&value) {
+            Ok(value)
+        } else {
+            Err(format!("{} doesn't fit in {}", value, what))
+        }
+    }
+
+    // Negative values are allowed where they fit, as two's complement.
+    fn byte(&self, expr: &Expr) -> Result<u8, String> {
+        self.fit(expr, -128, 0xFF, "a byte").map(|value| value as u8)
+    }
+
+    fn word(&self, expr: &Expr) -> Result<u16, String> {
+        self.fit(expr, -32768, 0xFFFF, "a word").map(|value| value as u16)
+    }
+
+    fn addr(&self, expr: &Expr) -> Result<u16, String> {
+        self.fit(expr, 0, 0xFFF, "12 bits").map(|value| value as u16)
+    }
+
+    fn nibble(&self, expr: &Expr) -> Result<u8, String> {
+        self.fit(expr, 0, 0xF, "a nibble").map(|value| value as u8)
+    }
+}
+
+const MNEMONICS: [&str; 30] = [
+    "CLS", "RET", "SCR", "SCL", "EXIT", "LOW", "HIGH", "AUDIO", "SCD", "SCU", "SYS", "JP", "CALL",
+    "SE", "SNE", "LD", "ADD", "OR", "AND", "XOR", "SUB", "SUBN", "SHR", "SHL", "RND", "DRW", "SKP",
+    "SKNP", "PLANE", "PITCH",
+];
+
+// Everything after a `;` that isn't in a string.
+fn strip_comment(text: &str) -> &str {
+    let mut quoted = false;
+
+    for (idx, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &text[..idx],
+            _ => (),
+        }
+    }
+
+    text
+}
+
+// A listing line starts with `0200: 6003` and then at least two spaces before the
+// instruction. Only the instruction is left.
+fn strip_listing(text: &str) -> &str {
+    let trimmed = text.trim_start();
+
+    let rest = match trimmed.get(..5) {
+        Some(addr) if addr.ends_with(':') && addr[..4].chars().all(|c| c.is_ascii_hexdigit()) => &trimmed[5..],
+        _ => return text,
+    };
+
+    let rest = rest.strip_prefix(' ').unwrap_or(rest);
+    let (bytes, code) = rest.split_once("  ").unwrap_or((rest, ""));
+
+    if bytes.split(' ').all(|word| !word.is_empty() && word.chars().all(|c| c.is_ascii_hexdigit())) {
+        code
+    } else {
+        text
+    }
+}
+
+fn is_symbol(token: &str) -> bool {
+    let mut chars = token.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '.') &&
+        chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '#' || c == '$' {
+            let mut word = String::new();
+            word.push(c);
+            chars.next();
+
+            while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphanumeric() || **c == '_' || **c == '.') {
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(word);
+        } else if c == '"' {
+            let mut string = String::from("\"");
+            chars.next();
+
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => string.push(c),
+                    None => return Err(String::from("Missing '\"' at the end of a string")),
+                }
+            }
+
+            string.push('"');
+            tokens.push(string);
+        } else {
+            chars.next();
+
+            let token = match (c, chars.peek()) {
+                ('<', Some('<')) | ('>', Some('>')) => {
+                    let second = chars.next().unwrap();
+                    format!("{}{}", c, second)
+                }
+                ('+', _) | ('-', _) | ('*', _) | ('/', _) | ('%', _) | ('&', _) | ('|', _) | ('^', _) |
+                ('~', _) | ('(', _) | (')', _) | ('[', _) | (']', _) | (',', _) | (':', _) | ('=', _) => c.to_string(),
+                _ => return Err(format!("Unexpected '{}'", c)),
+            };
+
+            tokens.push(token);
+        }
+    }
+
+    Ok(tokens)
+}
+
+// Splits the operands at the commas outside parentheses.
+fn split_operands(tokens: &[String]) -> Vec<&[String]> {
+    if tokens.is_empty() {
+        return Vec::new();
+    }
+
+    let mut operands = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (idx, token) in tokens.iter().enumerate() {
+        match token.as_str() {
+            "(" => depth += 1,
+            ")" => depth -= 1,
+            "," if depth == 0 => {
+                operands.push(&tokens[start..idx]);
+                start = idx + 1;
+            }
+            _ => (),
+        }
+    }
+
+    operands.push(&tokens[start..]);
+    operands
+}
+
+fn register(token: &str) -> Option<u8> {
+    let upper = token.to_uppercase();
+    let digit = upper.strip_prefix('V').filter(|digit| digit.len() == 1)?;
+    u8::from_str_radix(digit, 16).ok()
+}
+
+fn parse_operand(tokens: &[String]) -> Result<Operand, String> {
+    let upper: Vec<String> = tokens.iter().map(|token| token.to_uppercase()).collect();
+    let upper: Vec<&str> = upper.iter().map(String::as_str).collect();
+
+    let operand = match upper.as_slice() {
+        [reg] if register(reg).is_some() => Operand::V(register(reg).unwrap_or_default()),
+        [first, "-", last] if register(first).is_some() && register(last).is_some() => {
+            Operand::Range(register(first).unwrap_or_default(), register(last).unwrap_or_default())
+        }
+        ["I"] => Operand::I,
+        ["[", "I", "]"] => Operand::IndirectI,
+        ["DT"] => Operand::Dt,
+        ["ST"] => Operand::St,
+        ["K"] => Operand::K,
+        ["F"] => Operand::F,
+        ["HF"] => Operand::Hf,
+        ["B"] => Operand::B,
+        ["R"] => Operand::R,
+        ["LONG", ..] => Operand::Long(parse_expr(&tokens[1..])?),
+        _ => Operand::Value(parse_expr(tokens)?),
+    };
+
+    Ok(operand)
+}
+
+fn parse_item(tokens: &[String]) -> Result<Item, String> {
+    match tokens {
+        [string] if string.starts_with('"') => Ok(Item::Text(string.trim_matches('"').as_bytes().to_vec())),
+        _ => parse_expr(tokens).map(Item::Value),
+    }
+}
+
+fn parse_expr(tokens: &[String]) -> Result<Expr, String> {
+    let mut parser = Parser { tokens, pos: 0 };
+    let expr = parser.binary(0)?;
+
+    match parser.peek() {
+        None => Ok(expr),
+        Some(token) => Err(format!("Unexpected '{}'", token)),
+    }
+}
+
+// Binary operators from the loosest to the tightest.
+const PRECEDENCE: [&[&str]; 6] = [&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"], &["*", "/", "%"]];
+
+struct Parser<'a> {
+    tokens: &'a [String],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+
+        let mut expr = self.binary(level + 1)?;
+
+        while let Some(op) = self.peek().and_then(|token| PRECEDENCE[level].iter().find(|&&op| op == token)) {
+            self.next();
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.binary(level + 1)?));
+        }
+
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some("-") => Ok(Expr::Unary("-", Box::new(self.unary()?))),
+            Some("~") => Ok(Expr::Unary("~", Box::new(self.unary()?))),
+            Some("+") => self.unary(),
+            Some("(") => {
+                let expr = self.binary(0)?;
+                match self.next() {
+                    Some(")") => Ok(expr),
+                    _ => Err(String::from("Missing ')'")),
+                }
+            }
+            Some(token) if is_symbol(token) => Ok(Expr::Symbol(token.to_string())),
+            Some(token) => parse_number(token)
+                .map(Expr::Number)
+                .ok_or_else(|| format!("Invalid number '{}'", token)),
+            None => Err(String::from("Missing value")),
+        }
+    }
+}
+
+fn parse_number(token: &str) -> Option<i64> {
+    let lower = token.to_lowercase();
+
+    let (digits, radix) = if let Some(hex) = lower.strip_prefix('#').or_else(|| lower.strip_prefix('$')) {
+        (hex, 16)
+    } else if let Some(hex) = lower.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        (binary, 2)
+    } else {
+        (lower.as_str(), 10)
+    };
+
+    i64::from_str_radix(digits, radix).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::{Disassembler, Format, Mode};
+
+    #[test]
+    fn every_instruction_round_trips() {
+        for opcode in 0..=0xFFFFu16 {
+            if Instruction::decode(opcode).is_err() {
+                continue;
+            }
+
+            let bytes = opcode.to_be_bytes();
+            let source = Disassembler::with_mode(&bytes, Mode::Linear).disassemble();
+            let assembly = assemble(&source).unwrap_or_else(|err| panic!("{:04X}: {}\n{}", opcode, err, source));
+            assert_eq!(assembly.bytes, bytes, "{:04X}\n{}", opcode, source);
+        }
+    }
+
+    // A small game loop: code that jumps and calls around a sprite and a table.
+    const PROGRAM: &str = "
+SPEED = 2
+start:
+    CLS
+    LD V0, 0
+    LD V1, SPEED * 4
+loop:
+    LD I, sprite
+    DRW V0, V1, sprite_end - sprite
+    CALL move
+    SE V0, 60
+    JP loop
+    JP start
+move:
+    ADD V0, SPEED
+    LD I, table
+    LD V2, [I]
+    RET
+sprite:
+    db 0b11110000, #90, $90, 0x90
+sprite_end:
+table:
+    dw 0x1234
+";
+
+    #[test]
+    fn assembles_the_documented_syntax() {
+        let assembly = assemble(PROGRAM).unwrap();
+
+        assert_eq!(&assembly.bytes[..6], &[0x00, 0xE0, 0x60, 0x00, 0x61, 0x08]);
+        assert_eq!(assembly.labels["sprite"], 0x21A);
+        assert_eq!(&assembly.bytes[0x1A..], &[0xF0, 0x90, 0x90, 0x90, 0x12, 0x34]);
+        assert_eq!(assembly.symbols().label(0x212), Some("move"));
+    }
+
+    #[test]
+    fn program_round_trips_through_the_disassembler() {
+        let assembly = assemble(PROGRAM).unwrap();
+
+        for &mode in [Mode::Flow, Mode::Linear].iter() {
+            let source = Disassembler::with_mode(&assembly.bytes, mode).disassemble();
+            let again = assemble(&source).unwrap_or_else(|err| panic!("{}\n{}", err, source));
+            assert_eq!(again.bytes, assembly.bytes, "{}", source);
+
+            // And the second disassembly is the same as the first.
+            assert_eq!(Disassembler::with_mode(&again.bytes, mode).disassemble(), source);
+        }
+    }
+
+    #[test]
+    fn disassembly_keeps_the_symbols() {
+        let assembly = assemble(PROGRAM).unwrap();
+        let mut disassembler = Disassembler::new(&assembly.bytes);
+        disassembler.set_symbols(assembly.symbols());
+
+        let source = disassembler.disassemble_as(Format::Text);
+        assert!(source.contains("move:"), "{}", source);
+        assert_eq!(assemble(&source).unwrap().labels["move"], 0x212);
+    }
+
+    #[test]
+    fn listing_shows_addresses_and_bytes() {
+        let listing = assemble(PROGRAM).unwrap().listing();
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(lines[1], "                 SPEED = 2");
+        assert_eq!(lines[3], "0200  00E0           CLS");
+        assert!(lines.contains(&"021A  F090 9090      db 0b11110000, #90, $90, 0x90"), "{}", listing);
+    }
+
+    #[test]
+    fn disassembly_listing_assembles_back() {
+        let assembly = assemble(PROGRAM).unwrap();
+        let listing = Disassembler::with_mode(&assembly.bytes, Mode::Linear).disassemble();
+        assert!(listing.contains("0200: 00E0"), "{}", listing);
+        assert_eq!(assemble(&listing).unwrap().bytes, assembly.bytes);
+    }
+
+    #[test]
+    fn directives_and_expressions() {
+        let assembly = assemble("
+            BASE equ 0x300
+            db \"Hi\", (1 + 2) * 3, ~0 & 0xF, 1 << 4 | 1
+            org 0x208
+            LD I, BASE + 0x10
+        ").unwrap();
+
+        assert_eq!(assembly.bytes, vec![b'H', b'i', 9, 0x0F, 0x11, 0, 0, 0, 0xA3, 0x10]);
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        assert_eq!(assemble("CLS\nFOO V0").unwrap_err(), "Unknown instruction 'FOO' on line 2");
+        assert_eq!(assemble("LD I, nowhere").unwrap_err(), "Unknown symbol 'nowhere' on line 1");
+        assert_eq!(assemble("a:\na:").unwrap_err(), "'a' is defined twice on line 2");
+        assert!(assemble("LD V0, 256").unwrap_err().ends_with("on line 1"));
+    }
+}
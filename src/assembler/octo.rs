@@ -0,0 +1,1053 @@
+//! Octo, the language most CHIP-8 homebrew is written in.
+//!
+//! ```text
+//! :const SPEED 2
+//! :alias x v1
+//!
+//! : main
+//!     x := 0
+//!     loop
+//!         i := ball
+//!         sprite x x 4
+//!         x += SPEED
+//!         if x == 60 then x := 0
+//!     again
+//!
+//! : ball
+//!     0x60 0xF0 0xF0 0x60
+//! ```
+//!
+//! Everything is split on whitespace and `#` starts a comment. Labels are defined
+//! with `: name`, and a bare label name calls it while a bare number is a byte of
+//! data. Supported are the instructions (`:=`, `+=`, `-=`, `=-`, `|=`, `&=`, `^=`,
+//! `>>=`, `<<=`, `sprite`, `jump`, `jump0`, `:call`, `native` and the rest),
+//! `if ... then`, `if ... begin ... else ... end`, `loop ... while ... again`, and
+//! the directives `:const`, `:alias`, `:macro`, `:calc`, `:byte`, `:org`, `:next`,
+//! `:unpack`, `:breakpoint` and `:monitor`. Conditions compare with `==`, `!=`, `<`,
+//! `>`, `<=` and `>=` or test `key` and `-key`. Like in Octo, the ordered comparisons
+//! subtract into vF and then test it, so they overwrite vF.
+//!
+//! `:calc` expressions go between braces and, as in Octo, have no precedence: they're
+//! evaluated right to left, so group them with parentheses.
+//!
+//! The program starts at `main`. If that's not the first thing in it, a `jump main`
+//! goes in front.
+
+use super::{Assembly, Row, MEMORY_END, PROGRAM_START};
+use crate::instruction::Instruction;
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+/// Assembles an Octo program. Errors name the line they're on.
+pub fn assemble(source: &str) -> Result<Assembly, String> {
+    let tokens = tokenize(source);
+
+    // The first pass only finds out where the labels go, names used before they're
+    // defined count as zero.
+    let mut pass = Pass::new(&tokens, PROGRAM_START, None);
+    pass.run()?;
+
+    let main = *pass.labels.get("main").ok_or("The program has no main label")?;
+    let start = if main as u32 == PROGRAM_START { PROGRAM_START } else { PROGRAM_START + 2 };
+
+    if start != PROGRAM_START {
+        pass = Pass::new(&tokens, start, None);
+        pass.run()?;
+    }
+
+    // Every label is known now. Labels only move again when an :org or :calc used
+    // one before it was defined, give those a couple of tries to settle.
+    for _ in 0..4 {
+        let labels = pass.labels.clone();
+
+        pass = Pass::new(&tokens, start, Some(labels));
+        pass.run()?;
+
+        if Some(&pass.labels) == pass.previous.as_ref() {
+            return Ok(pass.finish(source, start));
+        }
+    }
+
+    Err(String::from("The labels never settle, an :org or :calc depends on a label defined after it"))
+}
+
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+
+    for (idx, text) in source.lines().enumerate() {
+        let code = text.split_once('#').map_or(text, |(code, _)| code);
+
+        for word in code.split_whitespace() {
+            tokens.push(Token { text: word.to_string(), line: idx + 1 });
+        }
+    }
+
+    tokens
+}
+
+// What `if`, `loop` and the like leave open until their `end` or `again`.
+enum Block {
+    If(u32), // The address of the jump past the `begin` part
+    Else(u32), // The address of the jump past the `else` part
+    Loop(u32, Vec<u32>), // Where the loop starts, and the jumps out of it from every `while`
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Condition {
+    Equal(u8, Rhs),
+    NotEqual(u8, Rhs),
+    Less(u8, Rhs),
+    Greater(u8, Rhs),
+    LessEqual(u8, Rhs),
+    GreaterEqual(u8, Rhs),
+    Key(u8),
+    NotKey(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Rhs {
+    Register(u8),
+    Byte(u8),
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+struct Pass {
+    tokens: Vec<Token>, // Macros are expanded in place
+    pos: usize,
+    line: usize, // Of the token being assembled
+    here: u32,
+    memory: Vec<u8>, // From 0x200
+    origin: Vec<usize>, // The source line of every byte, 0 for none
+    labels: BTreeMap<String, u16>,
+    label_lines: HashMap<usize, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    blocks: Vec<Block>,
+    breakpoints: Vec<(u16, String)>,
+    previous: Option<BTreeMap<String, u16>>, // The labels from the pass before, None in the first
+    expansions: usize,
+}
+
+impl Pass {
+    fn new(tokens: &[Token], start: u32, previous: Option<BTreeMap<String, u16>>) -> Self {
+        Self {
+            tokens: tokens.to_vec(),
+            pos: 0,
+            line: 0,
+            here: start,
+            memory: vec![0; (start - PROGRAM_START) as usize],
+            origin: vec![0; (start - PROGRAM_START) as usize],
+            labels: BTreeMap::new(),
+            label_lines: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            blocks: Vec::new(),
+            breakpoints: Vec::new(),
+            previous,
+            expansions: 0,
+        }
+    }
+
+    fn run(&mut self) -> Result<(), String> {
+        while let Some(token) = self.next_token() {
+            self.line = token.line;
+            self.statement(&token.text).map_err(|err| format!("{} on line {}", err, self.line))?;
+        }
+
+        match self.blocks.last() {
+            Some(Block::Loop(..)) => Err(String::from("A loop is missing its again")),
+            Some(_) => Err(String::from("An if ... begin is missing its end")),
+            None => Ok(()),
+        }
+    }
+
+    fn finish(mut self, source: &str, start: u32) -> Assembly {
+        let mut rows = Vec::new();
+
+        if start != PROGRAM_START {
+            let main = self.labels["main"];
+            let jump = Instruction::Jp(main).encode().to_be_bytes();
+            self.memory[..2].copy_from_slice(&jump);
+            rows.push(Row { addr: Some(PROGRAM_START as u16), bytes: jump.to_vec(), source: String::from("(jump main)") });
+        }
+
+        let mut lines: BTreeMap<usize, (u16, Vec<u8>)> = BTreeMap::new();
+        for (idx, (&byte, &line)) in self.memory.iter().zip(&self.origin).enumerate() {
+            if line != 0 {
+                let addr = PROGRAM_START as u16 + idx as u16;
+                lines.entry(line).or_insert((addr, Vec::new())).1.push(byte);
+            }
+        }
+
+        for (idx, text) in source.lines().enumerate() {
+            let line = idx + 1;
+            let (addr, bytes) = match lines.remove(&line) {
+                Some((addr, bytes)) => (Some(addr), bytes),
+                None => (self.label_lines.get(&line).copied(), Vec::new()),
+            };
+
+            rows.push(Row { addr, bytes, source: text.to_string() });
+        }
+
+        Assembly { bytes: self.memory, labels: self.labels, breakpoints: self.breakpoints, rows }
+    }
+
+    fn next_token(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        self.next_token().map(|token| token.text).ok_or_else(|| String::from("The program ends too early"))
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(format!("Expected '{}' but found '{}'", expected, token)),
+        }
+    }
+
+    fn statement(&mut self, token: &str) -> Result<(), String> {
+        match token {
+            ":" => {
+                let name = self.name()?;
+                self.define_label(name, self.here)?;
+            }
+            ":next" => {
+                // Names the second byte of the next instruction, for code that rewrites itself.
+                let name = self.name()?;
+                self.define_label(name, self.here + 1)?;
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.value()?;
+                self.constants.insert(name, value);
+            }
+            ":calc" => {
+                let name = self.name()?;
+                self.expect("{")?;
+                let value = self.calc()?;
+                self.constants.insert(name, value);
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let reg = self.register()?;
+                self.aliases.insert(name, reg);
+            }
+            ":macro" => self.define_macro()?,
+            ":byte" => {
+                let byte = self.byte()?;
+                self.emit(&[byte])?;
+            }
+            ":org" => {
+                let addr = self.value()? as i64;
+                if addr < PROGRAM_START as i64 || addr >= MEMORY_END as i64 {
+                    return Err(format!("Can't :org to {:#x}", addr));
+                }
+                self.here = addr as u32;
+            }
+            ":unpack" => {
+                // v0 and v1 get the address, with a nibble on top of it or the whole 16 bits.
+                let high = match self.next()?.as_str() {
+                    "long" => 0,
+                    nibble => (self.number(nibble)? as i64 & 0xF) << 4,
+                };
+                let addr = self.value()? as i64;
+
+                self.op(Instruction::LdByte(0, (high | (addr >> 8 & 0xFF)) as u8))?;
+                self.op(Instruction::LdByte(1, (addr & 0xFF) as u8))?;
+            }
+            ":breakpoint" => {
+                let name = self.name()?;
+                self.breakpoints.push((self.here as u16, name));
+            }
+            ":monitor" => {
+                // Only means something to Octo's own debugger.
+                self.next()?;
+                self.next()?;
+            }
+            ":call" => {
+                let addr = self.addr()?;
+                self.op(Instruction::Call(addr))?;
+            }
+            "jump" => {
+                let addr = self.addr()?;
+                self.op(Instruction::Jp(addr))?;
+            }
+            "jump0" => {
+                let addr = self.addr()?;
+                self.op(Instruction::JpV0(addr))?;
+            }
+            "native" => {
+                let addr = self.addr()?;
+                self.op(Instruction::Sys(addr))?;
+            }
+            "return" | ";" => self.op(Instruction::Ret)?,
+            "clear" => self.op(Instruction::Cls)?,
+            "exit" => self.op(Instruction::Exit)?,
+            "lores" => self.op(Instruction::Low)?,
+            "hires" => self.op(Instruction::High)?,
+            "scroll-left" => self.op(Instruction::Scl)?,
+            "scroll-right" => self.op(Instruction::Scr)?,
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.op(Instruction::Scd(n))?;
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.op(Instruction::Scu(n))?;
+            }
+            "audio" => self.op(Instruction::Audio)?,
+            "plane" => {
+                let n = self.nibble()?;
+                self.op(Instruction::Plane(n))?;
+            }
+            "sprite" => {
+                let (x, y, n) = (self.register()?, self.register()?, self.nibble()?);
+                self.op(Instruction::Drw(x, y, n))?;
+            }
+            "bcd" => {
+                let x = self.register()?;
+                self.op(Instruction::LdB(x))?;
+            }
+            "save" | "load" => {
+                let x = self.register()?;
+
+                let instruction = if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.register()?;
+                    if token == "save" { Instruction::SaveRange(x, y) } else { Instruction::LoadRange(x, y) }
+                } else if token == "save" {
+                    Instruction::LdIVx(x)
+                } else {
+                    Instruction::LdVxI(x)
+                };
+
+                self.op(instruction)?;
+            }
+            "saveflags" => {
+                let x = self.register()?;
+                self.op(Instruction::LdRVx(x))?;
+            }
+            "loadflags" => {
+                let x = self.register()?;
+                self.op(Instruction::LdVxR(x))?;
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+
+                self.op(match token {
+                    "delay" => Instruction::LdDtVx(x),
+                    "buzzer" => Instruction::LdStVx(x),
+                    _ => Instruction::Pitch(x),
+                })?;
+            }
+            "i" => self.index()?,
+            "if" => {
+                let condition = self.condition()?;
+
+                match self.next()?.as_str() {
+                    "then" => self.skip_unless(condition)?,
+                    "begin" => {
+                        self.skip_unless(condition.not())?;
+                        self.blocks.push(Block::If(self.here));
+                        self.op(Instruction::Jp(0))?;
+                    }
+                    other => return Err(format!("Expected 'then' or 'begin' but found '{}'", other)),
+                }
+            }
+            "else" => match self.blocks.pop() {
+                Some(Block::If(jump)) => {
+                    self.blocks.push(Block::Else(self.here));
+                    self.op(Instruction::Jp(0))?;
+                    self.patch(jump)?;
+                }
+                _ => return Err(String::from("else without if ... begin")),
+            },
+            "end" => match self.blocks.pop() {
+                Some(Block::If(jump)) | Some(Block::Else(jump)) => self.patch(jump)?,
+                _ => return Err(String::from("end without if ... begin")),
+            },
+            "loop" => self.blocks.push(Block::Loop(self.here, Vec::new())),
+            "while" => {
+                let condition = self.condition()?;
+                self.skip_unless(condition.not())?;
+
+                let here = self.here;
+                match self.blocks.iter_mut().rev().find(|block| matches!(block, Block::Loop(..))) {
+                    Some(Block::Loop(_, exits)) => exits.push(here),
+                    _ => return Err(String::from("while outside a loop")),
+                }
+                self.op(Instruction::Jp(0))?;
+            }
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop(start, exits)) => {
+                    self.op(Instruction::Jp(start as u16))?;
+                    for exit in exits {
+                        self.patch(exit)?;
+                    }
+                }
+                _ => return Err(String::from("again without loop")),
+            },
+            _ if self.find_register(token).is_some() => self.register_statement(token)?,
+            _ if self.macros.contains_key(token) => self.expand(token)?,
+            _ if token.starts_with(':') => return Err(format!("Unknown directive '{}'", token)),
+            // A bare number is a byte, a bare label a call.
+            _ if parse_number(token).is_some() || self.constants.contains_key(token) => {
+                let byte = self.to_byte(self.number(token)?)?;
+                self.emit(&[byte])?;
+            }
+            _ => {
+                let addr = self.to_addr(self.number(token)?)?;
+                self.op(Instruction::Call(addr))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn register_statement(&mut self, token: &str) -> Result<(), String> {
+        let x = self.find_register(token).unwrap_or_default();
+        let op = self.next()?;
+
+        // The right hand side is either a register or a number.
+        let rhs = self.next()?;
+        let y = self.find_register(&rhs);
+
+        let instruction = match (op.as_str(), y) {
+            (":=", _) if rhs == "random" => Instruction::Rnd(x, self.byte()?),
+            (":=", _) if rhs == "delay" => Instruction::LdVxDt(x),
+            (":=", _) if rhs == "key" => Instruction::LdVxK(x),
+            (":=", Some(y)) => Instruction::LdReg(x, y),
+            (":=", None) => Instruction::LdByte(x, self.rhs_byte(&rhs)?),
+            ("+=", Some(y)) => Instruction::AddReg(x, y),
+            ("+=", None) => Instruction::AddByte(x, self.rhs_byte(&rhs)?),
+            ("-=", Some(y)) => Instruction::Sub(x, y),
+            ("-=", None) => Instruction::AddByte(x, self.rhs_byte(&rhs)?.wrapping_neg()),
+            ("=-", Some(y)) => Instruction::Subn(x, y),
+            ("|=", Some(y)) => Instruction::Or(x, y),
+            ("&=", Some(y)) => Instruction::And(x, y),
+            ("^=", Some(y)) => Instruction::Xor(x, y),
+            (">>=", Some(y)) => Instruction::Shr(x, y),
+            ("<<=", Some(y)) => Instruction::Shl(x, y),
+            _ => return Err(format!("Can't do '{} {} {}'", token, op, rhs)),
+        };
+
+        self.op(instruction)
+    }
+
+    // `i := addr`, `i := long addr`, `i := hex vx`, `i := bighex vx` and `i += vx`.
+    fn index(&mut self) -> Result<(), String> {
+        match self.next()?.as_str() {
+            ":=" => match self.peek() {
+                Some("long") => {
+                    self.next()?;
+                    let addr = self.value()? as i64;
+                    if !(0..=0xFFFF).contains(&addr) {
+                        return Err(format!("{} doesn't fit in a word", addr));
+                    }
+
+                    self.op(Instruction::LdILong)?;
+                    self.emit(&(addr as u16).to_be_bytes())
+                }
+                Some("hex") | Some("bighex") => {
+                    let big = self.next()? == "bighex";
+                    let x = self.register()?;
+                    self.op(if big { Instruction::LdHf(x) } else { Instruction::LdF(x) })
+                }
+                _ => {
+                    let addr = self.addr()?;
+                    self.op(Instruction::LdI(addr))
+                }
+            },
+            "+=" => {
+                let x = self.register()?;
+                self.op(Instruction::AddI(x))
+            }
+            op => Err(format!("Can't do 'i {}'", op)),
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+        let x = self.register()?;
+
+        let condition = match self.next()?.as_str() {
+            "key" => Condition::Key(x),
+            "-key" => Condition::NotKey(x),
+            op @ "==" | op @ "!=" | op @ "<" | op @ ">" | op @ "<=" | op @ ">=" => {
+                let rhs = self.next()?;
+                let rhs = match self.find_register(&rhs) {
+                    Some(y) => Rhs::Register(y),
+                    None => Rhs::Byte(self.rhs_byte(&rhs)?),
+                };
+
+                match op {
+                    "==" => Condition::Equal(x, rhs),
+                    "!=" => Condition::NotEqual(x, rhs),
+                    "<" => Condition::Less(x, rhs),
+                    ">" => Condition::Greater(x, rhs),
+                    "<=" => Condition::LessEqual(x, rhs),
+                    _ => Condition::GreaterEqual(x, rhs),
+                }
+            }
+            op => return Err(format!("Invalid comparison '{}'", op)),
+        };
+
+        Ok(condition)
+    }
+
+    fn define_label(&mut self, name: String, addr: u32) -> Result<(), String> {
+        if self.labels.contains_key(&name) {
+            return Err(format!("'{}' is defined twice", name));
+        }
+
+        self.labels.insert(name, addr as u16);
+        self.label_lines.entry(self.line).or_insert(addr as u16);
+        Ok(())
+    }
+
+    fn define_macro(&mut self) -> Result<(), String> {
+        let name = self.name()?;
+        let mut params = Vec::new();
+
+        loop {
+            match self.next()? {
+                open if open == "{" => break,
+                param => params.push(param),
+            }
+        }
+
+        let mut body = Vec::new();
+        let mut depth = 0;
+
+        loop {
+            let token = self.next_token().ok_or_else(|| format!("The macro '{}' has no closing brace", name))?;
+
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => break,
+                "}" => depth -= 1,
+                _ => (),
+            }
+
+            body.push(token);
+        }
+
+        self.macros.insert(name, Macro { params, body });
+        Ok(())
+    }
+
+    // Replaces the macro and its arguments with its body.
+    fn expand(&mut self, name: &str) -> Result<(), String> {
+        self.expansions += 1;
+        if self.expansions > 100_000 {
+            return Err(format!("The macro '{}' never stops expanding", name));
+        }
+
+        let count = self.macros[name].params.len();
+        let mut args = Vec::new();
+        for _ in 0..count {
+            args.push(self.next()?);
+        }
+
+        let expansion: Vec<Token> = self.macros[name].body.iter()
+            .map(|token| {
+                let text = match self.macros[name].params.iter().position(|param| *param == token.text) {
+                    Some(idx) => args[idx].clone(),
+                    None => token.text.clone(),
+                };
+                // Whatever the macro emits belongs to the line that used it.
+                Token { text, line: self.line }
+            })
+            .collect();
+
+        self.tokens.splice(self.pos..self.pos, expansion);
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let name = self.next()?;
+
+        if parse_number(&name).is_some() || name.starts_with(':') || name == "{" || name == "}" {
+            return Err(format!("'{}' can't be a name", name));
+        }
+
+        Ok(name)
+    }
+
+    fn find_register(&self, token: &str) -> Option<u8> {
+        if let Some(&reg) = self.aliases.get(token) {
+            return Some(reg);
+        }
+
+        let digit = token.strip_prefix('v').or_else(|| token.strip_prefix('V'))?;
+        if digit.len() != 1 {
+            return None;
+        }
+        u8::from_str_radix(digit, 16).ok()
+    }
+
+    fn register(&mut self) -> Result<u8, String> {
+        let token = self.next()?;
+        self.find_register(&token).ok_or_else(|| format!("Expected a register but found '{}'", token))
+    }
+
+    // A number, name or `{ calc }`.
+    fn value(&mut self) -> Result<f64, String> {
+        match self.next()?.as_str() {
+            "{" => self.calc(),
+            token => self.number(token),
+        }
+    }
+
+    fn number(&self, token: &str) -> Result<f64, String> {
+        if let Some(value) = parse_number(token) {
+            return Ok(value as f64);
+        }
+
+        if let Some(&value) = self.constants.get(token) {
+            return Ok(value);
+        }
+
+        if let Some(&addr) = self.labels.get(token) {
+            return Ok(addr as f64);
+        }
+
+        match &self.previous {
+            Some(labels) => labels.get(token)
+                .map(|&addr| addr as f64)
+                .ok_or_else(|| format!("Unknown name '{}'", token)),
+            // Most likely a label further down, its address is known next time around.
+            None => Ok(0.0),
+        }
+    }
+
+    // A byte whose first token was already read.
+    fn rhs_byte(&mut self, token: &str) -> Result<u8, String> {
+        let value = if token == "{" { self.calc()? } else { self.number(token)? };
+        self.to_byte(value)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        let value = self.value()?;
+        self.to_byte(value)
+    }
+
+    fn nibble(&mut self) -> Result<u8, String> {
+        let value = self.value()? as i64;
+
+        if (0..=0xF).contains(&value) {
+            Ok(value as u8)
+        } else {
+            Err(format!("{} doesn't fit in a nibble", value))
+        }
+    }
+
+    fn addr(&mut self) -> Result<u16, String> {
+        let value = self.value()?;
+        self.to_addr(value)
+    }
+
+    // Negative values are allowed where they fit, as two's complement.
+    fn to_byte(&self, value: f64) -> Result<u8, String> {
+        let value = value as i64;
+
+        if (-128..=0xFF).contains(&value) {
+            Ok(value as u8)
+        } else {
+            Err(format!("{} doesn't fit in a byte", value))
+        }
+    }
+
+    fn to_addr(&self, value: f64) -> Result<u16, String> {
+        let value = value as i64;
+
+        if (0..=0xFFF).contains(&value) {
+            Ok(value as u16)
+        } else {
+            Err(format!("{} doesn't fit in 12 bits", value))
+        }
+    }
+
+    // The expression after a `{`, up to its `}`.
+    fn calc(&mut self) -> Result<f64, String> {
+        let mut tokens = Vec::new();
+
+        loop {
+            match self.next()? {
+                close if close == "}" => break,
+                token => tokens.push(token),
+            }
+        }
+
+        let mut pos = 0;
+        let value = self.calc_expr(&tokens, &mut pos)?;
+
+        match tokens.get(pos) {
+            None => Ok(value),
+            Some(token) => Err(format!("Unexpected '{}' in calc", token)),
+        }
+    }
+
+    // Octo's binary operators all have the same precedence and group to the right.
+    fn calc_expr(&self, tokens: &[String], pos: &mut usize) -> Result<f64, String> {
+        let left = self.calc_term(tokens, pos)?;
+
+        let op = match tokens.get(*pos) {
+            Some(op) if BINARY.contains(&op.as_str()) => op.as_str(),
+            _ => return Ok(left),
+        };
+        *pos += 1;
+
+        let right = self.calc_expr(tokens, pos)?;
+        let (a, b) = (left as i64, right as i64);
+        let truth = |holds: bool| if holds { 1.0 } else { 0.0 };
+
+        let value = match op {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" if right == 0.0 => return Err(String::from("Division by zero in calc")),
+            "/" => left / right,
+            "%" if b == 0 => return Err(String::from("Division by zero in calc")),
+            "%" => (a % b) as f64,
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => a.wrapping_shl(b as u32) as f64,
+            ">>" => a.wrapping_shr(b as u32) as f64,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => truth(left < right),
+            ">" => truth(left > right),
+            "<=" => truth(left <= right),
+            ">=" => truth(left >= right),
+            "==" => truth(left == right),
+            _ => truth(left != right),
+        };
+
+        Ok(value)
+    }
+
+    fn calc_term(&self, tokens: &[String], pos: &mut usize) -> Result<f64, String> {
+        let token = tokens.get(*pos).ok_or("Missing value in calc")?;
+        *pos += 1;
+
+        let value = match token.as_str() {
+            "(" => {
+                let value = self.calc_expr(tokens, pos)?;
+                if tokens.get(*pos).map(String::as_str) != Some(")") {
+                    return Err(String::from("Missing ')' in calc"));
+                }
+                *pos += 1;
+                value
+            }
+            "-" => -self.calc_term(tokens, pos)?,
+            "~" => !(self.calc_term(tokens, pos)? as i64) as f64,
+            "!" => if self.calc_term(tokens, pos)? == 0.0 { 1.0 } else { 0.0 },
+            "@" => {
+                // A byte of the program assembled so far.
+                let addr = self.calc_term(tokens, pos)? as i64 - PROGRAM_START as i64;
+                usize::try_from(addr).ok().and_then(|idx| self.memory.get(idx)).map_or(0.0, |&byte| byte as f64)
+            }
+            "sin" => self.calc_term(tokens, pos)?.sin(),
+            "cos" => self.calc_term(tokens, pos)?.cos(),
+            "tan" => self.calc_term(tokens, pos)?.tan(),
+            "exp" => self.calc_term(tokens, pos)?.exp(),
+            "log" => self.calc_term(tokens, pos)?.ln(),
+            "abs" => self.calc_term(tokens, pos)?.abs(),
+            "sqrt" => self.calc_term(tokens, pos)?.sqrt(),
+            "sign" => self.calc_term(tokens, pos)?.signum(),
+            "ceil" => self.calc_term(tokens, pos)?.ceil(),
+            "floor" => self.calc_term(tokens, pos)?.floor(),
+            "HERE" => self.here as f64,
+            "PI" => std::f64::consts::PI,
+            "E" => std::f64::consts::E,
+            _ => self.number(token)?,
+        };
+
+        Ok(value)
+    }
+
+    fn op(&mut self, instruction: Instruction) -> Result<(), String> {
+        self.emit(&instruction.encode().to_be_bytes())
+    }
+
+    // Emits the skip that runs the next instruction only when the condition holds. The
+    // ordered comparisons expand the way Octo does it: vF gets the right hand side, then
+    // vF -= x (or vF =- x) leaves the borrow flag in vF for the skip to test.
+    fn skip_unless(&mut self, condition: Condition) -> Result<(), String> {
+        match condition {
+            Condition::Equal(x, Rhs::Byte(kk)) => self.op(Instruction::SneByte(x, kk)),
+            Condition::Equal(x, Rhs::Register(y)) => self.op(Instruction::SneReg(x, y)),
+            Condition::NotEqual(x, Rhs::Byte(kk)) => self.op(Instruction::SeByte(x, kk)),
+            Condition::NotEqual(x, Rhs::Register(y)) => self.op(Instruction::SeReg(x, y)),
+            Condition::Key(x) => self.op(Instruction::Sknp(x)),
+            Condition::NotKey(x) => self.op(Instruction::Skp(x)),
+            // vF = rhs - x has no borrow when x <= rhs
+            Condition::Greater(x, rhs) | Condition::LessEqual(x, rhs) => {
+                self.op(load_compare(rhs))?;
+                self.op(Instruction::Sub(0xF, x))?;
+                self.op(if let Condition::Greater(..) = condition {
+                    Instruction::SeByte(0xF, 1)
+                } else {
+                    Instruction::SneByte(0xF, 1)
+                })
+            }
+            // vF = x - rhs has no borrow when x >= rhs
+            Condition::Less(x, rhs) | Condition::GreaterEqual(x, rhs) => {
+                self.op(load_compare(rhs))?;
+                self.op(Instruction::Subn(0xF, x))?;
+                self.op(if let Condition::Less(..) = condition {
+                    Instruction::SeByte(0xF, 1)
+                } else {
+                    Instruction::SneByte(0xF, 1)
+                })
+            }
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let start = (self.here - PROGRAM_START) as usize;
+        let end = start + bytes.len();
+
+        if end as u32 + PROGRAM_START > MEMORY_END {
+            return Err(String::from("The program doesn't fit in memory"));
+        }
+
+        if self.memory.len() < end {
+            self.memory.resize(end, 0);
+            self.origin.resize(end, 0);
+        }
+
+        self.memory[start..end].copy_from_slice(bytes);
+        let line = self.line;
+        self.origin[start..end].iter_mut().for_each(|origin| *origin = line);
+        self.here = end as u32 + PROGRAM_START;
+
+        Ok(())
+    }
+
+    // Points the jump at `at` to here.
+    fn patch(&mut self, at: u32) -> Result<(), String> {
+        let target = u16::try_from(self.here).ok().filter(|&addr| addr <= 0xFFF)
+            .ok_or_else(|| format!("Can't jump to {:#x}", self.here))?;
+
+        let idx = (at - PROGRAM_START) as usize;
+        self.memory[idx..idx + 2].copy_from_slice(&Instruction::Jp(target).encode().to_be_bytes());
+        Ok(())
+    }
+}
+
+const BINARY: [&str; 19] = [
+    "+", "-", "*", "/", "%", "&", "|", "^", "<<", ">>", "pow", "min", "max", "<", ">", "<=", ">=", "==", "!=",
+];
+
+impl Condition {
+    fn not(self) -> Self {
+        match self {
+            Condition::Equal(x, rhs) => Condition::NotEqual(x, rhs),
+            Condition::NotEqual(x, rhs) => Condition::Equal(x, rhs),
+            Condition::Less(x, rhs) => Condition::GreaterEqual(x, rhs),
+            Condition::Greater(x, rhs) => Condition::LessEqual(x, rhs),
+            Condition::LessEqual(x, rhs) => Condition::Greater(x, rhs),
+            Condition::GreaterEqual(x, rhs) => Condition::Less(x, rhs),
+            Condition::Key(x) => Condition::NotKey(x),
+            Condition::NotKey(x) => Condition::Key(x),
+        }
+    }
+}
+
+// vF := rhs, the first half of an ordered comparison.
+fn load_compare(rhs: Rhs) -> Instruction {
+    match rhs {
+        Rhs::Byte(kk) => Instruction::LdByte(0xF, kk),
+        Rhs::Register(y) => Instruction::LdReg(0xF, y),
+    }
+}
+
+fn parse_number(token: &str) -> Option<i64> {
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, token),
+    };
+
+    let lower = digits.to_lowercase();
+    let value = if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else {
+        lower.parse().ok()?
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::{Disassembler, Format};
+    use crate::{Quirks, CHIP8};
+
+    fn words(source: &str) -> Vec<u16> {
+        let bytes = assemble(source).unwrap_or_else(|err| panic!("{}\n{}", err, source)).bytes;
+        bytes.chunks(2).map(|word| u16::from_be_bytes([word[0], word[1]])).collect()
+    }
+
+    // Runs the program until it's stuck in its last `: spin jump spin`.
+    fn registers(source: &str) -> [u8; 16] {
+        let bytes = assemble(source).unwrap_or_else(|err| panic!("{}\n{}", err, source)).bytes;
+        let mut cpu = CHIP8::new(bytes, Quirks::default()).unwrap();
+        for _ in 0..100 {
+            cpu.tick([false; 16]).unwrap();
+        }
+        *cpu.registers()
+    }
+
+    #[test]
+    fn if_then_skips_one_instruction() {
+        assert_eq!(words(": main if v1 == 60 then v1 := 0"), vec![0x413C, 0x6100]);
+        assert_eq!(words(": main if v1 != v2 then v1 := 0"), vec![0x5120, 0x6100]);
+        assert_eq!(words(": main if v3 key then v1 := 0"), vec![0xE3A1, 0x6100]);
+        assert_eq!(words(": main if v3 -key then v1 := 0"), vec![0xE39E, 0x6100]);
+    }
+
+    #[test]
+    fn if_begin_else_end_jumps_around() {
+        let source = ": main if v0 == 1 begin v1 := 1 else v1 := 2 end v2 := 3";
+        assert_eq!(words(source), vec![0x3001, 0x1208, 0x6101, 0x120A, 0x6102, 0x6203]);
+    }
+
+    #[test]
+    fn loops_jump_back_and_out() {
+        let source = ": main loop v0 += 1 while v0 != 5 v1 += 2 again v2 := 0";
+        assert_eq!(words(source), vec![0x7001, 0x4005, 0x120A, 0x7102, 0x1200, 0x6200]);
+        assert_eq!(registers(&format!("{} : spin jump spin", source))[..3], [5, 8, 0]);
+    }
+
+    #[test]
+    fn ordered_comparisons_expand_like_octo() {
+        // vF := rhs, vF -= x or vF =- x, then a skip on the borrow flag.
+        assert_eq!(words(": main if v1 > 5 then v2 := 1"), vec![0x6F05, 0x8F15, 0x3F01, 0x6201]);
+        assert_eq!(words(": main if v1 < 5 then v2 := 1"), vec![0x6F05, 0x8F17, 0x3F01, 0x6201]);
+        assert_eq!(words(": main if v1 >= v3 then v2 := 1"), vec![0x8F30, 0x8F17, 0x4F01, 0x6201]);
+        assert_eq!(words(": main if v1 <= v3 then v2 := 1"), vec![0x8F30, 0x8F15, 0x4F01, 0x6201]);
+
+        // begin and while test the opposite.
+        assert_eq!(words(": main if v1 > 5 begin end"), vec![0x6F05, 0x8F15, 0x4F01, 0x1208]);
+        assert_eq!(words(": main loop while v1 < 5 again"), vec![0x6F05, 0x8F17, 0x4F01, 0x120A, 0x1200]);
+    }
+
+    #[test]
+    fn ordered_comparisons_run_right() {
+        for &op in ["==", "!=", "<", ">", "<=", ">="].iter() {
+            for a in 0..=4u8 {
+                let source = format!("
+                    : main
+                        v0 := {a} v1 := 2
+                        if v0 {op} v1 then v2 := 1
+                        if v0 {op} 2 begin v3 := 1 else v3 := 2 end
+                        loop while v0 {op} v1 v4 := 1 jump spin again
+                    : spin jump spin
+                ", a = a, op = op);
+
+                let want = match op {
+                    "==" => a == 2,
+                    "!=" => a != 2,
+                    "<" => a < 2,
+                    ">" => a > 2,
+                    "<=" => a <= 2,
+                    _ => a >= 2,
+                };
+                let v = registers(&source);
+                assert_eq!((v[2], v[3], v[4]), (want as u8, 2 - want as u8, want as u8), "{} {} 2", a, op);
+            }
+        }
+    }
+
+    #[test]
+    fn calc_has_no_precedence() {
+        let source = "
+            :const BASE 3
+            :calc RIGHT { 2 * BASE + 1 }
+            :calc GROUPED { ( 2 * BASE ) + 1 }
+            : main v0 := RIGHT v1 := GROUPED v2 := BASE
+        ";
+        assert_eq!(words(source), vec![0x6008, 0x6107, 0x6203]);
+    }
+
+    #[test]
+    fn aliases_and_macros() {
+        let source = "
+            :alias x v5
+            :macro bump REG AMOUNT { REG += AMOUNT }
+            : main x := 1 bump x 2 bump v0 3
+        ";
+        assert_eq!(words(source), vec![0x6501, 0x7502, 0x7003]);
+    }
+
+    #[test]
+    fn main_goes_first() {
+        let assembly = assemble(": sprite 0xF0 0x90 : main i := sprite").unwrap();
+        assert_eq!(assembly.bytes, vec![0x12, 0x04, 0xF0, 0x90, 0xA2, 0x02]);
+        assert_eq!(assembly.labels["sprite"], 0x202);
+    }
+
+    #[test]
+    fn octo_disassembly_round_trips() {
+        let source = "
+            : main
+                clear
+                v0 := 0
+                loop
+                    i := ball
+                    sprite v0 v0 4
+                    v0 += 2
+                    if v0 > 60 then v0 := 0
+                    move
+                again
+            : move
+                vF := 3
+                delay := vF
+                return
+            : ball
+                0x60 0xF0 0xF0 0x60
+        ";
+        let bytes = assemble(source).unwrap().bytes;
+
+        let octo = Disassembler::new(&bytes).disassemble_as(Format::Octo);
+        let again = assemble(&octo).unwrap_or_else(|err| panic!("{}\n{}", err, octo));
+        assert_eq!(again.bytes, bytes, "{}", octo);
+        assert_eq!(Disassembler::new(&again.bytes).disassemble_as(Format::Octo), octo);
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        assert_eq!(assemble(": main\nif v0 <> 1 then v1 := 0").unwrap_err(), "Invalid comparison '<>' on line 2");
+        assert_eq!(assemble(": main else").unwrap_err(), "else without if ... begin on line 1");
+        assert_eq!(assemble(": main\n\nwhile v0 == 1").unwrap_err(), "while outside a loop on line 3");
+        assert_eq!(assemble(": start clear").unwrap_err(), "The program has no main label");
+    }
+}
@@ -0,0 +1,154 @@
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::Sdl;
+
+const SAMPLE_RATE: i32 = 44100;
+
+// How long the tone takes to fade in or out. Starting or stopping the wave
+// abruptly is what makes a buzzer click, a few milliseconds of ramp is enough.
+const RAMP_SECONDS: f32 = 0.005;
+
+#[derive(Clone, Copy, Debug)]
+pub struct AudioSettings {
+    pub frequency: f32, // Tone frequency in Hz
+    pub volume: f32,    // 0.0 (silent) to 1.0 (full scale)
+    pub muted: bool,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self {
+            frequency: 440.0,
+            volume: 0.25,
+            muted: false,
+        }
+    }
+}
+
+// XO-CHIP plays its 128 bit pattern at 4000 * 2^((pitch - 64) / 48) bits per second.
+fn pattern_rate(pitch: u8) -> f32 {
+    4000.0 * 2f32.powf((pitch as f32 - 64.0) / 48.0)
+}
+
+struct Buzzer {
+    rate: f32,       // Device sample rate
+    phase: f32,      // Position inside the current period (0.0 - 1.0)
+    phase_inc: f32,  // Phase advance per sample
+    pattern: Option<[u8; 16]>, // XO-CHIP audio pattern, played instead of the square wave
+    bit_pos: f32,    // Position inside the pattern (0.0 - 128.0)
+    bit_inc: f32,    // Pattern bits advanced per sample
+    amplitude: f32,  // Current envelope level
+    target: f32,     // Level the envelope is ramping towards
+    ramp_step: f32,  // Envelope change per sample
+    volume: f32,
+}
+
+impl Buzzer {
+    fn next_level(&mut self) -> f32 {
+        match self.pattern {
+            Some(pattern) => {
+                let bit = self.bit_pos as usize;
+                let set = (pattern[bit / 8] >> (7 - bit % 8)) & 1 == 1;
+                self.bit_pos = (self.bit_pos + self.bit_inc) % 128.0;
+
+                if set { 1.0 } else { -1.0 }
+            }
+
+            None => {
+                let level = if self.phase < 0.5 { 1.0 } else { -1.0 };
+                self.phase = (self.phase + self.phase_inc) % 1.0;
+
+                level
+            }
+        }
+    }
+}
+
+impl AudioCallback for Buzzer {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            if self.amplitude < self.target {
+                self.amplitude = (self.amplitude + self.ramp_step).min(self.target);
+            } else if self.amplitude > self.target {
+                self.amplitude = (self.amplitude - self.ramp_step).max(self.target);
+            }
+
+            // The waveform keeps running while silent so it never restarts mid-period.
+            *sample = self.next_level() * self.amplitude * self.volume;
+        }
+    }
+}
+
+pub struct Beeper {
+    device: AudioDevice<Buzzer>,
+    active: bool,
+    muted: bool,
+    pattern: Option<([u8; 16], u8)>,
+}
+
+impl Beeper {
+    pub fn new(sdl_ctx: &Sdl, settings: AudioSettings) -> Result<Self, String> {
+        let audio_subsys = sdl_ctx.audio()?;
+
+        let desired = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE),
+            channels: Some(1),
+            samples: Some(512),
+        };
+
+        let device = audio_subsys.open_playback(None, &desired, |spec| {
+            let rate = spec.freq as f32;
+
+            Buzzer {
+                rate,
+                phase: 0.0,
+                phase_inc: settings.frequency / rate,
+                pattern: None,
+                bit_pos: 0.0,
+                bit_inc: 0.0,
+                amplitude: 0.0,
+                target: 0.0,
+                ramp_step: 1.0 / (RAMP_SECONDS * rate),
+                volume: settings.volume.clamp(0.0, 1.0),
+            }
+        })?;
+
+        device.resume();
+
+        Ok(Self {
+            device,
+            active: false,
+            muted: settings.muted,
+            pattern: None,
+        })
+    }
+
+    pub fn set_active(&mut self, active: bool) {
+        if self.active != active {
+            self.active = active;
+            self.update();
+        }
+    }
+
+    // Switches between the plain tone (None) and an XO-CHIP pattern at the given pitch.
+    pub fn set_pattern(&mut self, pattern: Option<([u8; 16], u8)>) {
+        if self.pattern != pattern {
+            self.pattern = pattern;
+
+            let mut buzzer = self.device.lock();
+            buzzer.pattern = pattern.map(|(bits, _)| bits);
+            buzzer.bit_inc = pattern.map_or(0.0, |(_, pitch)| pattern_rate(pitch) / buzzer.rate);
+        }
+    }
+
+    pub fn toggle_mute(&mut self) {
+        self.muted = !self.muted;
+        self.update();
+    }
+
+    fn update(&mut self) {
+        let target = if self.active && !self.muted { 1.0 } else { 0.0 };
+        self.device.lock().target = target;
+    }
+}
@@ -1,7 +1,68 @@
-use rand::Rng;
+use crate::quirks::{LoadStore, Quirks};
+use crate::rng::Rng;
+use crate::error::Chip8Error;
+use crate::instruction::Instruction;
+use crate::screen::{self, Screen};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+/// The delay and sound timers count down at 60 Hz, so one frame is 1/60th of an emulated second.
+pub const FRAMES_PER_SECOND: u32 = 60;
+pub const DEFAULT_CYCLES_PER_FRAME: u32 = 10;
+
+/// Where programs are loaded and where execution starts.
+pub const PROGRAM_START: usize = 0x200;
+const BIG_FONT_START: usize = 0x50; // SUPER-CHIP 8x10 digits, right after the 4x5 ones
+const DEFAULT_PITCH: u8 = 64;       // XO-CHIP pitch register value for 4000 Hz playback
+pub const DEFAULT_SEED: u64 = 0;
+
+/// A data read or write made by an instruction. Fetching instructions doesn't count.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub addr: u16,
+    pub kind: AccessKind,
+    pub value: u8, // What was read, or what was written
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// The registers the instructions work with. The program counter is left out, it
+/// moves with every instruction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RegisterFile {
+    pub v: [u8; 16],
+    pub i: u16,
+    pub sp: u8, // Stack depth
+    pub dt: u8,
+    pub st: u8,
+}
+
+/// An instruction that just ran, with the registers before and after it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Executed {
+    pub frame: u64,
+    pub cycle: u64, // Counting from 0, the same as cycles() right before the tick
+    pub addr: u16,
+    pub opcode: u16,
+    pub before: RegisterFile,
+    pub after: RegisterFile,
+}
+
+type InstructionHook = Box<dyn FnMut(&Executed)>;
 
+/// How much of the machine reset() puts back the way it was at power on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResetKind {
+    Soft, // Registers, timers, the stack and the screen, the program stays in memory
+    Hard, // Also clears memory and the SUPER-CHIP flags and loads the fonts again, not the program
+}
+
+/// The interpreter: memory, registers, timers and the display.
 pub struct CHIP8 {
-    mem: [u8; 4096],  // Memory for Chip-8 (4 KB)
+    mem: Vec<u8>,     // Memory for Chip-8 (4 KB, or 64 KB for XO-CHIP)
     vx: [u8; 16],     // General Purpose Registers (V0 - VF)
     stk: [u16; 16],   // Stack
     pc: u16,          // Program Counter
@@ -9,9 +70,25 @@ pub struct CHIP8 {
     i: u16,           // Index Register (Used for storing memory addresses)
     dt: u8,           // Delay Timer Register
     st: u8,           // Sound Timer Register
-    pub screen: [[bool; 64]; 32],
+    screen: Screen,
+    rpl: [u8; 16],    // SUPER-CHIP RPL user flags (Fx75/Fx85)
+    planes: u8,       // XO-CHIP bitplanes selected for drawing (Fn01)
+    audio_pattern: Option<[u8; 16]>, // XO-CHIP 1-bit audio pattern (F002), None until one is loaded
+    pitch: u8,        // XO-CHIP playback rate of the audio pattern (Fx3A)
     key_waiting: bool,
-    key_reg: u8
+    key_reg: u8,
+    seed: u64,        // Seed the RNG started from, kept so a run can be reproduced
+    rng: Rng,         // Random number generator for Cxkk
+    quirks: Quirks,
+    vblank_wait: bool, // Set by Dxyn when the display wait quirk is on
+    cycles_per_frame: u32,
+    frame_cycles: u32,  // Instructions executed so far in the current frame
+    cycles: u64,        // Ticks since power on
+    frames: u64,        // Frames ended since power on
+    halted: Option<Chip8Error>,
+    exited: bool,     // Set by the SUPER-CHIP 00FD instruction
+    memory_hook: Option<Box<dyn FnMut(MemoryAccess)>>,
+    instruction_hook: Option<InstructionHook>,
 }
 
 enum PCAction {
@@ -21,9 +98,10 @@ enum PCAction {
 }
 
 impl CHIP8 {
-    pub fn new(program_bytes: Vec<u8>) -> Self {
+    /// Loads a ROM at 0x200 and the fonts below it. Fails if the ROM doesn't fit in memory.
+    pub fn new(program_bytes: Vec<u8>, quirks: Quirks) -> Result<Self, Chip8Error> {
         let mut chip = CHIP8 {
-            mem: [0; 4096],
+            mem: vec![0; quirks.memory_size()],
             vx: [0; 16],
             stk: [0; 16],
             pc: 0x200,
@@ -31,11 +109,34 @@ impl CHIP8 {
             i: 0,
             dt: 0,
             st: 0,
-            screen: [[false; 64]; 32],
+            screen: Screen::new(screen::LORES_WIDTH, screen::LORES_HEIGHT),
+            rpl: [0; 16],
+            planes: 1,
+            audio_pattern: None,
+            pitch: DEFAULT_PITCH,
             key_waiting: false,
             key_reg: 0,
+            seed: DEFAULT_SEED,
+            rng: Rng::new(DEFAULT_SEED),
+            quirks,
+            vblank_wait: false,
+            cycles_per_frame: DEFAULT_CYCLES_PER_FRAME,
+            frame_cycles: 0,
+            cycles: 0,
+            frames: 0,
+            halted: None,
+            exited: false,
+            memory_hook: None,
+            instruction_hook: None,
         };
 
+        chip.load_fonts();
+        chip.load_program(&program_bytes, PROGRAM_START as u16)?;
+
+        Ok(chip)
+    }
+
+    fn load_fonts(&mut self) {
         let nums: [[u8; 5]; 16] = [
             [0xF0, 0x90, 0x90, 0x90, 0xF0],
             [0x20, 0x60, 0x20, 0x20, 0x70],
@@ -55,25 +156,371 @@ impl CHIP8 {
             [0xF0, 0x80, 0xF0, 0x80, 0x80],
         ];
 
+        let big_nums: [[u8; 10]; 16] = [
+            [0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C],
+            [0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C],
+            [0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF],
+            [0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C],
+            [0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06],
+            [0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C],
+            [0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C],
+            [0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60],
+            [0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C],
+            [0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C],
+            [0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3],
+            [0xFC, 0xFE, 0xC3, 0xC3, 0xFE, 0xFE, 0xC3, 0xC3, 0xFE, 0xFC],
+            [0x3C, 0x7E, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0x7E, 0x3C],
+            [0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC],
+            [0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF],
+            [0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0],
+        ];
+
         let mut i: usize = 0;
 
         for num_data in nums.iter() {
             for &row in num_data.iter() {
-                chip.mem[i] = row;
+                self.mem[i] = row;
                 i += 1;
             }
         }
 
-        i = 0x200;
+        i = BIG_FONT_START;
 
-        for &byte in program_bytes.iter() {
-            if i < 0xFFFF {
-                chip.mem[i] = byte;
-                i += 0x001;
+        for num_data in big_nums.iter() {
+            for &row in num_data.iter() {
+                self.mem[i] = row;
+                i += 1;
             }
         }
+    }
+
+    /// Puts the machine back at 0x200 the way it was switched on. After a soft reset the
+    /// program still in memory starts over. A hard reset leaves only the fonts, the caller
+    /// loads the program again at PROGRAM_START with load_program (or uses reload, which
+    /// does both). The hooks, quirks, seed and speed stay, and so do the cycle and frame
+    /// counts. The random numbers start over from the seed.
+    pub fn reset(&mut self, kind: ResetKind) {
+        self.vx = [0; 16];
+        self.stk = [0; 16];
+        self.pc = PROGRAM_START as u16;
+        self.sp = -1;
+        self.i = 0;
+        self.dt = 0;
+        self.st = 0;
+        self.screen = Screen::new(screen::LORES_WIDTH, screen::LORES_HEIGHT);
+        self.planes = 1;
+        self.audio_pattern = None;
+        self.pitch = DEFAULT_PITCH;
+        self.key_waiting = false;
+        self.key_reg = 0;
+        self.rng = Rng::new(self.seed);
+        self.vblank_wait = false;
+        self.frame_cycles = 0;
+        self.halted = None;
+        self.exited = false;
+
+        if kind == ResetKind::Hard {
+            self.rpl = [0; 16];
+            self.mem.iter_mut().for_each(|byte| *byte = 0);
+            self.load_fonts();
+        }
+    }
+
+    /// Copies a program into memory at addr, over whatever was there. Memory is left
+    /// alone if it doesn't fit.
+    pub fn load_program(&mut self, program_bytes: &[u8], addr: u16) -> Result<(), Chip8Error> {
+        let start = addr as usize;
+        self.check_fits(program_bytes, start)?;
+
+        self.mem[start..start + program_bytes.len()].copy_from_slice(program_bytes);
+        Ok(())
+    }
+
+    /// Swaps in a new version of the program, replacing everything from 0x200 up. With
+    /// keep_registers the program carries on from where the old one was, otherwise the
+    /// machine gets a hard reset first. Either way a halt is forgotten. Nothing changes
+    /// if the program doesn't fit.
+    pub fn reload(&mut self, program_bytes: &[u8], keep_registers: bool) -> Result<(), Chip8Error> {
+        self.check_fits(program_bytes, PROGRAM_START)?;
+
+        if keep_registers {
+            self.mem[PROGRAM_START..].iter_mut().for_each(|byte| *byte = 0);
+            self.halted = None;
+            self.exited = false;
+        } else {
+            self.reset(ResetKind::Hard);
+        }
+
+        self.load_program(program_bytes, PROGRAM_START as u16)
+    }
+
+    fn check_fits(&self, program_bytes: &[u8], start: usize) -> Result<(), Chip8Error> {
+        let max = self.mem.len().saturating_sub(start);
+
+        if program_bytes.len() > max {
+            return Err(Chip8Error::RomTooLarge { size: program_bytes.len(), max });
+        }
+
+        Ok(())
+    }
+
+    /// How many instructions run between two timer ticks. At least one.
+    pub fn set_cycles_per_frame(&mut self, cycles: u32) {
+        self.cycles_per_frame = cycles.max(1);
+    }
+
+    /// Restarts the random number generator. Two machines with the same seed that get
+    /// the same keypad input produce exactly the same random numbers.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = Rng::new(seed);
+    }
+
+    pub fn screen(&self) -> &Screen {
+        &self.screen
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    /// V0 - VF
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.vx
+    }
+
+    /// Return addresses of the subroutines being run, outermost first.
+    pub fn stack(&self) -> &[u16] {
+        &self.stk[..(self.sp + 1) as usize]
+    }
+
+    /// Ticks since the machine was created, counting the ones spent waiting for a key
+    /// or the vertical blank. Two runs with the same seed and input agree on it.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Frames ended since the machine was created.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.dt
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.st
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.mem
+    }
+
+    /// Calls hook with every memory access instructions make from now on (Dxyn, Fx33,
+    /// Fx55, Fx65, ...). There's one hook at a time, setting it replaces the old one.
+    pub fn set_memory_hook<F>(&mut self, hook: F)
+    where F: FnMut(MemoryAccess) + 'static {
+        self.memory_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_memory_hook(&mut self) {
+        self.memory_hook = None;
+    }
 
-        chip
+    /// Calls hook after every instruction that runs without an error. Ticks spent
+    /// waiting for a key or the vertical blank don't run one.
+    pub fn set_instruction_hook<F>(&mut self, hook: F)
+    where F: FnMut(&Executed) + 'static {
+        self.instruction_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_instruction_hook(&mut self) {
+        self.instruction_hook = None;
+    }
+
+    // Runs f with both hooks detached, for replaying ticks the hooks have already seen.
+    pub(crate) fn without_hooks<R>(&mut self, f: impl FnOnce(&mut CHIP8) -> R) -> R {
+        let memory_hook = self.memory_hook.take();
+        let instruction_hook = self.instruction_hook.take();
+
+        let result = f(self);
+
+        self.memory_hook = memory_hook;
+        self.instruction_hook = instruction_hook;
+        result
+    }
+
+    pub fn register_file(&self) -> RegisterFile {
+        RegisterFile {
+            v: self.vx,
+            i: self.i,
+            sp: self.stack().len() as u8,
+            dt: self.dt,
+            st: self.st,
+        }
+    }
+
+    /// True while the next tick won't run an instruction: the program is waiting for a
+    /// key (Fx0A), for the vertical blank, or has exited.
+    pub fn waiting(&self) -> bool {
+        self.key_waiting || self.vblank_wait || self.exited
+    }
+
+    /// Set once the machine has hit an error. It won't run any more instructions after that.
+    pub fn halted(&self) -> Option<Chip8Error> {
+        self.halted
+    }
+
+    /// Set once a SUPER-CHIP program has executed 00FD. Nothing runs after that.
+    pub fn exited(&self) -> bool {
+        self.exited
+    }
+
+    /// The buzzer sounds for as long as the sound timer is non-zero.
+    pub fn buzzer_active(&self) -> bool {
+        self.st > 0
+    }
+
+    /// What the buzzer should play on XO-CHIP: a 128 bit pattern and its pitch.
+    /// None means the program never loaded a pattern, so the plain tone is used.
+    pub fn audio_pattern(&self) -> Option<([u8; 16], u8)> {
+        self.audio_pattern.map(|pattern| (pattern, self.pitch))
+    }
+
+    /// Serializes the whole machine. Loading the result with load_state puts the
+    /// machine back exactly where it was, down to the cycle within the frame.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+
+        w.u16(self.quirks.to_bits());
+        w.u32(self.mem.len() as u32);
+        w.bytes(&self.mem);
+        w.bytes(&self.vx);
+        for &addr in self.stk.iter() {
+            w.u16(addr);
+        }
+        w.u16(self.pc);
+        w.u16(self.sp as u16);
+        w.u16(self.i);
+        w.u8(self.dt);
+        w.u8(self.st);
+
+        w.u16(self.screen.width() as u16);
+        w.u16(self.screen.height() as u16);
+        w.bytes(self.screen.pixels());
+        w.bytes(&self.rpl);
+        w.u8(self.planes);
+        w.bool(self.audio_pattern.is_some());
+        w.bytes(&self.audio_pattern.unwrap_or([0; 16]));
+        w.u8(self.pitch);
+
+        w.bool(self.key_waiting);
+        w.u8(self.key_reg);
+        w.u64(self.seed);
+        w.u64(self.rng.state());
+        w.bool(self.vblank_wait);
+        w.u32(self.cycles_per_frame);
+        w.u32(self.frame_cycles);
+        w.u64(self.cycles);
+        w.u64(self.frames);
+        write_halt_reason(&mut w, self.halted);
+        w.bool(self.exited);
+
+        w.finish()
+    }
+
+    /// Restores a state written by save_state. Nothing is touched unless the whole state is valid.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(data)?;
+
+        let quirks = Quirks::from_bits(r.u16()?);
+        let mem_len = r.u32()? as usize;
+        if mem_len != quirks.memory_size() {
+            return Err(StateError::Corrupt("memory size"));
+        }
+        let mem = r.bytes(mem_len)?.to_vec();
+
+        let mut vx = [0; 16];
+        vx.copy_from_slice(r.bytes(16)?);
+        let mut stk = [0; 16];
+        for addr in stk.iter_mut() {
+            *addr = r.u16()?;
+        }
+        let pc = r.u16()?;
+        let sp = r.u16()? as i16;
+        if sp < -1 || sp >= stk.len() as i16 {
+            return Err(StateError::Corrupt("stack pointer"));
+        }
+        let i = r.u16()?;
+        let dt = r.u8()?;
+        let st = r.u8()?;
+
+        let width = r.u16()? as usize;
+        let height = r.u16()? as usize;
+        let valid_size = (width, height) == (screen::LORES_WIDTH, screen::LORES_HEIGHT)
+            || (width, height) == (screen::HIRES_WIDTH, screen::HIRES_HEIGHT);
+        if !valid_size {
+            return Err(StateError::Corrupt("screen size"));
+        }
+        let pixels = r.bytes(width * height)?.to_vec();
+        let screen = Screen::from_pixels(width, height, pixels).ok_or(StateError::Corrupt("screen"))?;
+        let mut rpl = [0; 16];
+        rpl.copy_from_slice(r.bytes(16)?);
+        let planes = r.u8()?;
+        let has_pattern = r.bool()?;
+        let mut pattern = [0; 16];
+        pattern.copy_from_slice(r.bytes(16)?);
+        let pitch = r.u8()?;
+
+        let key_waiting = r.bool()?;
+        let key_reg = r.u8()?;
+        if key_reg > 0xF {
+            return Err(StateError::Corrupt("key register"));
+        }
+        let seed = r.u64()?;
+        let rng = Rng::from_state(r.u64()?);
+        let vblank_wait = r.bool()?;
+        let cycles_per_frame = r.u32()?;
+        let frame_cycles = r.u32()?;
+        let cycles = r.u64()?;
+        let frames = r.u64()?;
+        let halted = read_halt_reason(&mut r)?;
+        let exited = r.bool()?;
+
+        r.finish()?;
+
+        self.quirks = quirks;
+        self.mem = mem;
+        self.vx = vx;
+        self.stk = stk;
+        self.pc = pc;
+        self.sp = sp;
+        self.i = i;
+        self.dt = dt;
+        self.st = st;
+        self.screen = screen;
+        self.rpl = rpl;
+        self.planes = planes & screen::ALL_PLANES;
+        self.audio_pattern = if has_pattern { Some(pattern) } else { None };
+        self.pitch = pitch;
+        self.key_waiting = key_waiting;
+        self.key_reg = key_reg;
+        self.seed = seed;
+        self.rng = rng;
+        self.vblank_wait = vblank_wait;
+        self.cycles_per_frame = cycles_per_frame.max(1);
+        self.frame_cycles = frame_cycles;
+        self.cycles = cycles;
+        self.frames = frames;
+        self.halted = halted;
+        self.exited = exited;
+
+        Ok(())
     }
 
     fn tick_delay_timer(&mut self) {
@@ -88,37 +535,121 @@ impl CHIP8 {
         }
     }
 
-    fn stack_push(&mut self, val: u16) -> Result<(), &str> {
+    // Where I ends up after Fx55/Fx65 with registers V0 - Vx.
+    fn advance_i(&mut self, x: u8) {
+        match self.quirks.load_store {
+            LoadStore::Unchanged => (),
+            LoadStore::PastLast => self.i = self.i.wrapping_add(x as u16 + 1),
+            LoadStore::Last => self.i = self.i.wrapping_add(x as u16),
+        }
+    }
+
+    fn stack_push(&mut self, val: u16) -> Result<(), Chip8Error> {
         if self.sp != (self.stk.len() - 1) as i16 {
             self.sp += 1;
             self.stk[self.sp as usize] = val;
             Ok(())
 
         } else {
-            Err("Stack overflow detected.")
+            Err(Chip8Error::StackOverflow { addr: self.pc })
         }
     }
 
-    fn stack_pop(&mut self) -> Result<u16, &str> {
+    fn stack_pop(&mut self) -> Result<u16, Chip8Error> {
         if self.sp >= 0 {
             let data = self.stk[self.sp as usize];
             self.sp -= 1;
             Ok(data)
 
         } else {
-            Err("Stack underflow detected.")
+            Err(Chip8Error::StackUnderflow { addr: self.pc })
+        }
+    }
+
+    // Reads part of an instruction. Unlike read_byte this isn't reported to the memory hook.
+    fn fetch_byte(&self, addr: usize) -> Result<u8, Chip8Error> {
+        match self.mem.get(addr) {
+            Some(&byte) => Ok(byte),
+            None => Err(Chip8Error::MemoryOutOfBounds { addr: self.pc, access: addr }),
+        }
+    }
+
+    fn read_byte(&mut self, addr: usize) -> Result<u8, Chip8Error> {
+        let value = self.fetch_byte(addr)?;
+        self.report(addr, AccessKind::Read, value);
+        Ok(value)
+    }
+
+    fn write_byte(&mut self, addr: usize, val: u8) -> Result<(), Chip8Error> {
+        let pc = self.pc;
+
+        match self.mem.get_mut(addr) {
+            Some(byte) => {
+                *byte = val;
+                self.report(addr, AccessKind::Write, val);
+                Ok(())
+            }
+
+            None => Err(Chip8Error::MemoryOutOfBounds { addr: pc, access: addr }),
+        }
+    }
+
+    fn report(&mut self, addr: usize, kind: AccessKind, value: u8) {
+        if let Some(hook) = self.memory_hook.as_mut() {
+            hook(MemoryAccess { addr: addr as u16, kind, value });
         }
     }
 
-    fn read_opcode(&self) -> u16 {
-        let opcode = ((self.mem[self.pc as usize] as u16) << 8) 
-        | (self.mem[(self.pc + 1) as usize] as u16);
-        opcode
+    fn read_opcode(&self) -> Result<u16, Chip8Error> {
+        let high = self.fetch_byte(self.pc as usize)? as u16;
+        let low = self.fetch_byte(self.pc as usize + 1)? as u16;
+
+        Ok((high << 8) | low)
     }
 
-    pub fn tick(&mut self, keypad: [bool; 16]) -> bool {
+    /// Runs instructions until the frame's cycle budget is spent (or Dxyn waits for the
+    /// vertical blank), then ticks the timers once. Returns whether the screen changed.
+    pub fn run_frame(&mut self, keypad: [bool; 16]) -> Result<bool, Chip8Error> {
+        let mut redraw = false;
+
+        while !self.frame_complete() {
+            redraw |= self.tick(keypad)?;
+        }
+
+        self.end_frame();
+
+        Ok(redraw)
+    }
+
+    /// Whether the current frame is over and end_frame should be called before the next tick.
+    pub fn frame_complete(&self) -> bool {
+        self.vblank_wait || self.frame_cycles >= self.cycles_per_frame
+    }
+
+    /// Ticks the timers and starts a new frame.
+    pub fn end_frame(&mut self) {
+        self.tick_sound_timer();
+        self.tick_delay_timer();
+
+        self.vblank_wait = false;
+        self.frame_cycles = 0;
+        self.frames += 1;
+    }
+
+    /// Executes a single instruction. Timers are left alone, they only move in end_frame.
+    /// If the instruction fails the machine halts, and every later tick returns the same error.
+    pub fn tick(&mut self, keypad: [bool; 16]) -> Result<bool, Chip8Error> {
+        if let Some(err) = self.halted {
+            return Err(err);
+        }
 
-        if self.key_waiting {
+        self.frame_cycles += 1;
+        self.cycles += 1;
+
+        if self.vblank_wait || self.exited {
+            Ok(false)
+
+        } else if self.key_waiting {
             for (i, &key) in keypad.iter().enumerate() {
                 if key {
                     self.key_waiting = false;
@@ -126,41 +657,81 @@ impl CHIP8 {
                 }
             }
 
-            false
+            Ok(false)
 
         } else {
-            self.tick_sound_timer();
-            self.tick_delay_timer();
+            let addr = self.pc;
+            let before = self.instruction_hook.as_ref().map(|_| self.register_file());
+
+            let result = self.read_opcode().and_then(|opcode| {
+                let result = self.exec_opcode(opcode, keypad);
+
+                if let (Ok(_), Some(before)) = (&result, before) {
+                    let executed = Executed {
+                        frame: self.frames,
+                        cycle: self.cycles - 1,
+                        addr,
+                        opcode,
+                        before,
+                        after: self.register_file(),
+                    };
+
+                    if let Some(hook) = self.instruction_hook.as_mut() {
+                        hook(&executed);
+                    }
+                }
 
-            let opcode = self.read_opcode();
+                result
+            });
 
-            self.exec_opcode(opcode, keypad)
+            if let Err(err) = result {
+                self.halted = Some(err);
+            }
+
+            result
         }
+    }
+
+    // Skipping over XO-CHIP's four byte F000 nnnn has to skip the whole thing.
+    fn skip_length(&self) -> u16 {
+        let next = self.pc.wrapping_add(2) as usize;
 
-        
+        if self.quirks.xo_chip && self.mem.get(next) == Some(&0xF0) && self.mem.get(next + 1) == Some(&0x00) {
+            6
+        } else {
+            4
+        }
     }
 
-    pub fn exec_opcode(&mut self, opcode: u16, keys: [bool; 16]) -> bool {
-        let units = (
-            ((opcode & 0xF000) >> 12) as usize,
-            ((opcode & 0x0F00) >> 8) as usize,
-            ((opcode & 0x00F0) >> 4) as usize,
-            (opcode & 0x000F) as usize
-        );
+    fn register_range(x: usize, y: usize) -> Box<dyn Iterator<Item = usize>> {
+        if x <= y {
+            Box::new(x..=y)
+        } else {
+            Box::new((y..=x).rev())
+        }
+    }
 
-        let mut screen_changed = false;
+    fn set_hires(&mut self, hires: bool) {
+        if hires {
+            self.screen.resize(screen::HIRES_WIDTH, screen::HIRES_HEIGHT);
+        } else {
+            self.screen.resize(screen::LORES_WIDTH, screen::LORES_HEIGHT);
+        }
+    }
+
+    /// Executes one opcode as if it had been read at the program counter.
+    pub fn exec_opcode(&mut self, opcode: u16, keys: [bool; 16]) -> Result<bool, Chip8Error> {
+        let instruction = Instruction::decode(opcode)
+            .map_err(|_| Chip8Error::InvalidOpcode { addr: self.pc, opcode })?;
 
-        //ncurses::addstr(&format!("{:x}\n", opcode));
+        let mut screen_changed = false;
 
-        let pc_action: PCAction = match units {
+        let pc_action: PCAction = match instruction {
             // 00E0 - CLS
             // Clears the screen
-            (0x0, 0x0, 0xE, 0x0) => {
-                for y in 0..64 {
-                    for x in 0..32 {
-                        self.screen[y][x] = false;
-                    }
-                }
+            // On XO-CHIP only the selected planes are cleared.
+            Instruction::Cls => {
+                self.screen.clear(self.planes);
 
                 screen_changed = true;
 
@@ -170,32 +741,85 @@ impl CHIP8 {
             // 00EE - RET
             // Return from a subroutine.
             // The interpreter sets the program counter to the address at the top of the stack, then subtracts 1 from the stack pointer.
-            (0x0, 0x0, 0xE, 0xE) => {
-                let pc_value = self.stack_pop().expect("Stack Error");
+            Instruction::Ret => {
+                let pc_value = self.stack_pop()?;
                 PCAction::Jump(pc_value)
             }
 
+            // 00Cn - SCD nibble (SUPER-CHIP)
+            // Scroll the display down by n pixels.
+            Instruction::Scd(n) if self.quirks.super_chip => {
+                self.screen.scroll_down(n as usize, self.planes);
+                screen_changed = true;
+                PCAction::Next
+            }
+
+            // 00Dn - SCU nibble (XO-CHIP)
+            // Scroll the display up by n pixels.
+            Instruction::Scu(n) if self.quirks.xo_chip => {
+                self.screen.scroll_up(n as usize, self.planes);
+                screen_changed = true;
+                PCAction::Next
+            }
+
+            // 00FB - SCR (SUPER-CHIP)
+            // Scroll the display right by 4 pixels.
+            Instruction::Scr if self.quirks.super_chip => {
+                self.screen.scroll_right(4, self.planes);
+                screen_changed = true;
+                PCAction::Next
+            }
+
+            // 00FC - SCL (SUPER-CHIP)
+            // Scroll the display left by 4 pixels.
+            Instruction::Scl if self.quirks.super_chip => {
+                self.screen.scroll_left(4, self.planes);
+                screen_changed = true;
+                PCAction::Next
+            }
+
+            // 00FD - EXIT (SUPER-CHIP)
+            // Exit the interpreter.
+            Instruction::Exit if self.quirks.super_chip => {
+                self.exited = true;
+                PCAction::Next
+            }
+
+            // 00FE - LOW (SUPER-CHIP)
+            // Switch to the 64x32 low resolution mode.
+            Instruction::Low if self.quirks.super_chip => {
+                self.set_hires(false);
+                screen_changed = true;
+                PCAction::Next
+            }
+
+            // 00FF - HIGH (SUPER-CHIP)
+            // Switch to the 128x64 high resolution mode.
+            Instruction::High if self.quirks.super_chip => {
+                self.set_hires(true);
+                screen_changed = true;
+                PCAction::Next
+            }
+
             // 1nnn - JP addr
             // Set the program counter to nnn.
-            (0x1, _, _, _) => {
-                let pc_addr = opcode & 0x0FFF;
-                PCAction::Jump(pc_addr)
+            Instruction::Jp(nnn) => {
+                PCAction::Jump(nnn)
             }
 
             // 2nnn - CALL addr
             // Calls subroutine from nnn
             // The interpreter increments the stack pointer, then puts the current PC on the top of the stack. The PC is then set to nnn.
-            (0x2, _, _, _) => {
-                self.stack_push(self.pc + 2).expect("Stack Error");
-                PCAction::Jump(opcode & 0x0FFF)
+            Instruction::Call(nnn) => {
+                self.stack_push(self.pc.wrapping_add(2))?;
+                PCAction::Jump(nnn)
             }
 
             // 3xkk - SE Vx, byte
             // Skip next instruction if Vx = kk.
-            (0x3, _, _, _) => {
-                let vx = self.vx[units.1];
-                let byte = (opcode & 0x00FF) as u8;
-                if vx == byte {
+            Instruction::SeByte(x, kk) => {
+                let vx = self.vx[x as usize];
+                if vx == kk {
                     PCAction::Skip
                 } else {
                     PCAction::Next
@@ -204,10 +828,9 @@ impl CHIP8 {
 
             // 4xkk - SNE Vx, byte
             // Skip next instruction if Vx != kk.
-            (0x4, _, _, _) => {
-                let vx = self.vx[units.1];
-                let byte = (opcode & 0x00FF) as u8;
-                if vx != byte {
+            Instruction::SneByte(x, kk) => {
+                let vx = self.vx[x as usize];
+                if vx != kk {
                     PCAction::Skip
                 } else {
                     PCAction::Next
@@ -216,9 +839,9 @@ impl CHIP8 {
 
             // 5xy0 - SE Vx, Vy
             // Skip next instruction if Vx = Vy.
-            (0x5, _, _, 0x0) => {
-                let vx = self.vx[units.1];
-                let vy = self.vx[units.2];
+            Instruction::SeReg(x, y) => {
+                let vx = self.vx[x as usize];
+                let vy = self.vx[y as usize];
                 if vx == vy {
                     PCAction::Skip
                 } else {
@@ -226,125 +849,148 @@ impl CHIP8 {
                 }
             }
 
+            // 5xy2 - LD [I], Vx - Vy (XO-CHIP)
+            // Store registers Vx through Vy in memory starting at location I. I is not changed.
+            // If x > y the registers are stored in reverse order.
+            Instruction::SaveRange(x, y) if self.quirks.xo_chip => {
+                for (offset, reg) in Self::register_range(x as usize, y as usize).enumerate() {
+                    self.write_byte(self.i as usize + offset, self.vx[reg])?;
+                }
+
+                PCAction::Next
+            }
+
+            // 5xy3 - LD Vx - Vy, [I] (XO-CHIP)
+            // Read registers Vx through Vy from memory starting at location I. I is not changed.
+            Instruction::LoadRange(x, y) if self.quirks.xo_chip => {
+                for (offset, reg) in Self::register_range(x as usize, y as usize).enumerate() {
+                    self.vx[reg] = self.read_byte(self.i as usize + offset)?;
+                }
+
+                PCAction::Next
+            }
+
             // 6xkk - LD Vx, byte
             // Set Vx = kk.
-            (0x6, _, _, _) => {
-                let new_val = (opcode & 0x00FF) as u8;
-                self.vx[units.1] = new_val;
+            Instruction::LdByte(x, kk) => {
+                self.vx[x as usize] = kk;
                 PCAction::Next
             }
 
             // 7xkk - ADD Vx, byte
             // Set Vx = Vx + kk.
-            (0x7, _, _, _) => {
-                let vx_val = self.vx[units.1];
-                let byte = (opcode & 0x00FF) as u8;
-                self.vx[units.1] = vx_val.wrapping_add(byte);
+            Instruction::AddByte(x, kk) => {
+                self.vx[x as usize] = self.vx[x as usize].wrapping_add(kk);
                 PCAction::Next
             }
 
             // 8xy0 - LD Vx, Vy
             // Set Vx = Vy
-            (0x8, _, _, 0x0) => {
-                self.vx[units.1] = self.vx[units.2];
+            Instruction::LdReg(x, y) => {
+                self.vx[x as usize] = self.vx[y as usize];
                 PCAction::Next
             }
 
             // 8xy1 - OR Vx, Vy
             // Set Vx = Vx OR Vy.
-            (0x8, _, _, 0x1) => {
-                let vx = self.vx[units.1];
-                let vy = self.vx[units.2];
-                self.vx[units.1] = vx | vy;
+            Instruction::Or(x, y) => {
+                let vx = self.vx[x as usize];
+                let vy = self.vx[y as usize];
+                self.vx[x as usize] = vx | vy;
+                if self.quirks.vf_reset {
+                    self.vx[0xF] = 0;
+                }
                 PCAction::Next
             }
 
             // 8xy2 - AND Vx, Vy
             // Set Vx = Vx AND Vy
-            (0x8, _, _, 0x2) => {
-                let vx = self.vx[units.1];
-                let vy = self.vx[units.2];
-                self.vx[units.1] = vx & vy;
+            Instruction::And(x, y) => {
+                let vx = self.vx[x as usize];
+                let vy = self.vx[y as usize];
+                self.vx[x as usize] = vx & vy;
+                if self.quirks.vf_reset {
+                    self.vx[0xF] = 0;
+                }
                 PCAction::Next
             }
 
             // 8xy3 - XOR Vx, Vy
             // Set Vx = Vx XOR Vy
-            (0x8, _, _, 0x3) => {
-                let vx = self.vx[units.1];
-                let vy = self.vx[units.2];
-                self.vx[units.1] = vx ^ vy;
+            Instruction::Xor(x, y) => {
+                let vx = self.vx[x as usize];
+                let vy = self.vx[y as usize];
+                self.vx[x as usize] = vx ^ vy;
+                if self.quirks.vf_reset {
+                    self.vx[0xF] = 0;
+                }
                 PCAction::Next
             }
 
             // 8xy4 - ADD Vx, Vy
             // Set Vx = Vx + Vy, set VF = carry.
-            (0x8, _, _, 0x4) => {
-                let vx = self.vx[units.1];
-                let vy = self.vx[units.2];
+            Instruction::AddReg(x, y) => {
+                let vx = self.vx[x as usize];
+                let vy = self.vx[y as usize];
                 let addn = vx as u16 + vy as u16;
                 if addn > 0xFF {
-                    self.vx[0xF as usize] = 1;
+                    self.vx[0xF] = 1;
                 } else {
-                    self.vx[0xF as usize] = 0;
+                    self.vx[0xF] = 0;
                 }
-                self.vx[units.1] = addn as u8;
+                self.vx[x as usize] = addn as u8;
                 PCAction::Next
             }
 
             // 8xy5 - SUB Vx, Vy
             // Set Vx = Vx - Vy, set VF = NOT Borrow
-            (0x8, _, _, 0x5) => {
-                let vx = self.vx[units.1];
-                let vy = self.vx[units.2];
-                if vx > vy {
-                    self.vx[0xF as usize] = 1;
-                } else {
-                    self.vx[0xF as usize] = 0;
-                }
-                self.vx[units.1] = vx.wrapping_sub(vy);
+            // VF is written last so that it holds the flag even when x is F, Octo's
+            // comparisons depend on that.
+            Instruction::Sub(x, y) => {
+                let vx = self.vx[x as usize];
+                let vy = self.vx[y as usize];
+                self.vx[x as usize] = vx.wrapping_sub(vy);
+                self.vx[0xF] = if vx >= vy {1} else {0};
                 PCAction::Next
             }
 
             // 8xy6 - SHR Vx {, Vy}
             // Set Vx = Vx SHR 1.
             // If the least-significant bit of Vx is 1, then VF is set to 1, otherwise 0. Then Vx is divided by 2.
-            (0x8, _, _, 0x6) => {
-                let vx = self.vx[units.1];
-                self.vx[0xF as usize] = vx & 0x01;
-                self.vx[units.1] = vx >> 1;
+            // Without the shift quirk, Vy is shifted and the result stored in Vx.
+            Instruction::Shr(x, y) => {
+                let vx = if self.quirks.shift { self.vx[x as usize] } else { self.vx[y as usize] };
+                self.vx[0xF] = vx & 0x01;
+                self.vx[x as usize] = vx >> 1;
                 PCAction::Next
             }
 
             // 8xy7 - SUBN Vx, Vy
-            // Set Vx = Vy - Vx, set VF = NOT borrow.
-            (0x8, _, _, 0x7) => {
-                let vx = self.vx[units.1];
-                let vy = self.vx[units.2];
-                if vy > vx {
-                    self.vx[0xF as usize] = 1;
-                } else {
-                    self.vx[0xF as usize] = 0;
-                }
-                self.vx[units.1] = vy.wrapping_sub(vx);
+            // Set Vx = Vy - Vx, set VF = NOT borrow. As with 8xy5, VF is written last.
+            Instruction::Subn(x, y) => {
+                let vx = self.vx[x as usize];
+                let vy = self.vx[y as usize];
+                self.vx[x as usize] = vy.wrapping_sub(vx);
+                self.vx[0xF] = if vy >= vx {1} else {0};
                 PCAction::Next
             }
 
             // 8xyE - SHL Vx {, Vy}
             // Set Vx = Vx SHL 1.
             // If the most-significant bit of Vx is 1, then VF is set to 1, otherwise to 0. Then Vx is multiplied by 2.
-            (0x8, _, _, 0xE) => {
-                let vx = self.vx[units.1];
-                self.vx[0xF as usize] = (vx & 0x80) >> 7;
-                self.vx[units.1] = vx << 1;
+            // Without the shift quirk, Vy is shifted and the result stored in Vx.
+            Instruction::Shl(x, y) => {
+                let vx = if self.quirks.shift { self.vx[x as usize] } else { self.vx[y as usize] };
+                self.vx[0xF] = (vx & 0x80) >> 7;
+                self.vx[x as usize] = vx << 1;
                 PCAction::Next
             }
 
             // 9xy0 - SNE Vx, Vy
             // Skip next instruction if Vx != Vy.
-            (0x9, _, _, 0x0) => {
-                let vx = self.vx[units.1];
-                let vy = self.vx[units.2];
+            Instruction::SneReg(x, y) => {
+                let vx = self.vx[x as usize];
+                let vy = self.vx[y as usize];
                 if vx != vy {
                     PCAction::Skip
                 } else {
@@ -354,24 +1000,24 @@ impl CHIP8 {
 
             // Annn - LD I, addr
             // Set I = nnn
-            (0xA, _, _, _) => {
-                self.i = opcode & 0x0FFF;
+            Instruction::LdI(nnn) => {
+                self.i = nnn;
                 PCAction::Next
             }
 
             // Bnnn - JP V0, addr
             // Jump to location nnn + V0.
-            (0xB, _, _, _) => {
-                PCAction::Jump((opcode & 0x0FFF) + self.vx[0 as usize] as u16)
+            // With the jump quirk this is Bxnn, which jumps to location xnn + Vx.
+            Instruction::JpV0(nnn) => {
+                let reg = if self.quirks.jump { (nnn >> 8) as usize } else { 0 };
+                PCAction::Jump(nnn + self.vx[reg] as u16)
             }
 
             // Cxkk - RND Vx, byte
             // Set Vx = random byte AND kk.
-            (0xC, _, _, _) => {
-                let mut rng = rand::thread_rng();
-                let rand_n = rng.gen::<u8>();
-                let byte = (opcode & 0x00FF) as u8;
-                self.vx[units.1] = rand_n & byte;
+            Instruction::Rnd(x, kk) => {
+                let rand_n = self.rng.next_u8();
+                self.vx[x as usize] = rand_n & kk;
                 PCAction::Next
             }
 
@@ -384,36 +1030,74 @@ impl CHIP8 {
                 If this causes any pixels to be erased, VF is set to 1, otherwise it is set to 0.
                 If the sprite is positioned so part of it is outside the coordinates of the display,
                 it wraps around to the opposite side of the screen.
+                With the clipping quirk only the starting position wraps, and the parts of the
+                sprite that fall outside the display are not drawn.
+
+                Dxy0 - DRW Vx, Vy, 0 (SUPER-CHIP)
+                Same as above, but draws a 16x16 sprite made of 32 bytes (two per row).
+
+                On XO-CHIP the sprite is drawn to every selected plane. When both planes are
+                selected the data for the second plane follows right after the first's.
 
             */
-            (0xD, _, _, _) => {
-                let s_x = self.vx[units.1];
-                let s_y = self.vx[units.2];
-                let y_max = units.3;
+            Instruction::Drw(x, y, n) => {
+                let width = self.screen.width();
+                let height = self.screen.height();
+
+                let (sprite_w, sprite_h) = if n == 0 { (16, 16) } else { (8, n as usize) };
+                let row_bytes = sprite_w / 8;
+
+                let s_x = self.vx[x as usize] as usize % width;
+                let s_y = self.vx[y as usize] as usize % height;
                 let mut collision = false;
+                let mut addr = self.i as usize;
+
+                for plane_idx in 0..screen::PLANE_COUNT {
+                    let plane = 1 << plane_idx;
+
+                    if self.planes & plane == 0 {
+                        continue;
+                    }
+
+                    for y in 0..sprite_h {
+                        let mut row: u16 = 0;
+                        for b in 0..row_bytes {
+                            let byte = self.read_byte(addr + y * row_bytes + b)?;
+                            row = (row << 8) | byte as u16;
+                        }
+
+                        if self.quirks.clipping && s_y + y >= height {
+                            continue;
+                        }
+
+                        let y_val = (s_y + y) % height;
 
-                for y in 0..y_max {
-                    let y_val = ((s_y as usize + y) % 32) as usize;
-                    let byte = self.mem[self.i as usize + y];
+                        for x in 0..sprite_w {
+                            if self.quirks.clipping && s_x + x >= width {
+                                break;
+                            }
 
-                    for x in 0..8 {
-                        let x_val = ((s_x + x) % 64) as usize;
-                        let pix_val = ((byte >> (7 - x)) & 1) == 1;
-                        let drawn = pix_val ^ self.screen[y_val][x_val];
-                        if pix_val && self.screen[y_val][x_val] {
-                            collision = true;
+                            let x_val = (s_x + x) % width;
+                            let pix_val = ((row >> (sprite_w - 1 - x)) & 1) == 1;
+                            if pix_val && self.screen.flip(x_val, y_val, plane) {
+                                collision = true;
+                            }
                         }
-                        self.screen[y_val][x_val] = drawn;
                     }
+
+                    addr += sprite_h * row_bytes;
                 }
 
                 if collision {
-                    self.vx[0xF as usize] = 1;
+                    self.vx[0xF] = 1;
                 } else {
-                    self.vx[0xF as usize] = 0;
+                    self.vx[0xF] = 0;
+                }
+
+                if self.quirks.display_wait {
+                    self.vblank_wait = true;
                 }
 
-                
                 screen_changed = true;
 
                 PCAction::Next
@@ -421,8 +1105,9 @@ impl CHIP8 {
 
             // Ex9E - SKP Vx
             // Skip next instruction if key with the value of Vx is pressed.
-            (0xE, _, 0x9, 0xE) => {
-                let vx = self.vx[units.1];
+            // Only the low nibble of Vx selects the key.
+            Instruction::Skp(x) => {
+                let vx = self.vx[x as usize] & 0xF;
 
                 if keys[vx as usize] {
                     PCAction::Skip
@@ -433,8 +1118,8 @@ impl CHIP8 {
 
             // ExA1 - SKNP Vx
             // Skip next instruction if key with the value of Vx is not pressed.
-            (0xE, _, 0xA, 0x1) => {
-                let vx = self.vx[units.1];
+            Instruction::Sknp(x) => {
+                let vx = self.vx[x as usize] & 0xF;
 
                 if !keys[vx as usize] {
                     PCAction::Skip
@@ -445,90 +1130,207 @@ impl CHIP8 {
 
             // Fx07 - LD Vx, DT
             // Set Vx = delay timer value.
-            (0xF, _, 0x0, 0x7) => {
-                self.vx[units.1] = self.dt;
+            Instruction::LdVxDt(x) => {
+                self.vx[x as usize] = self.dt;
                 PCAction::Next
             }
 
             // Fx0A - LD Vx, K
             // Wait for a key press, store the value of the key in Vx.
-            (0xF, _, 0x0, 0xA) => {
+            Instruction::LdVxK(x) => {
                 self.key_waiting = true;
-                self.key_reg = units.1 as u8;
+                self.key_reg = x;
 
                 PCAction::Next
             }
 
             // Fx15 - LD DT, Vx
             // Set delay timer = Vx.
-            (0xF, _, 0x1, 0x5) => {
-                self.dt = self.vx[units.1];
+            Instruction::LdDtVx(x) => {
+                self.dt = self.vx[x as usize];
                 PCAction::Next
             }
 
             // Fx18 - LD ST, Vx
             // Set sound timer = Vx.
-            (0xF, _, 0x1, 0x8) => {
-                self.st = self.vx[units.1];
+            Instruction::LdStVx(x) => {
+                self.st = self.vx[x as usize];
                 PCAction::Next
             }
 
             // Fx1E - ADD I, Vx
-            // Set I = I + Vx.
-            (0xF, _, 0x1, 0xE) => {
-                self.i += self.vx[units.1] as u16;
-                self.vx[0xF as usize] = if self.i > 0x0F00 {1} else {0};
+            // Set I = I + Vx. With the index overflow quirk VF is set if I ends up past 0x0F00.
+            Instruction::AddI(x) => {
+                self.i = self.i.wrapping_add(self.vx[x as usize] as u16);
+                if self.quirks.index_overflow {
+                    self.vx[0xF] = if self.i > 0x0F00 {1} else {0};
+                }
+                PCAction::Next
+            }
+
+            // F000 nnnn - LD I, long nnnn (XO-CHIP)
+            // Set I = the 16 bit address stored in the word after this instruction.
+            Instruction::LdILong if self.quirks.xo_chip => {
+                let high = self.fetch_byte(self.pc as usize + 2)? as u16;
+                let low = self.fetch_byte(self.pc as usize + 3)? as u16;
+                self.i = (high << 8) | low;
+                PCAction::Jump(self.pc.wrapping_add(4))
+            }
+
+            // Fn01 - PLANE n (XO-CHIP)
+            // Select the bitplanes that drawing, clearing and scrolling work on.
+            Instruction::Plane(n) if self.quirks.xo_chip => {
+                self.planes = n & screen::ALL_PLANES;
+                PCAction::Next
+            }
+
+            // F002 - AUDIO (XO-CHIP)
+            // Load the 16 byte audio pattern buffer from memory starting at location I.
+            Instruction::Audio if self.quirks.xo_chip => {
+                let mut pattern = [0; 16];
+                for (offset, byte) in pattern.iter_mut().enumerate() {
+                    *byte = self.read_byte(self.i as usize + offset)?;
+                }
+
+                self.audio_pattern = Some(pattern);
+                PCAction::Next
+            }
+
+            // Fx3A - PITCH Vx (XO-CHIP)
+            // Set the audio pattern playback rate to 4000 * 2^((Vx - 64) / 48) Hz.
+            Instruction::Pitch(x) if self.quirks.xo_chip => {
+                self.pitch = self.vx[x as usize];
                 PCAction::Next
             }
 
             // Fx29 - LD F, Vx
             // Set I = location of sprite for digit Vx.
-            (0xF, _, 0x2, 0x9) => {
-                self.i = self.vx[units.1] as u16 * 5;
+            Instruction::LdF(x) => {
+                self.i = self.vx[x as usize] as u16 * 5;
+                PCAction::Next
+            }
+
+            // Fx30 - LD HF, Vx (SUPER-CHIP)
+            // Set I = location of the 8x10 sprite for digit Vx.
+            Instruction::LdHf(x) if self.quirks.super_chip => {
+                self.i = BIG_FONT_START as u16 + (self.vx[x as usize] & 0xF) as u16 * 10;
                 PCAction::Next
             }
 
             // Fx33 - LD B, Vx
             // Store BCD representation of Vx in memory locations I, I+1, and I+2.
-            (0xF, _, 0x3, 0x3) => {
-                let vx = self.vx[units.1];
-                self.mem[self.i as usize] = vx / 100;
-                self.mem[(self.i + 1) as usize] = (vx % 100) / 10;
-                self.mem[(self.i + 2) as usize] = vx % 10;
+            Instruction::LdB(x) => {
+                let vx = self.vx[x as usize];
+                self.write_byte(self.i as usize, vx / 100)?;
+                self.write_byte(self.i as usize + 1, (vx % 100) / 10)?;
+                self.write_byte(self.i as usize + 2, vx % 10)?;
 
                 PCAction::Next
             }
 
             // Fx55 - LD [I], Vx
             // Store registers V0 through Vx in memory starting at location I.
-            (0xF, _, 0x5, 0x5) => {
-                for x in 0..=units.1 {
-                    self.mem[self.i as usize + x] = self.vx[x];
+            // The load/store quirk decides whether I is left pointing past the last stored
+            // register, at it, or where it was.
+            Instruction::LdIVx(x) => {
+                for reg in 0..=x as usize {
+                    self.write_byte(self.i as usize + reg, self.vx[reg])?;
                 }
 
+                self.advance_i(x);
+
                 PCAction::Next
             }
 
             // Fx65 - LD Vx, [I]
             // Read registers V0 through Vx from memory starting at location I.
-            (0xF, _, 0x6, 0x5) => {
-                for x in 0..=units.1 {
-                    self.vx[x] = self.mem[self.i as usize + x];
+            // I moves the same way as for Fx55.
+            Instruction::LdVxI(x) => {
+                for reg in 0..=x as usize {
+                    self.vx[reg] = self.read_byte(self.i as usize + reg)?;
                 }
 
+                self.advance_i(x);
+
                 PCAction::Next
             }
 
-            // Just go ahead if illegal opcode detected.
-            _ => PCAction::Next
+            // Fx75 - LD R, Vx (SUPER-CHIP)
+            // Store V0 through Vx in the RPL user flags. XO-CHIP allows all 16 registers.
+            Instruction::LdRVx(x) if self.quirks.super_chip => {
+                self.rpl[..=x as usize].copy_from_slice(&self.vx[..=x as usize]);
+                PCAction::Next
+            }
+
+            // Fx85 - LD Vx, R (SUPER-CHIP)
+            // Read V0 through Vx from the RPL user flags.
+            Instruction::LdVxR(x) if self.quirks.super_chip => {
+                self.vx[..=x as usize].copy_from_slice(&self.rpl[..=x as usize]);
+                PCAction::Next
+            }
+
+            // 0nnn - SYS addr
+            // Jump to a machine code routine at nnn. Only the original hardware could do this,
+            // so like every other interpreter we ignore it. 00Dn is just another SYS outside XO-CHIP.
+            Instruction::Sys(_) | Instruction::Scu(_) => PCAction::Next,
+
+            // The remaining SUPER-CHIP and XO-CHIP instructions, with their quirks off.
+            _ => return Err(Chip8Error::InvalidOpcode { addr: self.pc, opcode })
         };
 
         match pc_action {
-            PCAction::Next => self.pc += 2,
-            PCAction::Skip => self.pc += 4,
+            PCAction::Next => self.pc = self.pc.wrapping_add(2),
+            PCAction::Skip => self.pc = self.pc.wrapping_add(self.skip_length()),
             PCAction::Jump(addr) => self.pc = addr,
         }
-        
-        screen_changed
+
+        Ok(screen_changed)
+    }
+}
+fn write_halt_reason(w: &mut StateWriter, halted: Option<Chip8Error>) {
+    match halted {
+        None => w.u8(0),
+
+        Some(Chip8Error::StackOverflow { addr }) => {
+            w.u8(1);
+            w.u16(addr);
+        }
+
+        Some(Chip8Error::StackUnderflow { addr }) => {
+            w.u8(2);
+            w.u16(addr);
+        }
+
+        Some(Chip8Error::MemoryOutOfBounds { addr, access }) => {
+            w.u8(3);
+            w.u16(addr);
+            w.u32(access as u32);
+        }
+
+        Some(Chip8Error::RomTooLarge { size, max }) => {
+            w.u8(4);
+            w.u32(size as u32);
+            w.u32(max as u32);
+        }
+
+        Some(Chip8Error::InvalidOpcode { addr, opcode }) => {
+            w.u8(5);
+            w.u16(addr);
+            w.u16(opcode);
+        }
     }
-}
\ No newline at end of file
+}
+
+fn read_halt_reason(r: &mut StateReader) -> Result<Option<Chip8Error>, StateError> {
+    let reason = match r.u8()? {
+        0 => None,
+        1 => Some(Chip8Error::StackOverflow { addr: r.u16()? }),
+        2 => Some(Chip8Error::StackUnderflow { addr: r.u16()? }),
+        3 => Some(Chip8Error::MemoryOutOfBounds { addr: r.u16()?, access: r.u32()? as usize }),
+        4 => Some(Chip8Error::RomTooLarge { size: r.u32()? as usize, max: r.u32()? as usize }),
+        5 => Some(Chip8Error::InvalidOpcode { addr: r.u16()?, opcode: r.u16()? }),
+        _ => return Err(StateError::Corrupt("halt reason")),
+    };
+
+    Ok(reason)
+}
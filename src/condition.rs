@@ -0,0 +1,296 @@
+//! Conditions over the machine state, like `V3 == 0x10 && I > 0x300`.
+//!
+//! Operands are the registers `V0`-`VF`, `I`, `PC`, `SP` (stack depth), `DT` and `ST`,
+//! numbers (decimal, or hex with `0x`) and memory bytes written as `[addr]`, where the
+//! address is itself an operand: `[I]`, `[0x3A0]`. They are compared with `==`, `!=`,
+//! `<`, `<=`, `>` and `>=`, combined with `&&` and `||`, and grouped with parentheses.
+//! An operand on its own is true when it isn't zero.
+
+use crate::chip8::CHIP8;
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Register {
+    V(u8),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+}
+
+impl Register {
+    pub fn from_name(name: &str) -> Option<Self> {
+        let upper = name.to_uppercase();
+
+        let reg = match upper.as_str() {
+            "I" => Register::I,
+            "PC" => Register::Pc,
+            "SP" => Register::Sp,
+            "DT" => Register::Dt,
+            "ST" => Register::St,
+            _ if upper.len() == 2 && upper.starts_with('V') => {
+                Register::V(u8::from_str_radix(&upper[1..], 16).ok()?)
+            }
+            _ => return None,
+        };
+
+        Some(reg)
+    }
+
+    pub fn value(&self, cpu: &CHIP8) -> u16 {
+        match *self {
+            Register::V(x) => cpu.registers()[x as usize & 0xF] as u16,
+            Register::I => cpu.i(),
+            Register::Pc => cpu.pc(),
+            Register::Sp => cpu.stack().len() as u16,
+            Register::Dt => cpu.delay_timer() as u16,
+            Register::St => cpu.sound_timer() as u16,
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Register::V(x) => write!(f, "V{:X}", x),
+            Register::I => write!(f, "I"),
+            Register::Pc => write!(f, "PC"),
+            Register::Sp => write!(f, "SP"),
+            Register::Dt => write!(f, "DT"),
+            Register::St => write!(f, "ST"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Register(Register),
+    Number(u16),
+    Memory(Box<Operand>),
+}
+
+impl Operand {
+    pub fn value(&self, cpu: &CHIP8) -> u16 {
+        match self {
+            Operand::Register(reg) => reg.value(cpu),
+            Operand::Number(val) => *val,
+            Operand::Memory(addr) => {
+                cpu.memory().get(addr.value(cpu) as usize).map_or(0, |&byte| byte as u16)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Register(reg) => write!(f, "{}", reg),
+            Operand::Number(val) => write!(f, "{:#x}", val),
+            Operand::Memory(addr) => write!(f, "[{}]", addr),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    fn holds(&self, left: u16, right: u16) -> bool {
+        match self {
+            Comparison::Eq => left == right,
+            Comparison::Ne => left != right,
+            Comparison::Lt => left < right,
+            Comparison::Le => left <= right,
+            Comparison::Gt => left > right,
+            Comparison::Ge => left >= right,
+        }
+    }
+
+    fn symbol(&self) -> &'static str {
+        match self {
+            Comparison::Eq => "==",
+            Comparison::Ne => "!=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Condition {
+    True(Operand), // Non-zero
+    Compare(Operand, Comparison, Operand),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens: &tokens, pos: 0 };
+
+        let condition = parser.or()?;
+
+        match parser.peek() {
+            None => Ok(condition),
+            Some(token) => Err(format!("Unexpected '{}' in condition", token)),
+        }
+    }
+
+    pub fn holds(&self, cpu: &CHIP8) -> bool {
+        match self {
+            Condition::True(operand) => operand.value(cpu) != 0,
+            Condition::Compare(left, cmp, right) => cmp.holds(left.value(cpu), right.value(cpu)),
+            Condition::And(left, right) => left.holds(cpu) && right.holds(cpu),
+            Condition::Or(left, right) => left.holds(cpu) || right.holds(cpu),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Condition::True(operand) => write!(f, "{}", operand),
+            Condition::Compare(left, cmp, right) => write!(f, "{} {} {}", left, cmp.symbol(), right),
+            Condition::And(left, right) => write!(f, "{} && {}", left, right),
+            Condition::Or(left, right) => write!(f, "({} || {})", left, right),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_alphanumeric() {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphanumeric()) {
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(word);
+        } else {
+            chars.next();
+
+            let token = match (c, chars.peek()) {
+                ('=', Some('=')) | ('!', Some('=')) | ('<', Some('=')) | ('>', Some('=')) |
+                ('&', Some('&')) | ('|', Some('|')) => {
+                    let second = chars.next().unwrap();
+                    format!("{}{}", c, second)
+                }
+                ('<', _) | ('>', _) | ('(', _) | (')', _) | ('[', _) | (']', _) => c.to_string(),
+                _ => return Err(format!("Unexpected '{}' in condition", c)),
+            };
+
+            tokens.push(token);
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [String],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(format!("Expected '{}' but found '{}' in condition", expected, token)),
+            None => Err(format!("Expected '{}' at the end of the condition", expected)),
+        }
+    }
+
+    fn or(&mut self) -> Result<Condition, String> {
+        let mut condition = self.and()?;
+
+        while self.peek() == Some("||") {
+            self.next();
+            condition = Condition::Or(Box::new(condition), Box::new(self.and()?));
+        }
+
+        Ok(condition)
+    }
+
+    fn and(&mut self) -> Result<Condition, String> {
+        let mut condition = self.comparison()?;
+
+        while self.peek() == Some("&&") {
+            self.next();
+            condition = Condition::And(Box::new(condition), Box::new(self.comparison()?));
+        }
+
+        Ok(condition)
+    }
+
+    fn comparison(&mut self) -> Result<Condition, String> {
+        if self.peek() == Some("(") {
+            self.next();
+            let condition = self.or()?;
+            self.expect(")")?;
+            return Ok(condition);
+        }
+
+        let left = self.operand()?;
+
+        let cmp = match self.peek() {
+            Some("==") => Comparison::Eq,
+            Some("!=") => Comparison::Ne,
+            Some("<") => Comparison::Lt,
+            Some("<=") => Comparison::Le,
+            Some(">") => Comparison::Gt,
+            Some(">=") => Comparison::Ge,
+            _ => return Ok(Condition::True(left)),
+        };
+        self.next();
+
+        Ok(Condition::Compare(left, cmp, self.operand()?))
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        let token = self.next().ok_or("Condition ends too early")?;
+
+        if token == "[" {
+            let addr = self.operand()?;
+            self.expect("]")?;
+            return Ok(Operand::Memory(Box::new(addr)));
+        }
+
+        if let Some(reg) = Register::from_name(token) {
+            return Ok(Operand::Register(reg));
+        }
+
+        let number = match token.strip_prefix("0x").or_else(|| token.strip_prefix("0X")) {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => token.parse(),
+        };
+
+        number.map(Operand::Number).map_err(|_| format!("Unknown operand '{}' in condition", token))
+    }
+}
@@ -0,0 +1,458 @@
+//! A step debugger that sits between a frontend and the machine.
+//!
+//! Frontends call [`Debugger::tick`] (or [`Debugger::run_frame`]) instead of the
+//! methods on [`CHIP8`]. While the debugger is paused nothing runs and the timers
+//! stand still. Stepping and continuing only set the debugger running again, the
+//! instructions themselves are executed by the frontend's usual loop, so frame
+//! counting and timing stay the frontend's business.
+//!
+//! Watches stop the machine right after the instruction that triggered them. Memory
+//! watches need the debugger to see memory accesses, see [`Debugger::attach`].
+
+use crate::chip8::{AccessKind, MemoryAccess, CHIP8};
+use crate::condition::{Condition, Register};
+use crate::disassembler::Disassembler;
+use crate::error::Chip8Error;
+use crate::instruction::Instruction;
+use crate::symbols::Symbols;
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// Why the debugger paused the machine.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// Asked to by the user.
+    Paused,
+    /// A step, step over or step out finished.
+    Step,
+    /// The program counter reached a breakpoint. The instruction there hasn't run yet.
+    Breakpoint(u16),
+    /// A watched address was read or written.
+    Memory(MemoryAccess),
+    /// A watched register changed.
+    Register { reg: Register, old: u16, new: u16 },
+    /// A watched condition became true.
+    Condition(Condition),
+}
+
+impl Stop {
+    /// Like to_string, with addresses named after the closest label.
+    pub fn describe(&self, symbols: &Symbols) -> String {
+        match self {
+            Stop::Paused => String::from("paused"),
+            Stop::Step => String::from("stepped"),
+            Stop::Breakpoint(addr) => format!("breakpoint at {}", symbols.describe(*addr)),
+            Stop::Memory(MemoryAccess { addr, kind: AccessKind::Read, value }) => {
+                format!("read {:#04x} from {}", value, symbols.describe(*addr))
+            }
+            Stop::Memory(MemoryAccess { addr, kind: AccessKind::Write, value }) => {
+                format!("wrote {:#04x} to {}", value, symbols.describe(*addr))
+            }
+            Stop::Register { reg, old, new } => format!("{} changed from {:#x} to {:#x}", reg, old, new),
+            Stop::Condition(condition) => condition.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.describe(&Symbols::new()))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Watch {
+    /// Accesses to start..=end. Reads, writes, or both when kind is None.
+    Memory { start: u16, end: u16, kind: Option<AccessKind> },
+    /// Any change to a register.
+    Register(Register),
+    /// The condition going from false to true.
+    Condition(Condition),
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Watch::Memory { start, end, kind } => {
+                let what = match kind {
+                    Some(AccessKind::Read) => "reads of",
+                    Some(AccessKind::Write) => "writes to",
+                    None => "accesses to",
+                };
+
+                if start == end {
+                    write!(f, "{} {:#05x}", what, start)
+                } else {
+                    write!(f, "{} {:#05x}-{:#05x}", what, start, end)
+                }
+            }
+            Watch::Register(reg) => write!(f, "changes to {}", reg),
+            Watch::Condition(condition) => write!(f, "{}", condition),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct WatchSlot {
+    id: usize,
+    watch: Watch,
+    last: u16, // Register value, or whether the condition held, after the last tick
+}
+
+// Where a running step should stop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Target {
+    Step,
+    StepOver { ret: u16, depth: usize }, // Back from the CALL at the same stack depth
+    StepOut { depth: usize },            // Once the stack is shallower than this
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Debugger {
+    breakpoints: BTreeMap<u16, Option<Condition>>,
+    watches: Vec<WatchSlot>,
+    next_watch_id: usize,
+    accesses: Rc<RefCell<Vec<MemoryAccess>>>, // Filled by the memory hook, emptied every tick
+    paused: bool,
+    resuming: bool, // Don't stop on the breakpoint we're sitting on
+    target: Option<Target>,
+    stop: Option<Stop>,
+    symbols: Symbols,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Installs the memory hook the watches rely on, replacing any other hook.
+    pub fn attach(&self, cpu: &mut CHIP8) {
+        let accesses = Rc::clone(&self.accesses);
+        cpu.set_memory_hook(move |access| accesses.borrow_mut().push(access));
+    }
+
+    /// Names for the program's addresses. Every breakpoint in them is set as well, the
+    /// ones from the symbols this replaces are removed.
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        for &(addr, _) in self.symbols.breakpoints() {
+            self.breakpoints.remove(&addr);
+        }
+
+        for &(addr, _) in symbols.breakpoints() {
+            self.add_breakpoint(addr);
+        }
+
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn pause(&mut self) {
+        if !self.paused {
+            self.paused = true;
+            self.target = None;
+            self.stop = Some(Stop::Paused);
+        }
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.resuming = true;
+    }
+
+    /// Runs a single instruction, then pauses again.
+    pub fn step(&mut self) {
+        self.target = Some(Target::Step);
+        self.resume();
+    }
+
+    /// Like step, but a CALL runs until the subroutine returns.
+    pub fn step_over(&mut self, cpu: &CHIP8) {
+        let pc = cpu.pc() as usize;
+        let opcode = cpu.memory().get(pc..pc + 2).map(|word| u16::from_be_bytes([word[0], word[1]]));
+
+        self.target = match opcode.map(Instruction::decode) {
+            Some(Ok(Instruction::Call(_))) => Some(Target::StepOver {
+                ret: cpu.pc().wrapping_add(2),
+                depth: cpu.stack().len(),
+            }),
+            _ => Some(Target::Step),
+        };
+        self.resume();
+    }
+
+    /// Runs until the current subroutine returns. Returns false outside of a subroutine.
+    pub fn step_out(&mut self, cpu: &CHIP8) -> bool {
+        if cpu.stack().is_empty() {
+            return false;
+        }
+
+        self.target = Some(Target::StepOut { depth: cpu.stack().len() });
+        self.resume();
+        true
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr, None);
+    }
+
+    /// Only stops at addr when the condition holds. Replaces any breakpoint already at addr.
+    pub fn add_conditional_breakpoint(&mut self, addr: u16, condition: Condition) {
+        self.breakpoints.insert(addr, Some(condition));
+    }
+
+    /// Returns false if there was no breakpoint at addr.
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr).is_some()
+    }
+
+    /// Returns true if the breakpoint is now set.
+    pub fn toggle_breakpoint(&mut self, addr: u16) -> bool {
+        if self.remove_breakpoint(addr) {
+            false
+        } else {
+            self.add_breakpoint(addr);
+            true
+        }
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (u16, Option<&Condition>)> + '_ {
+        self.breakpoints.iter().map(|(&addr, condition)| (addr, condition.as_ref()))
+    }
+
+    /// Returns the id to remove the watch with. Register and condition watches start
+    /// from the current state: a condition that already holds has to become false
+    /// before it can stop the machine.
+    pub fn add_watch(&mut self, watch: Watch, cpu: &CHIP8) -> usize {
+        let id = self.next_watch_id;
+        self.next_watch_id += 1;
+
+        let last = Self::watched_value(&watch, cpu);
+        self.watches.push(WatchSlot { id, watch, last });
+
+        id
+    }
+
+    /// Returns false if there is no watch with that id.
+    pub fn remove_watch(&mut self, id: usize) -> bool {
+        let count = self.watches.len();
+        self.watches.retain(|slot| slot.id != id);
+        self.watches.len() != count
+    }
+
+    pub fn watches(&self) -> impl Iterator<Item = (usize, &Watch)> + '_ {
+        self.watches.iter().map(|slot| (slot.id, &slot.watch))
+    }
+
+    /// Call after the machine jumped to another state (a rewind, a step back, a loaded
+    /// save), so the watches compare against it instead of stopping on the jump itself.
+    pub fn resync(&mut self, cpu: &CHIP8) {
+        self.accesses.borrow_mut().clear();
+
+        for slot in &mut self.watches {
+            slot.last = Self::watched_value(&slot.watch, cpu);
+        }
+    }
+
+    /// Why the debugger paused, once per pause.
+    pub fn take_stop(&mut self) -> Option<Stop> {
+        self.stop.take()
+    }
+
+    /// Pauses if the next tick would run an instruction with a breakpoint on it. Returns
+    /// whether the machine may tick.
+    pub fn check(&mut self, cpu: &CHIP8) -> bool {
+        if !self.paused && !self.resuming && Self::executes(cpu) {
+            let hit = match self.breakpoints.get(&cpu.pc()) {
+                Some(None) => true,
+                Some(Some(condition)) => condition.holds(cpu),
+                None => false,
+            };
+
+            if hit {
+                self.stop_at(Stop::Breakpoint(cpu.pc()));
+            }
+        }
+
+        !self.paused
+    }
+
+    /// Same as CHIP8::tick, but nothing runs while the debugger is paused, or when check()
+    /// pauses it.
+    pub fn tick(&mut self, cpu: &mut CHIP8, keypad: [bool; 16]) -> Result<bool, Chip8Error> {
+        if !self.check(cpu) {
+            return Ok(false);
+        }
+
+        let executes = Self::executes(cpu);
+        let result = cpu.tick(keypad);
+
+        // Watches are checked even when no instruction ran, Fx0A sets its register on a
+        // later tick.
+        let watch_stop = self.check_watches(cpu);
+
+        if result.is_err() {
+            self.paused = true;
+            self.target = None;
+        } else if let Some(stop) = watch_stop {
+            self.resuming = false;
+            self.stop_at(stop);
+        } else if executes {
+            self.resuming = false;
+
+            let done = match self.target {
+                Some(Target::Step) => true,
+                Some(Target::StepOver { ret, depth }) => cpu.pc() == ret && cpu.stack().len() == depth,
+                Some(Target::StepOut { depth }) => cpu.stack().len() < depth,
+                None => false,
+            };
+
+            if done {
+                self.stop_at(Stop::Step);
+            }
+        }
+
+        result
+    }
+
+    /// Same as CHIP8::run_frame, except that the frame is left unfinished if the debugger
+    /// pauses in the middle of it. The next call picks up where this one stopped.
+    pub fn run_frame(&mut self, cpu: &mut CHIP8, keypad: [bool; 16]) -> Result<bool, Chip8Error> {
+        let mut redraw = false;
+
+        while !cpu.frame_complete() && self.check(cpu) {
+            redraw |= self.tick(cpu, keypad)?;
+        }
+
+        if cpu.frame_complete() {
+            cpu.end_frame();
+        }
+
+        Ok(redraw)
+    }
+
+    // Updates every watch and returns the first one that fired.
+    fn check_watches(&mut self, cpu: &CHIP8) -> Option<Stop> {
+        let accesses = std::mem::take(&mut *self.accesses.borrow_mut());
+        let mut stop = None;
+
+        for slot in self.watches.iter_mut() {
+            let fired = match &slot.watch {
+                Watch::Memory { start, end, kind } => accesses.iter()
+                    .filter(|access| (*start..=*end).contains(&access.addr))
+                    .find(|access| kind.is_none_or(|kind| kind == access.kind))
+                    .map(|&access| Stop::Memory(access)),
+
+                Watch::Register(reg) => {
+                    let value = reg.value(cpu);
+                    Some(Stop::Register { reg: *reg, old: slot.last, new: value })
+                        .filter(|_| value != slot.last)
+                }
+
+                Watch::Condition(condition) => {
+                    let holds = condition.holds(cpu);
+                    Some(Stop::Condition(condition.clone()))
+                        .filter(|_| holds && slot.last == 0)
+                }
+            };
+
+            slot.last = Self::watched_value(&slot.watch, cpu);
+
+            if stop.is_none() {
+                stop = fired;
+            }
+        }
+
+        stop
+    }
+
+    fn watched_value(watch: &Watch, cpu: &CHIP8) -> u16 {
+        match watch {
+            Watch::Memory { .. } => 0,
+            Watch::Register(reg) => reg.value(cpu),
+            Watch::Condition(condition) => condition.holds(cpu) as u16,
+        }
+    }
+
+    fn executes(cpu: &CHIP8) -> bool {
+        !cpu.waiting() && cpu.halted().is_none()
+    }
+
+    fn stop_at(&mut self, stop: Stop) {
+        self.paused = true;
+        self.target = None;
+        self.stop = Some(stop);
+    }
+}
+
+/// Registers, timers, the stack and the instruction about to run, as a few lines of text.
+/// Addresses with a label get its name next to them.
+pub fn inspect(cpu: &CHIP8, symbols: &Symbols) -> String {
+    let named = |addr: u16| match symbols.lookup(addr) {
+        Some(name) => format!("{:04x} <{}>", addr, name),
+        None => format!("{:04x}", addr),
+    };
+
+    let mut out = String::new();
+
+    for (row, regs) in cpu.registers().chunks(8).enumerate() {
+        let line: Vec<String> = regs.iter()
+            .enumerate()
+            .map(|(idx, val)| format!("V{:X}={:02x}", row * 8 + idx, val))
+            .collect();
+        out.push_str(&line.join(" "));
+        out.push('\n');
+    }
+
+    out.push_str(&format!(
+        "I={:04x} PC={:04x} DT={:02x} ST={:02x}\n",
+        cpu.i(), cpu.pc(), cpu.delay_timer(), cpu.sound_timer()
+    ));
+
+    let stack: Vec<String> = cpu.stack().iter().map(|&addr| named(addr)).collect();
+    out.push_str(&format!("Stack: [{}]\n", stack.join(" ")));
+
+    let pc = cpu.pc() as usize;
+    let next = match cpu.memory().get(pc..pc + 2) {
+        Some(word) => {
+            let opcode = u16::from_be_bytes([word[0], word[1]]);
+            match Instruction::decode(opcode) {
+                Ok(instruction) => format!("{:04x}  {}", opcode, Disassembler::format(instruction)),
+                Err(err) => err.to_string(),
+            }
+        }
+        None => String::from("outside of memory"),
+    };
+    out.push_str(&format!("{}: {}", named(cpu.pc()), next));
+
+    out
+}
+
+/// Classic hex dump of len bytes starting at addr, 16 bytes per row.
+pub fn hex_dump(cpu: &CHIP8, addr: u16, len: usize) -> String {
+    let mem = cpu.memory();
+    let start = (addr as usize).min(mem.len());
+    let end = start.saturating_add(len).min(mem.len());
+
+    let rows: Vec<String> = mem[start..end].chunks(16)
+        .enumerate()
+        .map(|(row, bytes)| {
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let ascii: String = bytes.iter()
+                .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+                .collect();
+            format!("{:04x}: {:<47}  {}", start + row * 16, hex.join(" "), ascii)
+        })
+        .collect();
+
+    rows.join("\n")
+}
@@ -0,0 +1,244 @@
+//! Finding where two runs of a program part ways, either from two trace files or by
+//! running two machines side by side (the same ROM with different quirks, say) on
+//! the same input.
+
+use crate::chip8::CHIP8;
+use crate::condition::Register;
+use crate::error::Chip8Error;
+use crate::trace::TraceRecord;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::rc::Rc;
+
+/// How many instructions before the divergence are shown by default.
+pub const DEFAULT_CONTEXT: usize = 8;
+
+/// Where two traces stop agreeing. The frame and cycle columns aren't compared, they
+/// count differently from one emulator to the next.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceMismatch {
+    pub index: usize, // Of the first record that differs
+    pub a: Option<TraceRecord>, // None when that trace ended first
+    pub b: Option<TraceRecord>,
+    pub context: Vec<TraceRecord>, // The records before it, the same in both
+}
+
+impl fmt::Display for TraceMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "The traces differ at instruction {}", self.index + 1)?;
+
+        for record in &self.context {
+            writeln!(f, "    {}", record.to_text())?;
+        }
+
+        let show = |record: &Option<TraceRecord>| match record {
+            Some(record) => record.to_text(),
+            None => String::from("(end of trace)"),
+        };
+
+        writeln!(f, "a:  {}", show(&self.a))?;
+        write!(f, "b:  {}", show(&self.b))
+    }
+}
+
+/// Compares two traces record by record. None if they agree all the way.
+pub fn compare_traces(a: &[TraceRecord], b: &[TraceRecord], context: usize) -> Option<TraceMismatch> {
+    let same = |x: &TraceRecord, y: &TraceRecord| {
+        x.addr == y.addr && x.opcode == y.opcode && x.changes == y.changes
+    };
+
+    let index = (0..a.len().max(b.len())).find(|&idx| match (a.get(idx), b.get(idx)) {
+        (Some(x), Some(y)) => !same(x, y),
+        _ => true,
+    })?;
+
+    Some(TraceMismatch {
+        index,
+        a: a.get(index).cloned(),
+        b: b.get(index).cloned(),
+        context: a[index.saturating_sub(context)..index].to_vec(),
+    })
+}
+
+/// One way the two machines differ.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Difference {
+    Pc(u16, u16),
+    Register(Register, u16, u16),
+    Memory { addr: u16, a: u8, b: u8, count: usize }, // The first byte, and how many differ in all
+    Screen { x: usize, y: usize, a: u8, b: u8, count: usize }, // The same, for pixels
+    Resolution((usize, usize), (usize, usize)),
+    Halted(Option<Chip8Error>, Option<Chip8Error>),
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Difference::Pc(a, b) => write!(f, "PC: {:#05x} vs {:#05x}", a, b),
+            Difference::Register(reg @ Register::I, a, b) => write!(f, "{}: {:#05x} vs {:#05x}", reg, a, b),
+            Difference::Register(reg, a, b) => write!(f, "{}: {:#04x} vs {:#04x}", reg, a, b),
+            Difference::Memory { addr, a, b, count } => {
+                write!(f, "memory at {:#05x}: {:#04x} vs {:#04x} ({} bytes differ)", addr, a, b, count)
+            }
+            Difference::Screen { x, y, a, b, count } => {
+                write!(f, "pixel ({}, {}): {} vs {} ({} pixels differ)", x, y, a, b, count)
+            }
+            Difference::Resolution((aw, ah), (bw, bh)) => write!(f, "screen: {}x{} vs {}x{}", aw, ah, bw, bh),
+            Difference::Halted(a, b) => {
+                let show = |err: &Option<Chip8Error>| match err {
+                    Some(err) => format!("halted ({})", err),
+                    None => String::from("running"),
+                };
+                write!(f, "{} vs {}", show(a), show(b))
+            }
+        }
+    }
+}
+
+/// Everything that differs between the two machines, empty if they're in the same state.
+pub fn compare(a: &CHIP8, b: &CHIP8) -> Vec<Difference> {
+    let mut differences = Vec::new();
+
+    if a.halted() != b.halted() {
+        differences.push(Difference::Halted(a.halted(), b.halted()));
+    }
+
+    if a.pc() != b.pc() {
+        differences.push(Difference::Pc(a.pc(), b.pc()));
+    }
+
+    let registers = (0..16).map(Register::V)
+        .chain([Register::I, Register::Sp, Register::Dt, Register::St]);
+    for reg in registers {
+        if reg.value(a) != reg.value(b) {
+            differences.push(Difference::Register(reg, reg.value(a), reg.value(b)));
+        }
+    }
+
+    // Memory sizes only differ when the quirks do, compare what both have.
+    let mut bytes = a.memory().iter().zip(b.memory()).enumerate().filter(|(_, (x, y))| x != y);
+    if let Some((addr, (&x, &y))) = bytes.next() {
+        let count = 1 + bytes.count();
+        differences.push(Difference::Memory { addr: addr as u16, a: x, b: y, count });
+    }
+
+    let (sa, sb) = (a.screen(), b.screen());
+    if (sa.width(), sa.height()) != (sb.width(), sb.height()) {
+        differences.push(Difference::Resolution((sa.width(), sa.height()), (sb.width(), sb.height())));
+    } else {
+        let mut pixels = sa.pixels().iter().zip(sb.pixels()).enumerate().filter(|(_, (x, y))| x != y);
+        if let Some((idx, (&x, &y))) = pixels.next() {
+            let count = 1 + pixels.count();
+            let (x_pos, y_pos) = (idx % sa.width(), idx / sa.width());
+            differences.push(Difference::Screen { x: x_pos, y: y_pos, a: x, b: y, count });
+        }
+    }
+
+    differences
+}
+
+/// The first tick after which the two machines no longer agree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    pub cycle: u64,
+    pub frame: u64, // Of the first machine
+    pub differences: Vec<Difference>,
+    pub context_a: Vec<TraceRecord>, // The last instructions each of them ran, oldest first
+    pub context_b: Vec<TraceRecord>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "The machines diverged at cycle {} (frame {}):", self.cycle, self.frame)?;
+
+        for difference in &self.differences {
+            writeln!(f, "    {}", difference)?;
+        }
+
+        writeln!(f, "Last instructions on a:")?;
+        for record in &self.context_a {
+            writeln!(f, "    {}", record.to_text())?;
+        }
+
+        write!(f, "Last instructions on b:")?;
+        for record in &self.context_b {
+            write!(f, "\n    {}", record.to_text())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Two machines ticked together, compared after every tick.
+pub struct Lockstep {
+    pub a: CHIP8,
+    pub b: CHIP8,
+    history_a: Rc<RefCell<VecDeque<TraceRecord>>>,
+    history_b: Rc<RefCell<VecDeque<TraceRecord>>>,
+}
+
+impl Lockstep {
+    /// Remembers the last `context` instructions of each machine for the report. Takes
+    /// over their instruction hooks.
+    pub fn new(mut a: CHIP8, mut b: CHIP8, context: usize) -> Self {
+        let history_a = Self::record(&mut a, context);
+        let history_b = Self::record(&mut b, context);
+
+        Self { a, b, history_a, history_b }
+    }
+
+    /// Ticks both machines, each one with `keypad` for the frame it's on, and ends their
+    /// frames as they complete. Returns how they differ afterwards, if they do.
+    pub fn tick<F>(&mut self, mut keypad: F) -> Option<Divergence>
+    where F: FnMut(u64) -> [bool; 16] {
+        let cycle = self.a.cycles();
+        let frame = self.a.frames();
+
+        for cpu in [&mut self.a, &mut self.b] {
+            if cpu.frame_complete() {
+                cpu.end_frame();
+            }
+
+            // Errors are compared like everything else.
+            let _ = cpu.tick(keypad(cpu.frames()));
+        }
+
+        let differences = compare(&self.a, &self.b);
+        if differences.is_empty() {
+            return None;
+        }
+
+        Some(Divergence {
+            cycle,
+            frame,
+            differences,
+            context_a: self.history_a.borrow().iter().cloned().collect(),
+            context_b: self.history_b.borrow().iter().cloned().collect(),
+        })
+    }
+
+    /// Whether there's nothing left to run: both exited, or halted on the same error.
+    pub fn done(&self) -> bool {
+        (self.a.exited() && self.b.exited()) ||
+            (self.a.halted().is_some() && self.a.halted() == self.b.halted())
+    }
+
+    fn record(cpu: &mut CHIP8, context: usize) -> Rc<RefCell<VecDeque<TraceRecord>>> {
+        let history = Rc::new(RefCell::new(VecDeque::with_capacity(context)));
+        let hook_history = Rc::clone(&history);
+
+        cpu.set_instruction_hook(move |executed| {
+            let mut history = hook_history.borrow_mut();
+            if history.len() >= context {
+                history.pop_front();
+            }
+            if context > 0 {
+                history.push_back(TraceRecord::new(executed));
+            }
+        });
+
+        history
+    }
+}
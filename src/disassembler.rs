@@ -1,359 +1,513 @@
-use std::vec::Vec;
+use crate::instruction::Instruction;
+use crate::symbols::Symbols;
+
+use std::collections::BTreeMap;
+
+// Programs are loaded here, so that's where the first byte goes in the listing.
+const PROGRAM_START: u16 = 0x200;
+
+// Data is listed this many bytes to a line.
+const DATA_PER_LINE: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text, // The mnemonics from Cowgod's reference, with a comment on what each one does
+    Octo,
+    Json,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "text" | "txt" => Some(Format::Text),
+            "octo" | "8o" => Some(Format::Octo),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+/// How code is told apart from data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Linear, // Every word is read as an instruction, from the first to the last
+    Flow, // Only what can be reached from 0x200 through jumps, calls and skips is code
+}
+
+/// One instruction of the listing, or a few bytes of data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Line {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub long: Option<u16>, // The address after an XO-CHIP `F000`
+    pub instruction: Option<Instruction>, // None for data
+}
 
 pub struct Disassembler<'a> {
-    program: &'a Vec<u8>,
-    pc: u16
+    program: &'a [u8],
+    pc: u16,
+    mode: Mode,
+    labels: BTreeMap<u16, String>, // Jump and call targets, found by lines()
+    symbols: Symbols,
 }
 
 impl<'a> Disassembler<'a> {
-    pub fn new(bytes: &'a Vec<u8>) -> Self {
+    /// Follows the control flow of the program, see [`Mode::Flow`].
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self::with_mode(bytes, Mode::Flow)
+    }
+
+    pub fn with_mode(bytes: &'a [u8], mode: Mode) -> Self {
         Self {
             program: bytes,
-            pc: 0
+            pc: 0,
+            mode,
+            labels: BTreeMap::new(),
+            symbols: Symbols::new(),
         }
     }
 
+    /// Names from the program's symbol file. They replace the generated labels, and
+    /// data is split where they start.
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
     fn read_opcode(&mut self) -> Option<u16> {
-        if self.pc as usize >= self.program.len() - 2 {
-            None
-        } else {
-            let opcode = ((self.program[self.pc as usize] as u16) << 8)
-            | ((self.program[(self.pc + 1) as usize]) as u16);
-            self.pc += 2;
-
-            Some(opcode)
-        }
+        let opcode = self.word(self.pc as usize)?;
+        self.pc += 2;
+
+        Some(opcode)
+    }
+
+    fn word(&self, offset: usize) -> Option<u16> {
+        let bytes = self.program.get(offset..offset + 2)?;
+        Some(((bytes[0] as u16) << 8) | bytes[1] as u16)
     }
 
     pub fn disassemble(&mut self) -> String {
-        self.pc = 0;
+        self.disassemble_as(Format::Text)
+    }
 
-        let mut code: Vec<String> = Vec::new();
-
-        while let Some(opcode) = self.read_opcode() {
-            let units = (
-                ((opcode & 0xF000) >> 12) as usize,
-                ((opcode & 0x0F00) >> 8) as usize,
-                ((opcode & 0x00F0) >> 4) as usize,
-                (opcode & 0x000F) as usize
-            );
-
-            let code_str: String = match units {
-                // 00E0 - CLS
-                // Clears the screen
-                (0x0, 0x0, 0xE, 0x0) => {
-                    String::from("CLS")
-                }
-    
-                // 00EE - RET
-                // Return from a subroutine.
-                // The interpreter sets the program counter to the address at the top of the stack, then subtracts 1 from the stack pointer.
-                (0x0, 0x0, 0xE, 0xE) => {
-                    String::from("RET")
-                }
-    
-                // 1nnn - JP addr
-                // Set the program counter to nnn.
-                (0x1, _, _, _) => {
-                    format!("JP {:x}", opcode & 0x0FFF)
-                }
-    
-                // 2nnn - CALL addr
-                // Calls subroutine from nnn
-                // The interpreter increments the stack pointer, then puts the current PC on the top of the stack. The PC is then set to nnn.
-                (0x2, _, _, _) => {
-                    format!("CALL {:x}", opcode & 0x0FFF)
-                }
-    
-                // 3xkk - SE Vx, byte
-                // Skip next instruction if Vx = kk.
-                (0x3, _, _, _) => {
-                    let byte = (opcode & 0x00FF) as u8;
-                    format!("SE {:x}, {:x} ; (V{} == {} ? skip)", units.1, byte, units.1, byte)
-                }
-    
-                // 4xkk - SNE Vx, byte
-                // Skip next instruction if Vx != kk.
-                (0x4, _, _, _) => {
-                    let byte = (opcode & 0x00FF) as u8;
-                    format!("SNE {:x}, {:x} ; (V{} != {} ? skip)", units.1, byte, units.1, byte)
-                }
-    
-                // 5xy0 - SE Vx, Vy
-                // Skip next instruction if Vx = Vy.
-                (0x5, _, _, 0x0) => {
-                    format!(
-                        "SE {:x}, {:x} ; (V{} == V{} ? skip)",
-                        units.1, units.2, units.1, units.2
-                    )
-                }
-    
-                // 6xkk - LD Vx, byte
-                // Set Vx = kk.
-                (0x6, _, _, _) => {
-                    let new_val = (opcode & 0x00FF) as u8;
-                    format!(
-                        "LD {:x}, {:x} ; (V{} = {})",
-                        units.1, new_val, units.1, new_val
-                    )
-                }
-    
-                // 7xkk - ADD Vx, byte
-                // Set Vx = Vx + kk.
-                (0x7, _, _, _) => {
-                    let byte = (opcode & 0x00FF) as u8;
-                    format!(
-                        "ADD {:x}, {:x} ; (V{} = V{} + {})",
-                        units.1, byte, units.1, units.1, byte
-                    )
-                }
-    
-                // 8xy0 - LD Vx, Vy
-                // Set Vx = Vy
-                (0x8, _, _, 0x0) => {
-                    format!(
-                        "LD {:x}, {:x} ; (V{} = V{})",
-                        units.1, units.2, units.1, units.2
-                    )
-                }
-    
-                // 8xy1 - OR Vx, Vy
-                // Set Vx = Vx OR Vy.
-                (0x8, _, _, 0x1) => {
-                    format!(
-                        "OR {:x}, {:x} ; (V{} = V{} OR V{})",
-                        units.1, units.2, units.1, units.1, units.2
-                    )
-                }
-    
-                // 8xy2 - AND Vx, Vy
-                // Set Vx = Vx AND Vy
-                (0x8, _, _, 0x2) => {
-                    format!(
-                        "AND {:x}, {:x} ; (V{} = V{} AND V{})",
-                        units.1, units.2, units.1, units.1, units.2
-                    )
-                }
-    
-                // 8xy3 - XOR Vx, Vy
-                // Set Vx = Vx XOR Vy
-                (0x8, _, _, 0x3) => {
-                    format!(
-                        "XOR {:x}, {:x} ; (V{} = V{} XOR V{})",
-                        units.1, units.2, units.1, units.1, units.2
-                    )
-                }
-    
-                // 8xy4 - ADD Vx, Vy
-                // Set Vx = Vx + Vy, set VF = carry.
-                (0x8, _, _, 0x4) => {
-                    format!(
-                        "ADD {:x}, {:x} ; (V{} = V{} + V{}, VF = carry)",
-                        units.1, units.2, units.1, units.1, units.2
-                    )
-                }
-    
-                // 8xy5 - SUB Vx, Vy
-                // Set Vx = Vx - Vy, set VF = NOT Borrow
-                (0x8, _, _, 0x5) => {
-                    format!(
-                        "SUB {:x}, {:x} ; (V{} = V{} - V{}, VF = !borrow)",
-                        units.1, units.2, units.1, units.1, units.2
-                    )
-                }
-    
-                // 8xy6 - SHR Vx {, Vy}
-                // Set Vx = Vx SHR 1.
-                // If the least-significant bit of Vx is 1, then VF is set to 1, otherwise 0. Then Vx is divided by 2.
-                (0x8, _, _, 0x6) => {
-                    format!(
-                        "SHR {:x} {{, {:x}}} ; (V{} = V{} >> 1, VF = LSB)",
-                        units.1, units.2, units.1, units.1
-                    )
-                }
-    
-                // 8xy7 - SUBN Vx, Vy
-                // Set Vx = Vy - Vx, set VF = NOT borrow.
-                (0x8, _, _, 0x7) => {
-                    format!(
-                        "SUB {:x}, {:x} ; (V{} = V{} - V{}, VF = !borrow)",
-                        units.1, units.2, units.1, units.2, units.1
-                    )
-                }
-    
-                // 8xyE - SHL Vx {, Vy}
-                // Set Vx = Vx SHL 1.
-                // If the most-significant bit of Vx is 1, then VF is set to 1, otherwise to 0. Then Vx is multiplied by 2.
-                (0x8, _, _, 0xE) => {
-                    format!(
-                        "SHL {:x} {{, {:x}}} ; (V{} = V{} << 1, VF = MSB)",
-                        units.1, units.2, units.1, units.1
-                    )
-                }
-    
-                // 9xy0 - SNE Vx, Vy
-                // Skip next instruction if Vx != Vy.
-                (0x9, _, _, 0x0) => {
-                    format!(
-                        "SE {:x}, {:x} ; (V{} != V{} ? skip)",
-                        units.1, units.2, units.1, units.2
-                    )
-                }
-    
-                // Annn - LD I, addr
-                // Set I = nnn
-                (0xA, _, _, _) => {
-                    let val = opcode & 0x0FFF;
-                    format!(
-                        "LD I, {:x} ; (I = {})",
-                        val, val
-                    )
-                }
-    
-                // Bnnn - JP V0, addr
-                // Jump to location nnn + V0.
-                (0xB, _, _, _) => {
-                    let val = opcode & 0x0FFF;
-                    format!(
-                        "JP V0, {:x} ; (PC = V0 + {})",
-                        val, val
-                    )
-                }
-    
-                // Cxkk - RND Vx, byte
-                // Set Vx = random byte AND kk.
-                (0xC, _, _, _) => {
-                    let byte = (opcode & 0x00FF) as u8;
-                    format!(
-                        "RND {:x}, {:x} ; (V{} = rand() AND {:x})",
-                        units.1, byte, units.1, byte
-                    )
-                }
-    
-                /*
-                    Dxyn - DRW Vx, Vy, nibble
-                    Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision.
-                    The interpreter reads n bytes from memory, starting at the address stored in I.
-                    These bytes are then displayed as sprites on screen at coordinates (Vx, Vy).
-                    Sprites are XORed onto the existing screen.
-                    If this causes any pixels to be erased, VF is set to 1, otherwise it is set to 0.
-                    If the sprite is positioned so part of it is outside the coordinates of the display,
-                    it wraps around to the opposite side of the screen.
-    
-                */
-                (0xD, _, _, _) => {
-                    format!(
-                        "DRW {:x}, {:x}, {:x} ; ((V{}, V{}) -> {})",
-                        units.1, units.2, units.3, units.1, units.2, units.3
-                    )
-                }
-    
-                // Ex9E - SKP Vx
-                // Skip next instruction if key with the value of Vx is pressed.
-                (0xE, _, 0x9, 0xE) => {
-                    format!(
-                        "SKP {:x} ; (key(V{}) ? skip)",
-                        units.1, units.1
-                    )
-                }
-    
-                // ExA1 - SKNP Vx
-                // Skip next instruction if key with the value of Vx is not pressed.
-                (0xE, _, 0xA, 0x1) => {
-                    format!(
-                        "SKNP {:x} ; (!key(V{}) ? skip)",
-                        units.1, units.1
-                    )
-                }
-    
-                // Fx07 - LD Vx, DT
-                // Set Vx = delay timer value.
-                (0xF, _, 0x0, 0x7) => {
-                    format!(
-                        "LD {:x}, DT ; (V{} = DT)",
-                        units.1, units.1
-                    )
-                }
-    
-                // Fx0A - LD Vx, K
-                // Wait for a key press, store the value of the key in Vx.
-                (0xF, _, 0x0, 0xA) => {
-                    format!(
-                        "LD {:x}, K ; (Wait till keypress then V{} = Key)",
-                        units.1, units.1
-                    )
-                }
-    
-                // Fx15 - LD DT, Vx
-                // Set delay timer = Vx.
-                (0xF, _, 0x1, 0x5) => {
-                    format!(
-                        "LD DT, {:x} ; (DT = V{})",
-                        units.1, units.1
-                    )
-                }
-    
-                // Fx18 - LD ST, Vx
-                // Set sound timer = Vx.
-                (0xF, _, 0x1, 0x8) => {
-                    format!(
-                        "LD ST, {:x} ; (ST = V{})",
-                        units.1, units.1
-                    )
-                }
-    
-                // Fx1E - ADD I, Vx
-                // Set I = I + Vx.
-                (0xF, _, 0x1, 0xE) => {
-                    format!(
-                        "ADD I, {:x} ; (I = I + V{})",
-                        units.1, units.1
-                    )
-                }
-    
-                // Fx29 - LD F, Vx
-                // Set I = location of sprite for digit Vx.
-                (0xF, _, 0x2, 0x9) => {
-                    format!(
-                        "LD F, {:x} ; (I = Location of sprite for V{})",
-                        units.1, units.1
-                    )
-                }
-    
-                // Fx33 - LD B, Vx
-                // Store BCD representation of Vx in memory locations I, I+1, and I+2.
-                (0xF, _, 0x3, 0x3) => {
-                    format!(
-                        "LD B, {:x} ; (BCD(V{}) -> (I, I+1, I+2))",
-                        units.1, units.1
-                    )
-                }
-    
-                // Fx55 - LD [I], Vx
-                // Store registers V0 through Vx in memory starting at location I.
-                (0xF, _, 0x5, 0x5) => {
-                    format!(
-                        "LD [I], {:x} ; (mem[I + x] = Vx for x in 0..{})",
-                        units.1, units.1
-                    )
-                }
-    
-                // Fx65 - LD Vx, [I]
-                // Read registers V0 through Vx from memory starting at location I.
-                (0xF, _, 0x6, 0x5) => {
-                    format!(
-                        "LD {:x}, [I] ; (Vx = mem[I + x] for x in 0..{})",
-                        units.1, units.1
-                    )
+    pub fn disassemble_as(&mut self, format: Format) -> String {
+        let lines = self.lines();
+        let mut out = Vec::new();
+
+        match format {
+            Format::Text => {
+                for line in &lines {
+                    if let Some(label) = self.labels.get(&line.addr) {
+                        out.push(format!("{}:", label));
+                    }
+                    out.push(self.text_line(line));
+                }
+            }
+            Format::Octo => {
+                // Octo starts the program at `main`, the first byte is where it has to go.
+                // A main from the symbols anywhere else needs another name.
+                let moved_main = self.labels.iter()
+                    .find(|&(&addr, label)| addr != PROGRAM_START && label == "main")
+                    .map(|(&addr, _)| addr);
+                if let Some(addr) = moved_main {
+                    self.labels.insert(addr, format!("label_{:03X}", addr));
                 }
-    
-                // Just go ahead if illegal opcode detected.
-                _ => String::from("")
+
+                out.push(String::from(": main"));
+                for line in &lines {
+                    if let Some(label) = self.labels.get(&line.addr).filter(|&label| label != "main") {
+                        out.push(format!(": {}", label));
+                    }
+                    out.push(self.octo_line(line));
+                }
+            }
+            Format::Json => {
+                let entries: Vec<String> = lines.iter().map(|line| self.json_line(line)).collect();
+                out.push(format!("[\n{}\n]", entries.join(",\n")));
+            }
+        }
+
+        out.join("\n")
+    }
+
+    /// The program as instructions and data, the way the mode tells them apart. Also
+    /// names the jump and call targets.
+    pub fn lines(&mut self) -> Vec<Line> {
+        let lines = match self.mode {
+            Mode::Linear => self.sweep(),
+            Mode::Flow => self.follow(),
+        };
+
+        self.labels.clear();
+
+        for line in &lines {
+            let (target, prefix) = match line.instruction {
+                Some(Instruction::Call(nnn)) => (nnn, "sub"),
+                Some(Instruction::Jp(nnn)) | Some(Instruction::JpV0(nnn)) => (nnn, "label"),
+                _ => continue,
+            };
+
+            // Only instructions get a name, a jump into the middle of one or into data
+            // keeps its address.
+            let is_code = lines.iter().any(|other| other.addr == target && other.instruction.is_some());
+
+            // A subroutine that's also jumped to is still a subroutine.
+            let named_sub = self.labels.get(&target).is_some_and(|label| label.starts_with("sub"));
+
+            if is_code && !named_sub {
+                self.labels.insert(target, format!("{}_{:03X}", prefix, target));
+            }
+        }
+
+        // A symbol can only be written where a line starts.
+        for (addr, name) in self.symbols.labels() {
+            if lines.iter().any(|line| line.addr == addr) {
+                self.labels.insert(addr, name.to_string());
+            }
+        }
+
+        lines
+    }
+
+    /// Names of the jump and call targets and the symbols by address, from the last
+    /// call to lines().
+    pub fn labels(&self) -> &BTreeMap<u16, String> {
+        &self.labels
+    }
+
+    // Every word from the start, whether it's an instruction or not.
+    fn sweep(&mut self) -> Vec<Line> {
+        self.pc = 0;
+
+        let mut lines = Vec::new();
+
+        loop {
+            let addr = PROGRAM_START + self.pc;
+            let opcode = match self.read_opcode() {
+                Some(opcode) => opcode,
+                None => break,
+            };
+
+            let mut instruction = Instruction::decode(opcode).ok();
+            let long = match instruction {
+                Some(Instruction::LdILong) => self.read_opcode(),
+                _ => None,
             };
 
-            code.push(code_str);
+            let mut bytes = opcode.to_be_bytes().to_vec();
+            match long {
+                Some(addr) => bytes.extend_from_slice(&addr.to_be_bytes()),
+                // A LONG cut off by the end of the program.
+                None if instruction == Some(Instruction::LdILong) => instruction = None,
+                None => (),
+            }
+
+            lines.push(Line { addr, bytes, long, instruction });
         }
 
-        code.join("\n")
+        // An odd byte at the end can only be data.
+        if let Some(&byte) = self.program.get(self.pc as usize) {
+            lines.push(Line { addr: PROGRAM_START + self.pc, bytes: vec![byte], long: None, instruction: None });
+        }
+
+        lines
     }
-}
\ No newline at end of file
+
+    // Starts at the first instruction and follows every way the program can go from
+    // there. What's never reached is data.
+    fn follow(&mut self) -> Vec<Line> {
+        let len = self.program.len();
+        let mut starts = vec![None; len]; // The instruction at each offset that begins one
+        let mut covered = vec![false; len];
+        let mut pending = vec![0];
+
+        let offset_of = |addr: u16| (addr as usize).checked_sub(PROGRAM_START as usize);
+
+        while let Some(offset) = pending.pop() {
+            let instruction = match self.word(offset).map(Instruction::decode) {
+                Some(Ok(instruction)) => instruction,
+                // Ran off the end, or into something that isn't an instruction.
+                _ => continue,
+            };
+
+            let next = offset + instruction.size() as usize;
+            if next > len || covered[offset..next].iter().any(|&covered| covered) {
+                continue;
+            }
+
+            starts[offset] = Some(instruction);
+            covered[offset..next].iter_mut().for_each(|covered| *covered = true);
+
+            match instruction {
+                Instruction::Jp(nnn) => pending.extend(offset_of(nnn)),
+                // A jump table, only its first entry is known.
+                Instruction::JpV0(nnn) => pending.extend(offset_of(nnn)),
+                Instruction::Call(nnn) => {
+                    pending.extend(offset_of(nnn));
+                    pending.push(next);
+                }
+                Instruction::Ret | Instruction::Exit => (),
+                Instruction::SeByte(..) | Instruction::SneByte(..) | Instruction::SeReg(..) |
+                Instruction::SneReg(..) | Instruction::Skp(_) | Instruction::Sknp(_) => {
+                    // Skipping over `F000 nnnn` skips both words.
+                    let skipped = if self.word(next) == Some(0xF000) { 4 } else { 2 };
+                    pending.push(next);
+                    pending.push(next + skipped);
+                }
+                _ => pending.push(next),
+            }
+        }
+
+        let mut lines = Vec::new();
+        let mut offset = 0;
+
+        while offset < len {
+            let addr = PROGRAM_START + offset as u16;
+
+            if let Some(instruction) = starts[offset] {
+                let size = instruction.size() as usize;
+                let long = match instruction {
+                    Instruction::LdILong => self.word(offset + 2),
+                    _ => None,
+                };
+
+                let bytes = self.program[offset..offset + size].to_vec();
+                lines.push(Line { addr, bytes, long, instruction: Some(instruction) });
+                offset += size;
+            } else {
+                let named = |idx: usize| self.symbols.label(PROGRAM_START + idx as u16).is_some();
+                let end = (offset + 1..len)
+                    .take(DATA_PER_LINE - 1)
+                    .find(|&idx| starts[idx].is_some() || named(idx))
+                    .unwrap_or(len.min(offset + DATA_PER_LINE));
+
+                lines.push(Line { addr, bytes: self.program[offset..end].to_vec(), long: None, instruction: None });
+                offset = end;
+            }
+        }
+
+        lines
+    }
+
+    // `0200: 6003  LD V0, #03`, the address and bytes followed by the instruction. The
+    // assembler skips the first two columns, so a listing assembles back into the ROM.
+    fn text_line(&self, line: &Line) -> String {
+        let bytes: Vec<String> = line.bytes.chunks(2)
+            .map(|word| word.iter().map(|byte| format!("{:02X}", byte)).collect())
+            .collect();
+
+        let text = match self.mnemonic(line).split_once(" ; ") {
+            Some((mnemonic, comment)) => format!("{:<22} ; {}", mnemonic, comment),
+            None => self.mnemonic(line),
+        };
+
+        format!("{:04X}: {:<9}  {}", line.addr, bytes.join(" "), text)
+    }
+
+    fn mnemonic(&self, line: &Line) -> String {
+        match (line.instruction, line.long) {
+            (Some(Instruction::LdILong), Some(addr)) => format!("LD I, LONG #{:04X} ; (I = {})", addr, addr),
+            (Some(Instruction::Jp(nnn)), _) => format!("JP {}", self.target(nnn)),
+            (Some(Instruction::Call(nnn)), _) => format!("CALL {}", self.target(nnn)),
+            (Some(Instruction::JpV0(nnn)), _) => format!("JP V0, {} ; (PC = V0 + {})", self.target(nnn), nnn),
+            (Some(Instruction::LdI(nnn)), _) if self.labels.contains_key(&nnn) => {
+                format!("LD I, {} ; (I = {})", self.target(nnn), nnn)
+            }
+            (Some(instruction), _) => Self::format(instruction),
+            // Data, or an opcode no interpreter knows.
+            (None, _) => {
+                let bytes: Vec<String> = line.bytes.iter().map(|byte| format!("#{:02X}", byte)).collect();
+                format!("db {}", bytes.join(", "))
+            }
+        }
+    }
+
+    // A jump or call target, by name if it has one.
+    fn target(&self, addr: u16) -> String {
+        match self.labels.get(&addr) {
+            Some(label) => label.clone(),
+            None => format!("#{:03X}", addr),
+        }
+    }
+
+    fn octo_line(&self, line: &Line) -> String {
+        match (line.instruction, line.long) {
+            (Some(Instruction::LdILong), Some(addr)) => format!("i := long {:#06x}", addr),
+            (Some(Instruction::Jp(nnn)), _) if self.labels.contains_key(&nnn) => format!("jump {}", self.target(nnn)),
+            (Some(Instruction::Call(nnn)), _) if self.labels.contains_key(&nnn) => self.target(nnn),
+            (Some(Instruction::JpV0(nnn)), _) if self.labels.contains_key(&nnn) => format!("jump0 {}", self.target(nnn)),
+            (Some(Instruction::LdI(nnn)), _) if self.labels.contains_key(&nnn) => format!("i := {}", self.target(nnn)),
+            (Some(Instruction::Sys(_)), _) | (None, _) => {
+                let bytes: Vec<String> = line.bytes.iter().map(|byte| format!("{:#04x}", byte)).collect();
+                bytes.join(" ")
+            }
+            (Some(instruction), _) => Self::format_octo(instruction),
+        }
+    }
+
+    fn json_line(&self, line: &Line) -> String {
+        let text = self.mnemonic(line);
+        let (mnemonic, comment) = match text.split_once(" ; ") {
+            Some((mnemonic, comment)) => (mnemonic, Some(comment.trim_start_matches('(').trim_end_matches(')'))),
+            None => (text.as_str(), None),
+        };
+
+        let bytes: String = line.bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+        format!(
+            "  {{\"address\": {}, \"bytes\": \"{}\", \"label\": {}, \"instruction\": {}, \"comment\": {}}}",
+            line.addr,
+            bytes,
+            self.labels.get(&line.addr).map_or(String::from("null"), |label| json_string(label)),
+            json_string(mnemonic),
+            comment.map_or(String::from("null"), json_string)
+        )
+    }
+
+    /// The instruction in the listing's syntax, with a comment on what it does. Registers
+    /// are `Vx` and numbers `#`-prefixed hex, the way the assembler reads them back.
+    pub(crate) fn format(instruction: Instruction) -> String {
+        match instruction {
+            Instruction::Sys(nnn) => format!("SYS #{:03X} ; (ignored)", nnn),
+            Instruction::Cls => String::from("CLS"),
+            Instruction::Ret => String::from("RET"),
+            Instruction::Scd(n) => format!("SCD #{:X} ; (scroll down {})", n, n),
+            Instruction::Scu(n) => format!("SCU #{:X} ; (scroll up {})", n, n),
+            Instruction::Scr => String::from("SCR ; (scroll right 4)"),
+            Instruction::Scl => String::from("SCL ; (scroll left 4)"),
+            Instruction::Exit => String::from("EXIT"),
+            Instruction::Low => String::from("LOW ; (64x32)"),
+            Instruction::High => String::from("HIGH ; (128x64)"),
+            Instruction::Jp(nnn) => format!("JP #{:03X}", nnn),
+            Instruction::Call(nnn) => format!("CALL #{:03X}", nnn),
+            Instruction::SeByte(x, kk) => format!("SE V{:X}, #{:02X} ; (V{:X} == {} ? skip)", x, kk, x, kk),
+            Instruction::SneByte(x, kk) => format!("SNE V{:X}, #{:02X} ; (V{:X} != {} ? skip)", x, kk, x, kk),
+            Instruction::SeReg(x, y) => format!("SE V{:X}, V{:X} ; (V{:X} == V{:X} ? skip)", x, y, x, y),
+            Instruction::SaveRange(x, y) => {
+                format!("LD [I], V{:X} - V{:X} ; (mem[I..] = V{:X}..V{:X})", x, y, x, y)
+            }
+            Instruction::LoadRange(x, y) => {
+                format!("LD V{:X} - V{:X}, [I] ; (V{:X}..V{:X} = mem[I..])", x, y, x, y)
+            }
+            Instruction::LdByte(x, kk) => format!("LD V{:X}, #{:02X} ; (V{:X} = {})", x, kk, x, kk),
+            Instruction::AddByte(x, kk) => format!("ADD V{:X}, #{:02X} ; (V{:X} = V{:X} + {})", x, kk, x, x, kk),
+            Instruction::LdReg(x, y) => format!("LD V{:X}, V{:X} ; (V{:X} = V{:X})", x, y, x, y),
+            Instruction::Or(x, y) => format!("OR V{:X}, V{:X} ; (V{:X} = V{:X} OR V{:X})", x, y, x, x, y),
+            Instruction::And(x, y) => format!("AND V{:X}, V{:X} ; (V{:X} = V{:X} AND V{:X})", x, y, x, x, y),
+            Instruction::Xor(x, y) => format!("XOR V{:X}, V{:X} ; (V{:X} = V{:X} XOR V{:X})", x, y, x, x, y),
+            Instruction::AddReg(x, y) => {
+                format!("ADD V{:X}, V{:X} ; (V{:X} = V{:X} + V{:X}, VF = carry)", x, y, x, x, y)
+            }
+            Instruction::Sub(x, y) => {
+                format!("SUB V{:X}, V{:X} ; (V{:X} = V{:X} - V{:X}, VF = !borrow)", x, y, x, x, y)
+            }
+            Instruction::Shr(x, y) => {
+                format!("SHR V{:X}, V{:X} ; (V{:X} = V{:X} >> 1, VF = LSB)", x, y, x, x)
+            }
+            Instruction::Subn(x, y) => {
+                format!("SUBN V{:X}, V{:X} ; (V{:X} = V{:X} - V{:X}, VF = !borrow)", x, y, x, y, x)
+            }
+            Instruction::Shl(x, y) => {
+                format!("SHL V{:X}, V{:X} ; (V{:X} = V{:X} << 1, VF = MSB)", x, y, x, x)
+            }
+            Instruction::SneReg(x, y) => format!("SNE V{:X}, V{:X} ; (V{:X} != V{:X} ? skip)", x, y, x, y),
+            Instruction::LdI(nnn) => format!("LD I, #{:03X} ; (I = {})", nnn, nnn),
+            Instruction::JpV0(nnn) => format!("JP V0, #{:03X} ; (PC = V0 + {})", nnn, nnn),
+            Instruction::Rnd(x, kk) => format!("RND V{:X}, #{:02X} ; (V{:X} = rand() AND {:x})", x, kk, x, kk),
+            Instruction::Drw(x, y, n) => {
+                format!("DRW V{:X}, V{:X}, #{:X} ; ((V{:X}, V{:X}) -> {})", x, y, n, x, y, n)
+            }
+            Instruction::Skp(x) => format!("SKP V{:X} ; (key(V{:X}) ? skip)", x, x),
+            Instruction::Sknp(x) => format!("SKNP V{:X} ; (!key(V{:X}) ? skip)", x, x),
+            Instruction::LdVxDt(x) => format!("LD V{:X}, DT ; (V{:X} = DT)", x, x),
+            Instruction::LdVxK(x) => format!("LD V{:X}, K ; (Wait till keypress then V{:X} = Key)", x, x),
+            Instruction::LdDtVx(x) => format!("LD DT, V{:X} ; (DT = V{:X})", x, x),
+            Instruction::LdStVx(x) => format!("LD ST, V{:X} ; (ST = V{:X})", x, x),
+            Instruction::AddI(x) => format!("ADD I, V{:X} ; (I = I + V{:X})", x, x),
+            Instruction::LdF(x) => format!("LD F, V{:X} ; (I = Location of sprite for V{:X})", x, x),
+            Instruction::LdHf(x) => format!("LD HF, V{:X} ; (I = Location of big sprite for V{:X})", x, x),
+            Instruction::LdB(x) => format!("LD B, V{:X} ; (BCD(V{:X}) -> (I, I+1, I+2))", x, x),
+            Instruction::LdIVx(x) => format!("LD [I], V{:X} ; (mem[I + x] = Vx for x in 0..{})", x, x),
+            Instruction::LdVxI(x) => format!("LD V{:X}, [I] ; (Vx = mem[I + x] for x in 0..{})", x, x),
+            Instruction::LdRVx(x) => format!("LD R, V{:X} ; (flags = V0..V{:X})", x, x),
+            Instruction::LdVxR(x) => format!("LD V{:X}, R ; (V0..V{:X} = flags)", x, x),
+            Instruction::LdILong => String::from("LD I, LONG"),
+            Instruction::Plane(n) => format!("PLANE #{:X}", n),
+            Instruction::Audio => String::from("AUDIO ; (pattern = mem[I..I+16])"),
+            Instruction::Pitch(x) => format!("PITCH V{:X} ; (pitch = V{:X})", x, x),
+        }
+    }
+
+    /// The instruction in Octo's syntax. Skips become the condition of an `if ... then`
+    /// on the instruction after them, so the condition is the opposite of the skip's.
+    pub(crate) fn format_octo(instruction: Instruction) -> String {
+        match instruction {
+            Instruction::Sys(nnn) => format!("{:#04x} {:#04x}", nnn >> 8, nnn & 0xFF),
+            Instruction::Cls => String::from("clear"),
+            Instruction::Ret => String::from("return"),
+            Instruction::Scd(n) => format!("scroll-down {}", n),
+            Instruction::Scu(n) => format!("scroll-up {}", n),
+            Instruction::Scr => String::from("scroll-right"),
+            Instruction::Scl => String::from("scroll-left"),
+            Instruction::Exit => String::from("exit"),
+            Instruction::Low => String::from("lores"),
+            Instruction::High => String::from("hires"),
+            Instruction::Jp(nnn) => format!("jump {:#05x}", nnn),
+            Instruction::Call(nnn) => format!(":call {:#05x}", nnn),
+            Instruction::SeByte(x, kk) => format!("if v{:x} != {:#04x} then", x, kk),
+            Instruction::SneByte(x, kk) => format!("if v{:x} == {:#04x} then", x, kk),
+            Instruction::SeReg(x, y) => format!("if v{:x} != v{:x} then", x, y),
+            Instruction::SaveRange(x, y) => format!("save v{:x} - v{:x}", x, y),
+            Instruction::LoadRange(x, y) => format!("load v{:x} - v{:x}", x, y),
+            Instruction::LdByte(x, kk) => format!("v{:x} := {:#04x}", x, kk),
+            Instruction::AddByte(x, kk) => format!("v{:x} += {:#04x}", x, kk),
+            Instruction::LdReg(x, y) => format!("v{:x} := v{:x}", x, y),
+            Instruction::Or(x, y) => format!("v{:x} |= v{:x}", x, y),
+            Instruction::And(x, y) => format!("v{:x} &= v{:x}", x, y),
+            Instruction::Xor(x, y) => format!("v{:x} ^= v{:x}", x, y),
+            Instruction::AddReg(x, y) => format!("v{:x} += v{:x}", x, y),
+            Instruction::Sub(x, y) => format!("v{:x} -= v{:x}", x, y),
+            Instruction::Shr(x, y) => format!("v{:x} >>= v{:x}", x, y),
+            Instruction::Subn(x, y) => format!("v{:x} =- v{:x}", x, y),
+            Instruction::Shl(x, y) => format!("v{:x} <<= v{:x}", x, y),
+            Instruction::SneReg(x, y) => format!("if v{:x} == v{:x} then", x, y),
+            Instruction::LdI(nnn) => format!("i := {:#05x}", nnn),
+            Instruction::JpV0(nnn) => format!("jump0 {:#05x}", nnn),
+            Instruction::Rnd(x, kk) => format!("v{:x} := random {:#04x}", x, kk),
+            Instruction::Drw(x, y, n) => format!("sprite v{:x} v{:x} {}", x, y, n),
+            Instruction::Skp(x) => format!("if v{:x} -key then", x),
+            Instruction::Sknp(x) => format!("if v{:x} key then", x),
+            Instruction::LdVxDt(x) => format!("v{:x} := delay", x),
+            Instruction::LdVxK(x) => format!("v{:x} := key", x),
+            Instruction::LdDtVx(x) => format!("delay := v{:x}", x),
+            Instruction::LdStVx(x) => format!("buzzer := v{:x}", x),
+            Instruction::AddI(x) => format!("i += v{:x}", x),
+            Instruction::LdF(x) => format!("i := hex v{:x}", x),
+            Instruction::LdHf(x) => format!("i := bighex v{:x}", x),
+            Instruction::LdB(x) => format!("bcd v{:x}", x),
+            Instruction::LdIVx(x) => format!("save v{:x}", x),
+            Instruction::LdVxI(x) => format!("load v{:x}", x),
+            Instruction::LdRVx(x) => format!("saveflags v{:x}", x),
+            Instruction::LdVxR(x) => format!("loadflags v{:x}", x),
+            Instruction::LdILong => String::from("i := long"),
+            Instruction::Plane(n) => format!("plane {}", n),
+            Instruction::Audio => String::from("audio"),
+            Instruction::Pitch(x) => format!("pitch := v{:x}", x),
+        }
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
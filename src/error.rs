@@ -0,0 +1,41 @@
+use std::fmt;
+
+/// Everything that can stop the machine. Once one of these is raised the CPU
+/// halts and stays halted, addresses are kept so the frontend can point at the
+/// instruction that caused it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Chip8Error {
+    StackOverflow { addr: u16 },
+    StackUnderflow { addr: u16 },
+    MemoryOutOfBounds { addr: u16, access: usize },
+    RomTooLarge { size: usize, max: usize },
+    InvalidOpcode { addr: u16, opcode: u16 },
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Chip8Error::StackOverflow { addr } => {
+                write!(f, "stack overflow at {:#05x}", addr)
+            }
+
+            Chip8Error::StackUnderflow { addr } => {
+                write!(f, "stack underflow (RET with an empty stack) at {:#05x}", addr)
+            }
+
+            Chip8Error::MemoryOutOfBounds { addr, access } => {
+                write!(f, "memory access out of bounds ({:#x}) at {:#05x}", access, addr)
+            }
+
+            Chip8Error::RomTooLarge { size, max } => {
+                write!(f, "ROM is {} bytes, the largest that fits in memory is {} bytes", size, max)
+            }
+
+            Chip8Error::InvalidOpcode { addr, opcode } => {
+                write!(f, "invalid opcode {:04x} at {:#05x}", opcode, addr)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Chip8Error {}
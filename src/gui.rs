@@ -1,27 +1,57 @@
-use crate::WIN_WIDTH;
-use crate::WIN_HEIGHT;
-use crate::PIXEL_SIZE;
+use crate::audio::{AudioSettings, Beeper};
+use chipper8::Chip8Error;
+use chipper8::chip8::ResetKind;
+use chipper8::screen::{self, Screen};
 
 use sdl2::pixels::Color;
 use sdl2::event::Event;
 use sdl2::EventPump;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Mod};
 use sdl2::render::Canvas;
 use sdl2::video::Window;
 use sdl2::rect::Rect;
 
+// Emulator commands bound to keys outside the CHIP-8 keypad, or to files dropped on
+// the window.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Hotkey {
+    SaveState(u8), // Shift + F1-F9
+    LoadState(u8), // F1-F9
+    Pause,            // P, pauses or continues
+    Step,             // F11
+    StepOver,         // F10
+    StepOut,          // Shift + F11
+    StepBack,         // Shift + F10
+    ToggleBreakpoint, // B, at the program counter
+    Inspect,          // I, prints the registers to the terminal
+    ToggleReadOnly,   // T, switches the movie between playing back and recording
+    Reset(ResetKind), // F12 for a soft reset, Shift + F12 for a hard one
+    AskOpen,          // O, asks for the path of a ROM to open on the terminal
+    Open(String),     // A ROM file dropped on the window, or the path typed after O
+}
+
+// Size of a low resolution CHIP-8 pixel in the window.
+const PIXEL_SIZE: u32 = 15;
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct GUI {
     canvas: Canvas<Window>,
     events: EventPump,
+    beeper: Option<Beeper>,
+    hotkeys: Vec<Hotkey>,
+    rewinding: bool, // Backspace is held down
 }
 
 impl GUI {
-    pub fn new() -> Self {
+    pub fn new(audio: AudioSettings) -> Self {
         let sdl_ctx = sdl2::init().unwrap();
         let event_pump = sdl_ctx.event_pump().unwrap();
         let video_subsys = sdl_ctx.video().unwrap();
 
-        let window = video_subsys.window("Chipper 8", WIN_WIDTH * PIXEL_SIZE, WIN_HEIGHT * PIXEL_SIZE)
+        let win_width = screen::LORES_WIDTH as u32 * PIXEL_SIZE;
+        let win_height = screen::LORES_HEIGHT as u32 * PIXEL_SIZE;
+
+        let window = video_subsys.window("Chipper 8", win_width, win_height)
             .position_centered()
             .build()
             .unwrap();
@@ -32,21 +62,71 @@ impl GUI {
         canvas.clear();
         canvas.present();
 
+        // A missing sound card shouldn't stop the games from running.
+        let beeper = match Beeper::new(&sdl_ctx, audio) {
+            Ok(beeper) => Some(beeper),
+            Err(msg) => {
+                eprintln!("Sound disabled: {}", msg);
+                None
+            }
+        };
+
         Self {
-            canvas: canvas,
-            events: event_pump
+            canvas,
+            events: event_pump,
+            beeper,
+            hotkeys: Vec::new(),
+            rewinding: false,
         }
     }
 
-    pub fn draw(&mut self, screen: &[[bool; WIN_WIDTH as usize]; WIN_HEIGHT as usize]) {
-        for y in 0..WIN_HEIGHT {
-            let row = screen[y as usize];
+    pub fn draw(&mut self, screen: &Screen) {
+        self.draw_with(screen, Self::get_color);
+    }
+
+    // Freezes the last frame in red and puts the error in the title bar, so a crashed
+    // ROM leaves something to look at instead of closing the window.
+    pub fn show_error(&mut self, screen: &Screen, err: &Chip8Error) {
+        let title = format!("Chipper 8 - halted: {}", err);
+        self.canvas.window_mut().set_title(&title).ok();
+
+        self.set_buzzer(false);
+
+        self.draw_with(screen, |pixel| {
+            if pixel != 0 {
+                Color::RGB(255, 80, 80)
+            } else {
+                Color::RGB(60, 0, 0)
+            }
+        });
+    }
+
+    pub fn clear_error(&mut self) {
+        self.canvas.window_mut().set_title("Chipper 8").ok();
+    }
+
+    pub fn show_paused(&mut self, paused: bool) {
+        let title = if paused { "Chipper 8 - paused" } else { "Chipper 8" };
+        self.canvas.window_mut().set_title(title).ok();
+    }
+
+    fn draw_with<F>(&mut self, screen: &Screen, color_of: F)
+    where F: Fn(u8) -> Color {
+        let width = screen.width() as u32;
+        let height = screen.height() as u32;
+
+        // Drawing in CHIP-8 pixels and letting SDL scale them up means the window
+        // doesn't care whether the program is in low or high resolution mode.
+        if self.canvas.logical_size() != (width, height) {
+            self.canvas.set_logical_size(width, height).expect("Error resizing the screen");
+        }
 
-            for x in 0..WIN_WIDTH {
-                let pixel_val = row[x as usize];
+        for y in 0..height {
+            for x in 0..width {
+                let pixel_val = screen.get(x as usize, y as usize);
 
-                let col = self.get_color(pixel_val);
-                let rect = Rect::new((x * PIXEL_SIZE) as i32 , (y * PIXEL_SIZE) as i32, PIXEL_SIZE, PIXEL_SIZE);
+                let col = color_of(pixel_val);
+                let rect = Rect::new(x as i32, y as i32, 1, 1);
                 self.canvas.set_draw_color(col);
                 self.canvas.fill_rect(rect).expect("Error drawing to the screen");
             }
@@ -59,11 +139,47 @@ impl GUI {
         for event in self.events.poll_iter() {
             match event {
                 Event::Quit {..} => return None,
+
+                // M toggles the sound on and off.
+                Event::KeyDown { keycode: Some(Keycode::M), repeat: false, .. } => {
+                    if let Some(beeper) = self.beeper.as_mut() {
+                        beeper.toggle_mute();
+                    }
+                }
+
+                Event::KeyDown { keycode: Some(key), keymod, repeat, .. } => {
+                    let shift = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+
+                    // Stepping is the one thing worth holding a key down for.
+                    let hotkey = match key {
+                        Keycode::F10 if shift => Some(Hotkey::StepBack),
+                        Keycode::F10 => Some(Hotkey::StepOver),
+                        Keycode::F11 if shift => Some(Hotkey::StepOut),
+                        Keycode::F11 => Some(Hotkey::Step),
+                        _ if repeat => None,
+                        Keycode::P => Some(Hotkey::Pause),
+                        Keycode::B => Some(Hotkey::ToggleBreakpoint),
+                        Keycode::I => Some(Hotkey::Inspect),
+                        Keycode::T => Some(Hotkey::ToggleReadOnly),
+                        Keycode::O => Some(Hotkey::AskOpen),
+                        Keycode::F12 if shift => Some(Hotkey::Reset(ResetKind::Hard)),
+                        Keycode::F12 => Some(Hotkey::Reset(ResetKind::Soft)),
+                        _ => Self::state_slot(key).map(|slot| {
+                            if shift { Hotkey::SaveState(slot) } else { Hotkey::LoadState(slot) }
+                        }),
+                    };
+
+                    self.hotkeys.extend(hotkey);
+                }
+
+                Event::DropFile { filename, .. } => self.hotkeys.push(Hotkey::Open(filename)),
+
                 _ => ()
             }
         }
 
         let mut keypad = [false; 16];
+        self.rewinding = false;
 
         let keys: Vec<Keycode> = self.events
                                     .keyboard_state()
@@ -89,6 +205,7 @@ impl GUI {
                 Keycode::X => keypad[0x0] = true,
                 Keycode::C => keypad[0xB] = true,
                 Keycode::V => keypad[0xF] = true,
+                Keycode::Backspace => self.rewinding = true,
                 _ => ()
             }
         }
@@ -96,11 +213,47 @@ impl GUI {
         Some(keypad)
     }
 
-    fn get_color(&self, pixel: bool) -> Color {
-        if pixel {
-            Color::RGB(255, 255, 255)
-        } else {
-            Color::RGB(0, 0, 0)
+    // Whether the game should run backwards, as of the last get_keypad_state.
+    pub fn rewinding(&self) -> bool {
+        self.rewinding
+    }
+
+    // Hotkeys pressed since the last call, oldest first.
+    pub fn take_hotkeys(&mut self) -> Vec<Hotkey> {
+        std::mem::take(&mut self.hotkeys)
+    }
+
+    fn state_slot(key: Keycode) -> Option<u8> {
+        let slot = match key {
+            Keycode::F1 => 1,
+            Keycode::F2 => 2,
+            Keycode::F3 => 3,
+            Keycode::F4 => 4,
+            Keycode::F5 => 5,
+            Keycode::F6 => 6,
+            Keycode::F7 => 7,
+            Keycode::F8 => 8,
+            Keycode::F9 => 9,
+            _ => return None,
+        };
+
+        Some(slot)
+    }
+
+    pub fn set_buzzer(&mut self, active: bool) {
+        if let Some(beeper) = self.beeper.as_mut() {
+            beeper.set_active(active);
         }
     }
+
+    pub fn set_audio_pattern(&mut self, pattern: Option<([u8; 16], u8)>) {
+        if let Some(beeper) = self.beeper.as_mut() {
+            beeper.set_pattern(pattern);
+        }
+    }
+
+    fn get_color(pixel: u8) -> Color {
+        let (r, g, b) = screen::PALETTE[(pixel & screen::ALL_PLANES) as usize];
+        Color::RGB(r, g, b)
+    }
 }
@@ -0,0 +1,354 @@
+// Runs a ROM without opening a window: a fixed number of frames or cycles with
+// scripted keypad input, then the final screen is written out. Meant for CI and
+// scripts, so nothing here touches SDL.
+use crate::prompt::{self, Prompt};
+use chipper8::rewind::Rewind;
+use chipper8::diff::{self, Lockstep};
+use chipper8::{Debugger, Movie, Quirks, CHIP8};
+use chipper8::screen::{self, Screen};
+
+use std::fs;
+
+pub const DEFAULT_FRAMES: u64 = 600; // Ten emulated seconds
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpFormat {
+    Ascii,
+    Pbm,
+    Png,
+}
+
+impl DumpFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "ascii" | "txt" => Some(DumpFormat::Ascii),
+            "pbm" => Some(DumpFormat::Pbm),
+            "png" => Some(DumpFormat::Png),
+            _ => None,
+        }
+    }
+
+    // Guesses the format from a file name, falling back to ASCII art.
+    pub fn from_path(path: &str) -> Self {
+        path.rsplit('.').next()
+            .and_then(Self::from_name)
+            .unwrap_or(DumpFormat::Ascii)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Frames(u64),
+    Cycles(u64),
+}
+
+// Keypad input as a list of (frame, keys) changes. Each entry holds from its frame
+// until the next one, e.g. "0:;30:5;35:" presses key 5 for frames 30 to 34.
+// Entries are separated by ';' or newlines, keys are hex digits.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyScript {
+    changes: Vec<(u64, [bool; 16])>,
+}
+
+impl KeyScript {
+    pub fn parse(script: &str) -> Result<Self, String> {
+        let mut changes = Vec::new();
+
+        for entry in script.split([';', '\n']).map(str::trim) {
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+
+            let (frame, keys) = match entry.find(':') {
+                Some(idx) => (&entry[..idx], &entry[idx + 1..]),
+                None => return Err(format!("Key script entry '{}' should look like <frame>:<keys>", entry)),
+            };
+
+            let frame = frame.trim().parse::<u64>()
+                .map_err(|_| format!("Invalid frame number in key script entry '{}'", entry))?;
+
+            let mut keypad = [false; 16];
+            for key in keys.chars().filter(|c| !c.is_whitespace() && *c != ',') {
+                let idx = key.to_digit(16)
+                    .ok_or_else(|| format!("Invalid key '{}' in key script entry '{}'", key, entry))?;
+                keypad[idx as usize] = true;
+            }
+
+            changes.push((frame, keypad));
+        }
+
+        changes.sort_by_key(|&(frame, _)| frame);
+
+        Ok(Self { changes })
+    }
+
+    pub fn keypad_at(&self, frame: u64) -> [bool; 16] {
+        self.changes.iter()
+            .take_while(|&&(start, _)| start <= frame)
+            .last()
+            .map_or([false; 16], |&(_, keypad)| keypad)
+    }
+}
+
+pub struct HeadlessOptions {
+    pub limit: Limit,
+    pub keys: KeyScript,
+    pub dump_path: Option<String>, // Standard output when missing
+    pub format: Option<DumpFormat>,
+    pub compare: Option<Quirks>, // Run a second machine with these quirks next to the first
+}
+
+// Returns false if the machine halted on an error before reaching the limit.
+// With a debugger the terminal prompt takes over whenever it pauses, and the rewind
+// buffer gets a snapshot every frame so it can step back. A movie either records the
+// key script or, when it's read-only, replaces it.
+pub fn run(
+    cpu: &mut CHIP8,
+    options: &HeadlessOptions,
+    mut debugger: Option<Debugger>,
+    mut rewind: Rewind,
+    mut movie: Option<&mut Movie>,
+) -> Result<bool, String> {
+    let mut prompt = Prompt::default();
+    let mut snapshot_frame = None;
+
+    if let Some(dbg) = &debugger {
+        dbg.attach(cpu);
+    }
+
+    let completed = loop {
+        // Read back from the machine, they go down again when the debugger steps back.
+        let frame = cpu.frames();
+
+        let done = match options.limit {
+            Limit::Frames(limit) => frame >= limit,
+            Limit::Cycles(limit) => cpu.cycles() >= limit,
+        };
+
+        if done || cpu.exited() {
+            break true;
+        }
+
+        if cpu.frame_complete() {
+            cpu.end_frame();
+            continue;
+        }
+
+        let keypad = options.keys.keypad_at(frame);
+        let keypad = match movie.as_mut() {
+            Some(movie) => movie.input(frame, keypad),
+            None => keypad,
+        };
+
+        // One snapshot per frame, taken as it starts.
+        if debugger.is_some() && snapshot_frame != Some(frame) {
+            rewind.push(cpu, keypad);
+            snapshot_frame = Some(frame);
+        }
+
+        if let Some(dbg) = debugger.as_mut() {
+            if !dbg.check(cpu) {
+                prompt::report_stop(dbg, cpu);
+
+                match prompt::read_line() {
+                    Some(line) => {
+                        let command = prompt.interpret(&line, dbg.symbols());
+                        if command.is_some_and(|command| !prompt::execute(command, dbg, cpu, &mut rewind)) {
+                            break true;
+                        }
+                    }
+
+                    // Nobody left to type commands, let the program finish on its own.
+                    None => {
+                        dbg.resume();
+                        debugger = None;
+                    }
+                }
+
+                continue;
+            }
+        }
+
+        let result = match debugger.as_mut() {
+            Some(dbg) => dbg.tick(cpu, keypad),
+            None => cpu.tick(keypad),
+        };
+
+        if let Err(err) = result {
+            eprintln!("CHIP-8 halted after {} frames: {}", frame, err);
+            break false;
+        }
+    };
+
+    let format = match (options.format, &options.dump_path) {
+        (Some(format), _) => format,
+        (None, Some(path)) => DumpFormat::from_path(path),
+        (None, None) => DumpFormat::Ascii,
+    };
+
+    let output = match format {
+        DumpFormat::Ascii => to_ascii(cpu.screen()).into_bytes(),
+        DumpFormat::Pbm => to_pbm(cpu.screen()).into_bytes(),
+        DumpFormat::Png => to_png(cpu.screen()),
+    };
+
+    match &options.dump_path {
+        Some(path) => fs::write(path, output).map_err(|err| format!("Can't write {}: {}", path, err))?,
+        None => {
+            use std::io::Write;
+            std::io::stdout().write_all(&output).map_err(|err| err.to_string())?;
+        }
+    }
+
+    Ok(completed)
+}
+
+// Runs two machines on the same input until they stop agreeing, then reports where.
+// Returns false if they did.
+pub fn compare(a: CHIP8, b: CHIP8, options: &HeadlessOptions, movie: Option<&Movie>) -> bool {
+    let mut lockstep = Lockstep::new(a, b, diff::DEFAULT_CONTEXT);
+
+    let keypad = |frame| match movie.and_then(|movie| movie.keypad(frame)) {
+        Some(keypad) => keypad,
+        None => options.keys.keypad_at(frame),
+    };
+
+    loop {
+        let done = match options.limit {
+            Limit::Frames(limit) => lockstep.a.frames() >= limit,
+            Limit::Cycles(limit) => lockstep.a.cycles() >= limit,
+        };
+
+        if done || lockstep.done() {
+            println!("No difference after {} cycles ({} frames)", lockstep.a.cycles(), lockstep.a.frames());
+            return true;
+        }
+
+        if let Some(divergence) = lockstep.tick(keypad) {
+            println!("{}", divergence);
+            return false;
+        }
+    }
+}
+
+// One character per pixel. Plane 1 is '#', plane 2 is '+' and both planes are '@'.
+pub fn to_ascii(screen: &Screen) -> String {
+    let mut out = String::with_capacity((screen.width() + 1) * screen.height());
+
+    for y in 0..screen.height() {
+        for x in 0..screen.width() {
+            out.push(match screen.get(x, y) {
+                0 => '.',
+                1 => '#',
+                2 => '+',
+                _ => '@',
+            });
+        }
+        out.push('\n');
+    }
+
+    out
+}
+
+// Plain (P1) portable bitmap. Any lit plane counts as black.
+pub fn to_pbm(screen: &Screen) -> String {
+    let mut out = format!("P1\n{} {}\n", screen.width(), screen.height());
+
+    for y in 0..screen.height() {
+        let row: Vec<&str> = (0..screen.width())
+            .map(|x| if screen.get(x, y) != 0 { "1" } else { "0" })
+            .collect();
+        out.push_str(&row.join(" "));
+        out.push('\n');
+    }
+
+    out
+}
+
+// Indexed color PNG using the same palette as the window. The image data is
+// stored without compression, which keeps this free of dependencies.
+pub fn to_png(screen: &Screen) -> Vec<u8> {
+    let mut png = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(screen.width() as u32).to_be_bytes());
+    header.extend_from_slice(&(screen.height() as u32).to_be_bytes());
+    header.extend_from_slice(&[8, 3, 0, 0, 0]); // 8 bit palette indices, no interlacing
+    write_chunk(&mut png, b"IHDR", &header);
+
+    let palette: Vec<u8> = screen::PALETTE.iter()
+        .flat_map(|&(r, g, b)| vec![r, g, b])
+        .collect();
+    write_chunk(&mut png, b"PLTE", &palette);
+
+    let mut raw = Vec::with_capacity((screen.width() + 1) * screen.height());
+    for y in 0..screen.height() {
+        raw.push(0); // No filter
+        for x in 0..screen.width() {
+            raw.push(screen.get(x, y) & screen::ALL_PLANES);
+        }
+    }
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+
+    write_chunk(&mut png, b"IEND", &[]);
+
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// A zlib stream made of uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    (b << 16) | a
+}
@@ -0,0 +1,342 @@
+//! Decoded CHIP-8 instructions.
+//!
+//! Covers the original instruction set plus the SUPER-CHIP 1.1 and XO-CHIP
+//! extensions. Register operands are register numbers (0x0 - 0xF), addresses
+//! are 12 bits unless noted otherwise.
+
+use std::fmt;
+
+/// One decoded instruction, named after the mnemonics in Cowgod's reference.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    /// `0nnn` - SYS addr. Jump to a machine code routine, ignored by interpreters.
+    Sys(u16),
+    /// `00E0` - CLS
+    Cls,
+    /// `00EE` - RET
+    Ret,
+    /// `00Cn` - SCD nibble (SUPER-CHIP). Scroll down n pixels.
+    Scd(u8),
+    /// `00Dn` - SCU nibble (XO-CHIP). Scroll up n pixels.
+    Scu(u8),
+    /// `00FB` - SCR (SUPER-CHIP). Scroll right 4 pixels.
+    Scr,
+    /// `00FC` - SCL (SUPER-CHIP). Scroll left 4 pixels.
+    Scl,
+    /// `00FD` - EXIT (SUPER-CHIP)
+    Exit,
+    /// `00FE` - LOW (SUPER-CHIP). Switch to 64x32.
+    Low,
+    /// `00FF` - HIGH (SUPER-CHIP). Switch to 128x64.
+    High,
+    /// `1nnn` - JP addr
+    Jp(u16),
+    /// `2nnn` - CALL addr
+    Call(u16),
+    /// `3xkk` - SE Vx, byte
+    SeByte(u8, u8),
+    /// `4xkk` - SNE Vx, byte
+    SneByte(u8, u8),
+    /// `5xy0` - SE Vx, Vy
+    SeReg(u8, u8),
+    /// `5xy2` - `LD [I], Vx - Vy` (XO-CHIP)
+    SaveRange(u8, u8),
+    /// `5xy3` - `LD Vx - Vy, [I]` (XO-CHIP)
+    LoadRange(u8, u8),
+    /// `6xkk` - LD Vx, byte
+    LdByte(u8, u8),
+    /// `7xkk` - ADD Vx, byte
+    AddByte(u8, u8),
+    /// `8xy0` - LD Vx, Vy
+    LdReg(u8, u8),
+    /// `8xy1` - OR Vx, Vy
+    Or(u8, u8),
+    /// `8xy2` - AND Vx, Vy
+    And(u8, u8),
+    /// `8xy3` - XOR Vx, Vy
+    Xor(u8, u8),
+    /// `8xy4` - ADD Vx, Vy
+    AddReg(u8, u8),
+    /// `8xy5` - SUB Vx, Vy
+    Sub(u8, u8),
+    /// `8xy6` - SHR Vx {, Vy}
+    Shr(u8, u8),
+    /// `8xy7` - SUBN Vx, Vy
+    Subn(u8, u8),
+    /// `8xyE` - SHL Vx {, Vy}
+    Shl(u8, u8),
+    /// `9xy0` - SNE Vx, Vy
+    SneReg(u8, u8),
+    /// `Annn` - LD I, addr
+    LdI(u16),
+    /// `Bnnn` - JP V0, addr (`Bxnn` - JP Vx, addr with the jump quirk)
+    JpV0(u16),
+    /// `Cxkk` - RND Vx, byte
+    Rnd(u8, u8),
+    /// `Dxyn` - DRW Vx, Vy, nibble
+    Drw(u8, u8, u8),
+    /// `Ex9E` - SKP Vx
+    Skp(u8),
+    /// `ExA1` - SKNP Vx
+    Sknp(u8),
+    /// `Fx07` - LD Vx, DT
+    LdVxDt(u8),
+    /// `Fx0A` - LD Vx, K
+    LdVxK(u8),
+    /// `Fx15` - LD DT, Vx
+    LdDtVx(u8),
+    /// `Fx18` - LD ST, Vx
+    LdStVx(u8),
+    /// `Fx1E` - ADD I, Vx
+    AddI(u8),
+    /// `Fx29` - LD F, Vx
+    LdF(u8),
+    /// `Fx30` - LD HF, Vx (SUPER-CHIP)
+    LdHf(u8),
+    /// `Fx33` - LD B, Vx
+    LdB(u8),
+    /// `Fx55` - `LD [I], Vx`
+    LdIVx(u8),
+    /// `Fx65` - `LD Vx, [I]`
+    LdVxI(u8),
+    /// `Fx75` - LD R, Vx (SUPER-CHIP)
+    LdRVx(u8),
+    /// `Fx85` - LD Vx, R (SUPER-CHIP)
+    LdVxR(u8),
+    /// `F000 nnnn` - LD I, long nnnn (XO-CHIP). The 16 bit address is the next word.
+    LdILong,
+    /// `Fn01` - PLANE n (XO-CHIP)
+    Plane(u8),
+    /// `F002` - AUDIO (XO-CHIP)
+    Audio,
+    /// `Fx3A` - PITCH Vx (XO-CHIP)
+    Pitch(u8),
+}
+
+/// The opcode doesn't correspond to any instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodeError {
+    pub opcode: u16,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x} is not a valid instruction", self.opcode)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Instruction {
+    /// Decodes a 16 bit opcode.
+    pub fn decode(opcode: u16) -> Result<Self, DecodeError> {
+        let units = (
+            ((opcode & 0xF000) >> 12) as u8,
+            ((opcode & 0x0F00) >> 8) as u8,
+            ((opcode & 0x00F0) >> 4) as u8,
+            (opcode & 0x000F) as u8
+        );
+
+        let (x, y, n) = (units.1, units.2, units.3);
+        let nnn = opcode & 0x0FFF;
+        let kk = (opcode & 0x00FF) as u8;
+
+        let instruction = match units {
+            (0x0, 0x0, 0xE, 0x0) => Instruction::Cls,
+            (0x0, 0x0, 0xE, 0xE) => Instruction::Ret,
+            (0x0, 0x0, 0xC, _) => Instruction::Scd(n),
+            (0x0, 0x0, 0xD, _) => Instruction::Scu(n),
+            (0x0, 0x0, 0xF, 0xB) => Instruction::Scr,
+            (0x0, 0x0, 0xF, 0xC) => Instruction::Scl,
+            (0x0, 0x0, 0xF, 0xD) => Instruction::Exit,
+            (0x0, 0x0, 0xF, 0xE) => Instruction::Low,
+            (0x0, 0x0, 0xF, 0xF) => Instruction::High,
+            (0x0, _, _, _) => Instruction::Sys(nnn),
+            (0x1, _, _, _) => Instruction::Jp(nnn),
+            (0x2, _, _, _) => Instruction::Call(nnn),
+            (0x3, _, _, _) => Instruction::SeByte(x, kk),
+            (0x4, _, _, _) => Instruction::SneByte(x, kk),
+            (0x5, _, _, 0x0) => Instruction::SeReg(x, y),
+            (0x5, _, _, 0x2) => Instruction::SaveRange(x, y),
+            (0x5, _, _, 0x3) => Instruction::LoadRange(x, y),
+            (0x6, _, _, _) => Instruction::LdByte(x, kk),
+            (0x7, _, _, _) => Instruction::AddByte(x, kk),
+            (0x8, _, _, 0x0) => Instruction::LdReg(x, y),
+            (0x8, _, _, 0x1) => Instruction::Or(x, y),
+            (0x8, _, _, 0x2) => Instruction::And(x, y),
+            (0x8, _, _, 0x3) => Instruction::Xor(x, y),
+            (0x8, _, _, 0x4) => Instruction::AddReg(x, y),
+            (0x8, _, _, 0x5) => Instruction::Sub(x, y),
+            (0x8, _, _, 0x6) => Instruction::Shr(x, y),
+            (0x8, _, _, 0x7) => Instruction::Subn(x, y),
+            (0x8, _, _, 0xE) => Instruction::Shl(x, y),
+            (0x9, _, _, 0x0) => Instruction::SneReg(x, y),
+            (0xA, _, _, _) => Instruction::LdI(nnn),
+            (0xB, _, _, _) => Instruction::JpV0(nnn),
+            (0xC, _, _, _) => Instruction::Rnd(x, kk),
+            (0xD, _, _, _) => Instruction::Drw(x, y, n),
+            (0xE, _, 0x9, 0xE) => Instruction::Skp(x),
+            (0xE, _, 0xA, 0x1) => Instruction::Sknp(x),
+            (0xF, 0x0, 0x0, 0x0) => Instruction::LdILong,
+            (0xF, _, 0x0, 0x1) => Instruction::Plane(x),
+            (0xF, 0x0, 0x0, 0x2) => Instruction::Audio,
+            (0xF, _, 0x0, 0x7) => Instruction::LdVxDt(x),
+            (0xF, _, 0x0, 0xA) => Instruction::LdVxK(x),
+            (0xF, _, 0x1, 0x5) => Instruction::LdDtVx(x),
+            (0xF, _, 0x1, 0x8) => Instruction::LdStVx(x),
+            (0xF, _, 0x1, 0xE) => Instruction::AddI(x),
+            (0xF, _, 0x2, 0x9) => Instruction::LdF(x),
+            (0xF, _, 0x3, 0x0) => Instruction::LdHf(x),
+            (0xF, _, 0x3, 0x3) => Instruction::LdB(x),
+            (0xF, _, 0x3, 0xA) => Instruction::Pitch(x),
+            (0xF, _, 0x5, 0x5) => Instruction::LdIVx(x),
+            (0xF, _, 0x6, 0x5) => Instruction::LdVxI(x),
+            (0xF, _, 0x7, 0x5) => Instruction::LdRVx(x),
+            (0xF, _, 0x8, 0x5) => Instruction::LdVxR(x),
+            _ => return Err(DecodeError { opcode }),
+        };
+
+        Ok(instruction)
+    }
+
+    /// Encodes the instruction back into its opcode. Register and address operands are
+    /// masked to the bits the opcode has room for. For `F000 nnnn` only the first word
+    /// is returned, the address goes in the word after it.
+    pub fn encode(&self) -> u16 {
+        fn xy(high: u16, x: u8, y: u8, low: u16) -> u16 {
+            (high << 12) | ((x as u16 & 0xF) << 8) | ((y as u16 & 0xF) << 4) | low
+        }
+
+        fn xkk(high: u16, x: u8, kk: u8) -> u16 {
+            (high << 12) | ((x as u16 & 0xF) << 8) | kk as u16
+        }
+
+        fn fx(x: u8, low: u16) -> u16 {
+            0xF000 | ((x as u16 & 0xF) << 8) | low
+        }
+
+        match *self {
+            Instruction::Sys(nnn) => nnn & 0x0FFF,
+            Instruction::Cls => 0x00E0,
+            Instruction::Ret => 0x00EE,
+            Instruction::Scd(n) => 0x00C0 | (n as u16 & 0xF),
+            Instruction::Scu(n) => 0x00D0 | (n as u16 & 0xF),
+            Instruction::Scr => 0x00FB,
+            Instruction::Scl => 0x00FC,
+            Instruction::Exit => 0x00FD,
+            Instruction::Low => 0x00FE,
+            Instruction::High => 0x00FF,
+            Instruction::Jp(nnn) => 0x1000 | (nnn & 0x0FFF),
+            Instruction::Call(nnn) => 0x2000 | (nnn & 0x0FFF),
+            Instruction::SeByte(x, kk) => xkk(0x3, x, kk),
+            Instruction::SneByte(x, kk) => xkk(0x4, x, kk),
+            Instruction::SeReg(x, y) => xy(0x5, x, y, 0x0),
+            Instruction::SaveRange(x, y) => xy(0x5, x, y, 0x2),
+            Instruction::LoadRange(x, y) => xy(0x5, x, y, 0x3),
+            Instruction::LdByte(x, kk) => xkk(0x6, x, kk),
+            Instruction::AddByte(x, kk) => xkk(0x7, x, kk),
+            Instruction::LdReg(x, y) => xy(0x8, x, y, 0x0),
+            Instruction::Or(x, y) => xy(0x8, x, y, 0x1),
+            Instruction::And(x, y) => xy(0x8, x, y, 0x2),
+            Instruction::Xor(x, y) => xy(0x8, x, y, 0x3),
+            Instruction::AddReg(x, y) => xy(0x8, x, y, 0x4),
+            Instruction::Sub(x, y) => xy(0x8, x, y, 0x5),
+            Instruction::Shr(x, y) => xy(0x8, x, y, 0x6),
+            Instruction::Subn(x, y) => xy(0x8, x, y, 0x7),
+            Instruction::Shl(x, y) => xy(0x8, x, y, 0xE),
+            Instruction::SneReg(x, y) => xy(0x9, x, y, 0x0),
+            Instruction::LdI(nnn) => 0xA000 | (nnn & 0x0FFF),
+            Instruction::JpV0(nnn) => 0xB000 | (nnn & 0x0FFF),
+            Instruction::Rnd(x, kk) => xkk(0xC, x, kk),
+            Instruction::Drw(x, y, n) => xy(0xD, x, y, n as u16 & 0xF),
+            Instruction::Skp(x) => xkk(0xE, x, 0x9E),
+            Instruction::Sknp(x) => xkk(0xE, x, 0xA1),
+            Instruction::LdVxDt(x) => fx(x, 0x07),
+            Instruction::LdVxK(x) => fx(x, 0x0A),
+            Instruction::LdDtVx(x) => fx(x, 0x15),
+            Instruction::LdStVx(x) => fx(x, 0x18),
+            Instruction::AddI(x) => fx(x, 0x1E),
+            Instruction::LdF(x) => fx(x, 0x29),
+            Instruction::LdHf(x) => fx(x, 0x30),
+            Instruction::LdB(x) => fx(x, 0x33),
+            Instruction::LdIVx(x) => fx(x, 0x55),
+            Instruction::LdVxI(x) => fx(x, 0x65),
+            Instruction::LdRVx(x) => fx(x, 0x75),
+            Instruction::LdVxR(x) => fx(x, 0x85),
+            Instruction::LdILong => 0xF000,
+            Instruction::Plane(n) => fx(n, 0x01),
+            Instruction::Audio => 0xF002,
+            Instruction::Pitch(x) => fx(x, 0x3A),
+        }
+    }
+
+    /// Size of the instruction in bytes. Everything is one word, except `F000 nnnn`.
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LdILong => 4,
+            _ => 2,
+        }
+    }
+
+    /// What kind of instruction it is, see [`Class`].
+    pub fn class(&self) -> Class {
+        match self {
+            Instruction::Sys(_) | Instruction::Ret | Instruction::Exit | Instruction::Jp(_) |
+            Instruction::Call(_) | Instruction::JpV0(_) => Class::Flow,
+
+            Instruction::SeByte(..) | Instruction::SneByte(..) | Instruction::SeReg(..) |
+            Instruction::SneReg(..) => Class::Skip,
+
+            Instruction::LdByte(..) | Instruction::AddByte(..) | Instruction::LdReg(..) |
+            Instruction::Or(..) | Instruction::And(..) | Instruction::Xor(..) | Instruction::AddReg(..) |
+            Instruction::Sub(..) | Instruction::Shr(..) | Instruction::Subn(..) | Instruction::Shl(..) |
+            Instruction::Rnd(..) => Class::Alu,
+
+            Instruction::SaveRange(..) | Instruction::LoadRange(..) | Instruction::LdI(_) |
+            Instruction::AddI(_) | Instruction::LdF(_) | Instruction::LdHf(_) | Instruction::LdB(_) |
+            Instruction::LdIVx(_) | Instruction::LdVxI(_) | Instruction::LdRVx(_) |
+            Instruction::LdVxR(_) | Instruction::LdILong => Class::Memory,
+
+            Instruction::Cls | Instruction::Scd(_) | Instruction::Scu(_) | Instruction::Scr |
+            Instruction::Scl | Instruction::Low | Instruction::High | Instruction::Drw(..) |
+            Instruction::Plane(_) => Class::Display,
+
+            Instruction::Skp(_) | Instruction::Sknp(_) | Instruction::LdVxK(_) => Class::Input,
+
+            Instruction::LdVxDt(_) | Instruction::LdDtVx(_) | Instruction::LdStVx(_) => Class::Timer,
+
+            Instruction::Audio | Instruction::Pitch(_) => Class::Audio,
+        }
+    }
+}
+
+/// Rough groups of instructions, for picking out the interesting ones in a trace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Class {
+    Flow,    // Jumps, calls and returns
+    Skip,    // Conditional skips on registers
+    Alu,     // Arithmetic and logic on V registers
+    Memory,  // Anything that moves I or touches memory through it
+    Display, // Drawing, scrolling and resolution changes
+    Input,   // Keypad checks and waits
+    Timer,   // Delay and sound timers
+    Audio,   // XO-CHIP audio pattern and pitch
+}
+
+pub const CLASS_NAMES: [&str; 8] = ["flow", "skip", "alu", "memory", "display", "input", "timer", "audio"];
+
+impl Class {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "flow" => Some(Class::Flow),
+            "skip" => Some(Class::Skip),
+            "alu" => Some(Class::Alu),
+            "memory" | "mem" => Some(Class::Memory),
+            "display" | "draw" => Some(Class::Display),
+            "input" | "key" => Some(Class::Input),
+            "timer" => Some(Class::Timer),
+            "audio" => Some(Class::Audio),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,49 @@
+//! A CHIP-8 interpreter, with the SUPER-CHIP 1.1 and XO-CHIP extensions.
+//!
+//! [`CHIP8`] is the machine. Build one from a ROM and a set of [`Quirks`], then
+//! feed it the keypad state once per frame:
+//!
+//! ```no_run
+//! use chipper8::{CHIP8, Quirks};
+//!
+//! let rom = std::fs::read("pong.ch8").unwrap();
+//! let mut cpu = CHIP8::new(rom, Quirks::default()).unwrap();
+//!
+//! loop {
+//!     let keypad = [false; 16];
+//!     if cpu.run_frame(keypad).unwrap() {
+//!         // cpu.screen() changed, draw it
+//!     }
+//!     // wait for the next 1/60th of a second
+//! }
+//! ```
+//!
+//! Frontends (the SDL window, the headless runner) live in the `chipper8` binary
+//! and only use the API exported here.
+
+pub mod assembler;
+pub mod chip8;
+pub mod condition;
+pub mod debugger;
+pub mod diff;
+pub mod disassembler;
+pub mod error;
+pub mod instruction;
+pub mod movie;
+pub mod quirks;
+pub mod rewind;
+mod rng;
+pub mod savestate;
+pub mod screen;
+pub mod symbols;
+pub mod trace;
+
+pub use chip8::CHIP8;
+pub use debugger::Debugger;
+pub use disassembler::Disassembler;
+pub use error::Chip8Error;
+pub use instruction::{DecodeError, Instruction};
+pub use movie::Movie;
+pub use quirks::Quirks;
+pub use screen::Screen;
+pub use symbols::Symbols;
@@ -1,64 +1,1077 @@
-mod chip8;
+#[cfg(feature = "gui")]
+mod audio;
+#[cfg(feature = "gui")]
 mod gui;
-//mod disassembler;
-//use disassembler::Disassembler;
+mod headless;
+mod prompt;
+#[cfg(feature = "gui")]
+mod watch;
+
+use chipper8::instruction::{self, Class};
+use chipper8::rewind::{self, Rewind};
+use chipper8::diff;
+use chipper8::trace::{self, TraceFilter, TraceFormat, Tracer};
+use chipper8::disassembler::{Format, Mode};
+use chipper8::{assembler, movie};
+use chipper8::{chip8, quirks, Debugger, Disassembler, Instruction, Movie, Quirks, Symbols};
 use std::vec::Vec;
 use std::fs::{self, File};
-use std::io::prelude::*;
-use std::time::Duration;
-
-const WIN_WIDTH: u32 = 64;
-const WIN_HEIGHT: u32 = 32;
-const PIXEL_SIZE: u32 = 15;
+use std::io::BufWriter;
+use std::path::Path;
+#[cfg(feature = "gui")]
+use std::time::{Duration, Instant};
+use headless::{DumpFormat, HeadlessOptions, KeyScript, Limit};
+#[cfg(feature = "gui")]
+use audio::AudioSettings;
+#[cfg(feature = "gui")]
+use gui::Hotkey;
+#[cfg(feature = "gui")]
+use chipper8::chip8::ResetKind;
+#[cfg(feature = "gui")]
+use prompt::Prompt;
+#[cfg(feature = "gui")]
+use watch::Watcher;
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
+    let program = &args[0];
+
+    match args.get(1).map(String::as_str) {
+        Some("run") => run(program, &args[2..]),
+        Some("disasm") => std::process::exit(disasm(&args[2..])),
+        Some("asm") => std::process::exit(asm(&args[2..])),
+        Some("info") => std::process::exit(info(&args[2..])),
+        Some("diff") => std::process::exit(diff_traces(&args[2..])),
+        Some("help") | Some("--help") | Some("-h") => print_usage(program),
+        None => {
+            print_usage(program);
+            std::process::exit(1);
+        }
+        // Without a subcommand it's a ROM to run, the way it always worked.
+        Some(_) => run(program, &args[1..]),
+    }
+}
+
+fn print_usage(program: &str) {
+    eprintln!(
+        "Usage: {} [run] <rom_file> [--quirks {}] [--cycles-per-frame <n>] \
+         [--tone <hz>] [--volume <0-100>] [--mute] [--seed <n>] [--debug] [--break <addr>]... [--symbols <file>] [--rewind-seconds <n>] \
+         [--record <movie> | --play <movie>]",
+        program, quirks::PRESET_NAMES.join("|")
+    );
+    eprintln!(
+        "       [--watch] [--watch-source <file>] [--keep-registers] [--trace <file>] [--trace-format text|binary] [--trace-addr <start>[-<end>]]... \
+         [--trace-class {}] [--trace-frames <first>[-<last>]]",
+        instruction::CLASS_NAMES.join(",")
+    );
+    eprintln!(
+        "       {} [run] <rom_file> --headless [--frames <n> | --cycles <n>] \
+         [--keys <script or file>] [--dump <file>] [--dump-format ascii|pbm|png] [--compare <quirks>]",
+        program
+    );
+    eprintln!("       {} disasm <rom_file> [-o <file>] [--format text|octo|json] [--linear] [--symbols <file>]", program);
+    eprintln!("       {} asm <source_file> [-o <rom_file>] [-l <listing_file>] [-s <symbol_file>] [--octo]", program);
+    eprintln!("       {} info <rom_file>", program);
+    eprintln!("       {} diff <trace> <trace> [--context <n>]", program);
+}
+
+fn run(program: &str, args: &[String]) {
+    let options = match parse_args(args) {
+        Ok(options) => options,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            print_usage(program);
+            std::process::exit(1);
+        }
+    };
+
+    let program_bytes = match read_rom(&options.rom_path) {
+        Ok(bytes) => bytes,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            std::process::exit(1);
+        }
+    };
+
+    let symbols = match load_symbols(&options.rom_path, options.symbols_path.as_deref()) {
+        Ok(symbols) => symbols,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            std::process::exit(1);
+        }
+    };
+
+    // Breakpoints can be labels, so they wait for the symbols.
+    let breakpoints: Vec<u16> = match options.breakpoints.iter().map(|addr| prompt::parse_addr(addr, &symbols)).collect() {
+        Ok(breakpoints) => breakpoints,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            std::process::exit(1);
+        }
+    };
+
+    // A movie only replays right on the machine it was recorded on, so its settings
+    // win over the command line.
+    let (machine_quirks, cycles_per_frame, seed) = match &options.movie {
+        Some(movie) if !movie.matches_rom(&program_bytes) => {
+            eprintln!("The movie was recorded with a different ROM than {}", options.rom_path);
+            std::process::exit(1);
+        }
+        Some(movie) => (movie.quirks, movie.cycles_per_frame, Some(movie.seed)),
+        None => (options.quirks, options.cycles_per_frame, options.seed),
+    };
+
+    // Without a seed every run is different. Print the one we picked so a run that
+    // went wrong can be replayed with --seed.
+    let seed = seed.unwrap_or_else(|| {
+        let seed = rand::random();
+        eprintln!("RNG seed: {}", seed);
+        seed
+    });
+
+    let mut movie = match (&options.movie, &options.movie_path) {
+        (Some(movie), _) => Some(movie.clone()),
+        (None, Some(_)) => Some(Movie::new(&program_bytes, seed, machine_quirks, cycles_per_frame)),
+        (None, None) => None,
+    };
+
+    let build = |quirks| {
+        let mut cpu = match chip8::CHIP8::new(program_bytes.clone(), quirks) {
+            Ok(cpu) => cpu,
+            Err(err) => {
+                eprintln!("Can't load {}: {}", options.rom_path, err);
+                std::process::exit(1);
+            }
+        };
+        cpu.set_cycles_per_frame(cycles_per_frame);
+        cpu.set_seed(seed);
+        cpu
+    };
+
+    let mut cpu = build(machine_quirks);
+
+    // The second machine gets the same ROM, seed and input, only the quirks differ.
+    if let Some(headless_options) = &options.headless {
+        if let Some(other_quirks) = headless_options.compare {
+            if !headless::compare(cpu, build(other_quirks), headless_options, movie.as_ref()) {
+                std::process::exit(3);
+            }
+            return;
+        }
+    }
+
+    // --debug starts out paused at the first instruction, a breakpoint alone just
+    // waits for the program to get there. The symbol file can bring its own.
+    let mut dbg = Debugger::new();
+    dbg.set_symbols(symbols.clone());
+    for addr in breakpoints {
+        dbg.add_breakpoint(addr);
+    }
+    if options.debug {
+        dbg.pause();
+    }
+
+    let tracer = options.trace_path.as_ref().map(|path| {
+        let file = match File::create(path) {
+            Ok(file) => file,
+            Err(err) => {
+                eprintln!("Can't write {}: {}", path, err);
+                std::process::exit(1);
+            }
+        };
+
+        let format = options.trace_format.unwrap_or_else(|| TraceFormat::from_path(path));
+        let tracer = Tracer::new(Box::new(BufWriter::new(file)), format, options.trace_filter.clone());
+        tracer.set_symbols(symbols.clone());
+        tracer.attach(&mut cpu);
+        tracer
+    });
+
+    let rewind = Rewind::new(options.rewind_seconds as usize * chip8::FRAMES_PER_SECOND as usize);
+
+    let status = match &options.headless {
+        Some(headless_options) => {
+            // Without anything to stop at the run doesn't need the debugger.
+            let debugger = (options.debug || dbg.breakpoints().next().is_some()).then_some(dbg);
+
+            match headless::run(&mut cpu, headless_options, debugger, rewind, movie.as_mut()) {
+                Ok(true) => 0,
+                Ok(false) => 2,
+                Err(msg) => {
+                    eprintln!("{}", msg);
+                    1
+                }
+            }
+        }
+
+        None => {
+            run_gui(cpu, program_bytes, &options, dbg, rewind, movie.as_mut());
+            0
+        }
+    };
+
+    if let (Some(tracer), Some(path)) = (tracer, &options.trace_path) {
+        match tracer.finish() {
+            Ok(count) => eprintln!("Traced {} instructions to {}", count, path),
+            Err(err) => eprintln!("Can't write the trace to {}: {}", path, err),
+        }
+    }
+
+    // A movie that was played back without re-recording anything stays as it was.
+    if let (Some(movie), Some(path)) = (movie.filter(Movie::modified), &options.movie_path) {
+        match fs::write(path, movie.to_string()) {
+            Ok(()) => eprintln!("Saved {} frames of input to {}", movie.len(), path),
+            Err(err) => eprintln!("Can't save the movie to {}: {}", path, err),
+        }
+    }
+
+    if status != 0 {
+        std::process::exit(status);
+    }
+}
+
+#[cfg(not(feature = "gui"))]
+fn run_gui(_cpu: chip8::CHIP8, _rom: Vec<u8>, _options: &Options, _dbg: Debugger, _rewind: Rewind, _movie: Option<&mut Movie>) {
+    eprintln!("This build has no GUI support, run it with --headless.");
+    std::process::exit(1);
+}
+
+// The debugger hotkeys always work in the window. The terminal prompt is only read
+// with --debug, so a normal run leaves standard input alone. Every frame goes into the
+// rewind buffer, holding the rewind key plays them back in reverse. With a movie the
+// keypad goes through it, T switches between playing it back and recording over it.
+#[cfg(feature = "gui")]
+fn run_gui(mut cpu: chip8::CHIP8, mut rom: Vec<u8>, options: &Options, mut dbg: Debugger, mut rewind: Rewind, mut movie: Option<&mut Movie>) {
+    let mut win = gui::GUI::new(options.audio);
+    dbg.attach(&mut cpu);
+
+    let mut rom_path = options.rom_path.clone(); // Until another ROM is dropped on the window
+    let mut watcher = options.watch.then(|| {
+        Watcher::new(&options.rom_path, options.watch_source.as_deref(), options.symbols_path.as_deref())
+    });
+
+    let mut commands = if options.debug {
+        eprintln!("Debugger commands are read from the terminal, type 'help' for a list.");
+        Some(prompt::spawn_reader())
+    } else {
+        None
+    };
+    let mut prompt = Prompt::default();
+    let mut asked_open = false; // The next line typed is the path of a ROM to open
+    let mut typed_rom = None;
+    let mut was_paused = false;
+
+    let frame_time = Duration::from_secs(1) / chip8::FRAMES_PER_SECOND;
+    let mut next_frame = Instant::now();
+
+    while let Some(keypad) = win.get_keypad_state() {
+        for hotkey in win.take_hotkeys().into_iter().chain(typed_rom.take().map(Hotkey::Open)) {
+            match hotkey {
+                Hotkey::Pause if dbg.paused() => dbg.resume(),
+                Hotkey::Pause => dbg.pause(),
+                Hotkey::Step => dbg.step(),
+                Hotkey::StepOver => dbg.step_over(&cpu),
+
+                Hotkey::StepOut => {
+                    if !dbg.step_out(&cpu) {
+                        eprintln!("Not inside a subroutine");
+                    }
+                }
+
+                Hotkey::StepBack => {
+                    prompt::step_back(&mut dbg, &mut cpu, &mut rewind);
+                    show_machine(&mut win, &cpu);
+                }
+
+                Hotkey::ToggleBreakpoint => {
+                    let verb = if dbg.toggle_breakpoint(cpu.pc()) { "Set" } else { "Removed" };
+                    eprintln!("{} breakpoint at {}", verb, dbg.symbols().describe(cpu.pc()));
+                }
 
-    let program_bytes = get_program_bytes(args).expect("Problem reading file");
+                Hotkey::ToggleReadOnly => match movie.as_mut() {
+                    Some(movie) => {
+                        movie.set_read_only(!movie.read_only());
+                        if movie.read_only() {
+                            eprintln!("Movie is read-only, playing back");
+                        } else {
+                            eprintln!("Movie is read-write, recording from frame {}", cpu.frames());
+                        }
+                    }
+                    None => eprintln!("No movie, start with --record or --play"),
+                },
 
-    // let mut disass = Disassembler::new(&program_bytes);
+                Hotkey::Inspect => eprintln!("{}", chipper8::debugger::inspect(&cpu, dbg.symbols())),
 
-    // println!("{}", disass.disassemble());
-    
-    let mut cpu = chip8::CHIP8::new(program_bytes);
-    
-    let mut win = gui::GUI::new();
+                // The movie has no way to record a reset, it would play back out of sync.
+                Hotkey::Reset(_) if movie.is_some() => eprintln!("Can't reset while a movie runs"),
 
-    let delay = Duration::from_millis(2);
+                Hotkey::Reset(kind) => {
+                    // A hard reset clears memory, the ROM goes back in right after.
+                    cpu.reset(kind);
+                    if kind == ResetKind::Hard {
+                        cpu.load_program(&rom, chip8::PROGRAM_START as u16).expect("the ROM fit before the reset");
+                    }
 
-    loop {
-        match win.get_keypad_state() {
-            Some(keypad) => {
-                let redraw = cpu.tick(keypad);
+                    dbg.resync(&cpu);
+                    show_machine(&mut win, &cpu);
+                    eprintln!("{} reset", if kind == ResetKind::Hard { "Hard" } else { "Soft" });
+                }
+
+                // A movie is tied to its ROM, there's no switching in the middle of one.
+                Hotkey::AskOpen | Hotkey::Open(_) if movie.is_some() => {
+                    eprintln!("Can't open another ROM while a movie runs");
+                }
+
+                // The path is read from the terminal like the debugger commands, without
+                // --debug the reader starts now.
+                Hotkey::AskOpen => {
+                    eprint!("ROM to open (an empty line cancels): ");
+                    commands.get_or_insert_with(prompt::spawn_reader);
+                    asked_open = true;
+                }
+
+                Hotkey::Open(path) => match open_rom(&mut cpu, &path) {
+                    Ok((bytes, symbols)) => {
+                        eprintln!("Opened {}", path);
+                        dbg.set_symbols(symbols);
+                        dbg.resync(&cpu);
+                        rewind.clear();
+                        show_machine(&mut win, &cpu);
+
+                        // --watch follows along to the new ROM.
+                        if watcher.is_some() {
+                            watcher = Some(Watcher::new(&path, None, None));
+                        }
+                        rom_path = path;
+                        rom = bytes;
+                    }
+                    Err(msg) => eprintln!("{}", msg),
+                },
+
+                Hotkey::SaveState(slot) => save_state(&cpu, &rom_path, slot),
+
+                Hotkey::LoadState(slot) => {
+                    if load_state(&mut cpu, &rom_path, slot) {
+                        // The buffer would rewind into a different timeline.
+                        rewind.clear();
+                        dbg.resync(&cpu);
+                        show_machine(&mut win, &cpu);
+                    }
+                }
+            }
+        }
+
+        if let Some(reloaded) = watcher.as_mut().and_then(Watcher::poll) {
+            match reloaded.and_then(|program| {
+                cpu.reload(&program.bytes, options.keep_registers).map_err(|err| err.to_string())?;
+                Ok(program)
+            }) {
+                Ok(program) => {
+                    eprintln!("Reloaded {}", rom_path);
+                    rom = program.bytes;
+                    dbg.set_symbols(program.symbols);
+                    dbg.resync(&cpu);
+                    // The buffer would rewind into the old program.
+                    rewind.clear();
+                    show_machine(&mut win, &cpu);
+                }
+                Err(msg) => eprintln!("Can't reload {}: {}", rom_path, msg),
+            }
+        }
 
-                if redraw {
-                    win.draw(&cpu.screen);
+        for line in commands.iter().flat_map(|rx| rx.try_iter()) {
+            if asked_open {
+                asked_open = false;
+                typed_rom = Some(line.trim().to_string()).filter(|path| !path.is_empty());
+                continue;
+            }
+
+            // Without --debug the reader only runs for O's path.
+            if !options.debug {
+                continue;
+            }
+
+            if let Some(command) = prompt.interpret(&line, dbg.symbols()) {
+                if !prompt::execute(command, &mut dbg, &mut cpu, &mut rewind) {
+                    return;
+                }
+                show_machine(&mut win, &cpu);
+            }
+        }
+
+        // A halted machine just sits there with its diagnostic until the window is closed,
+        // or until it's rewound to before the error.
+        if win.rewinding() {
+            if rewind.pop(&mut cpu) {
+                dbg.resync(&cpu);
+                show_machine(&mut win, &cpu);
+            }
+            win.set_buzzer(false);
+        } else if cpu.halted().is_none() {
+            let keypad = match movie.as_mut() {
+                Some(movie) => movie.input(cpu.frames(), keypad),
+                None => keypad,
+            };
+            rewind.push(&cpu, keypad);
+
+            match dbg.run_frame(&mut cpu, keypad) {
+                Ok(redraw) => {
+                    if redraw {
+                        win.draw(cpu.screen());
+                    }
+
+                    win.set_audio_pattern(cpu.audio_pattern());
+                    win.set_buzzer(cpu.buzzer_active() && !dbg.paused());
+
+                    if dbg.paused() != was_paused {
+                        was_paused = dbg.paused();
+                        win.show_paused(was_paused);
+                    }
+                    prompt::report_stop(&mut dbg, &cpu);
+
+                    if cpu.exited() {
+                        break;
+                    }
+                }
+
+                Err(err) => {
+                    eprintln!("CHIP-8 halted: {}", err);
+                    win.show_error(cpu.screen(), &err);
                 }
             }
+        }
+
+        next_frame += frame_time;
+        let now = Instant::now();
+
+        if next_frame > now {
+            std::thread::sleep(next_frame - now);
+        } else if now - next_frame > frame_time * 5 {
+            // We fell far behind (window dragged, machine busy...). Don't try to catch up.
+            next_frame = now;
+        }
+    }
+}
+
+// Loads the ROM at path into the machine, with a hard reset first. Returns the ROM and
+// its symbols.
+#[cfg(feature = "gui")]
+fn open_rom(cpu: &mut chip8::CHIP8, path: &str) -> Result<(Vec<u8>, Symbols), String> {
+    let bytes = read_rom(path)?;
+    let symbols = load_symbols(path, None)?;
+    cpu.reload(&bytes, false).map_err(|err| format!("Can't load {}: {}", path, err))?;
+
+    Ok((bytes, symbols))
+}
+
+// Redraws the screen after the machine jumped to another state, along with its
+// diagnostic if it's halted there.
+#[cfg(feature = "gui")]
+fn show_machine(win: &mut gui::GUI, cpu: &chip8::CHIP8) {
+    match cpu.halted() {
+        Some(err) => win.show_error(cpu.screen(), &err),
+        None => {
+            win.clear_error();
+            win.draw(cpu.screen());
+        }
+    }
+}
+
+// Writes the listing of a ROM to standard output or to the -o file.
+fn disasm(args: &[String]) -> i32 {
+    let mut rom_path = None;
+    let mut out_path = None;
+    let mut format = None;
+    let mut mode = Mode::Flow;
+    let mut symbols_path = None;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let parsed = match arg.as_str() {
+            "-o" | "--output" => iter.next().map(|path| out_path = Some(path.clone())).ok_or("-o expects a file name"),
+            "--linear" => {
+                mode = Mode::Linear;
+                Ok(())
+            }
+            "--format" => match iter.next() {
+                Some(name) => Format::from_name(name)
+                    .map(|parsed| format = Some(parsed))
+                    .ok_or("Unknown format, expected text, octo or json"),
+                None => Err("--format expects text, octo or json"),
+            },
+            "--symbols" => {
+                iter.next().map(|path| symbols_path = Some(path.clone())).ok_or("--symbols expects a file name")
+            }
+            _ if rom_path.is_none() => {
+                rom_path = Some(arg.clone());
+                Ok(())
+            }
+            _ => Err("disasm takes a single ROM file"),
+        };
+
+        if let Err(msg) = parsed {
+            eprintln!("{}", msg);
+            return 1;
+        }
+    }
+
+    let rom_path = match rom_path {
+        Some(path) => path,
+        None => {
+            eprintln!("disasm expects a ROM file");
+            return 1;
+        }
+    };
+
+    let bytes = match read_rom(&rom_path) {
+        Ok(bytes) => bytes,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            return 1;
+        }
+    };
+
+    // Without --format the output file's extension decides.
+    let format = format.unwrap_or_else(|| match out_path.as_deref().and_then(|path| path.rsplit('.').next()) {
+        Some("8o") => Format::Octo,
+        Some("json") => Format::Json,
+        _ => Format::Text,
+    });
+
+    let symbols = match load_symbols(&rom_path, symbols_path.as_deref()) {
+        Ok(symbols) => symbols,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            return 1;
+        }
+    };
+
+    let mut disassembler = Disassembler::with_mode(&bytes, mode);
+    disassembler.set_symbols(symbols);
+    let listing = disassembler.disassemble_as(format) + "\n";
+
+    match out_path {
+        Some(path) => match fs::write(&path, listing) {
+            Ok(()) => 0,
+            Err(err) => {
+                eprintln!("Can't write {}: {}", path, err);
+                1
+            }
+        },
+        None => {
+            print!("{}", listing);
+            0
+        }
+    }
+}
+
+// Assembles a source file into a ROM, with a listing and the symbols next to it.
+fn asm(args: &[String]) -> i32 {
+    let mut source_path = None;
+    let mut out_path = None;
+    let mut listing_path = None;
+    let mut symbols_path = None;
+    let mut octo = false;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let parsed = match arg.as_str() {
+            "-o" | "--output" => iter.next().map(|path| out_path = Some(path.clone())).ok_or("-o expects a file name"),
+            "--octo" => {
+                octo = true;
+                Ok(())
+            }
+            "-l" | "--listing" => {
+                iter.next().map(|path| listing_path = Some(path.clone())).ok_or("-l expects a file name")
+            }
+            "-s" | "--symbols" => {
+                iter.next().map(|path| symbols_path = Some(path.clone())).ok_or("-s expects a file name")
+            }
+            _ if source_path.is_none() => {
+                source_path = Some(arg.clone());
+                Ok(())
+            }
+            _ => Err("asm takes a single source file"),
+        };
+
+        if let Err(msg) = parsed {
+            eprintln!("{}", msg);
+            return 1;
+        }
+    }
+
+    let source_path = match source_path {
+        Some(path) => path,
+        None => {
+            eprintln!("asm expects a source file");
+            return 1;
+        }
+    };
+
+    // game.asm becomes game.ch8, game.lst and game.sym, unless told otherwise.
+    let out_path = out_path.unwrap_or_else(|| Path::new(&source_path).with_extension("ch8").display().to_string());
+    let listing_path = listing_path.unwrap_or_else(|| Path::new(&out_path).with_extension("lst").display().to_string());
+    let symbols_path = symbols_path.unwrap_or_else(|| Path::new(&out_path).with_extension("sym").display().to_string());
+
+    match assemble_file(&source_path, &out_path, &listing_path, &symbols_path, octo) {
+        Ok(size) => {
+            println!("Assembled {} bytes into {}", size, out_path);
+            0
+        }
+        Err(msg) => {
+            eprintln!("{}", msg);
+            1
+        }
+    }
+}
+
+// Assembles the source and writes the ROM, listing and symbols. Returns the size of the ROM.
+fn assemble_file(source_path: &str, out_path: &str, listing_path: &str, symbols_path: &str, octo: bool) -> Result<usize, String> {
+    let source = fs::read_to_string(source_path).map_err(|err| format!("Can't read {}: {}", source_path, err))?;
+
+    // Octo sources are told apart by their extension.
+    let assembled = if octo || source_path.ends_with(".8o") {
+        assembler::octo::assemble(&source)
+    } else {
+        assembler::assemble(&source)
+    };
+
+    let assembly = assembled.map_err(|msg| format!("{}: {}", source_path, msg))?;
+
+    let outputs = [
+        (out_path, assembly.bytes.clone()),
+        (listing_path, assembly.listing().into_bytes()),
+        (symbols_path, assembly.symbols().to_string().into_bytes()),
+    ];
+
+    for (path, contents) in outputs {
+        fs::write(path, contents).map_err(|err| format!("Can't write {}: {}", path, err))?;
+    }
+
+    Ok(assembly.bytes.len())
+}
+
+// What the ROM is: its size, hash and which extensions its instructions need.
+fn info(args: &[String]) -> i32 {
+    let rom_path = match args {
+        [path] => path,
+        _ => {
+            eprintln!("info expects a single ROM file");
+            return 1;
+        }
+    };
 
-            None => break,
+    let bytes = match read_rom(rom_path) {
+        Ok(bytes) => bytes,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            return 1;
         }
+    };
+
+    let lines = Disassembler::new(&bytes).lines();
+    let instructions: Vec<Instruction> = lines.iter().filter_map(|line| line.instruction).collect();
+
+    let xo_chip = instructions.iter().any(|instruction| matches!(instruction,
+        Instruction::SaveRange(..) | Instruction::LoadRange(..) | Instruction::LdILong |
+        Instruction::Plane(_) | Instruction::Audio | Instruction::Pitch(_) | Instruction::Scu(_)));
+
+    let schip = instructions.iter().any(|instruction| matches!(instruction,
+        Instruction::Scd(_) | Instruction::Scr | Instruction::Scl | Instruction::Exit | Instruction::Low |
+        Instruction::High | Instruction::LdHf(_) | Instruction::LdRVx(_) | Instruction::LdVxR(_) |
+        Instruction::Drw(_, _, 0)));
 
-        std::thread::sleep(delay);
+    let (platform, preset) = match (xo_chip, schip) {
+        (true, _) => ("XO-CHIP", "xochip"),
+        (false, true) => ("SUPER-CHIP", "schip"),
+        (false, false) => ("CHIP-8", "modern"),
+    };
+
+    let room = Quirks::from_name(preset).unwrap_or_default().memory_size() - chip8::PROGRAM_START;
+
+    println!("File:         {}", rom_path);
+    println!("Size:         {} bytes ({} free for a {} program)", bytes.len(), room.saturating_sub(bytes.len()), platform);
+    println!("Hash:         {:016x}", movie::rom_hash(&bytes));
+    let data: usize = lines.iter().filter(|line| line.instruction.is_none()).map(|line| line.bytes.len()).sum();
+
+    println!("Instructions: {} (and {} bytes of data)", instructions.len(), data);
+    println!("Platform:     {} (run it with --quirks {})", platform, preset);
+    println!("Note: code only reached through a jump table (JP V0) isn't checked, so the platform is a guess.");
+
+    0
+}
+
+// Prints the first difference between two trace files. The exit status is 0 when
+// they agree, 3 when they don't and 1 on errors.
+fn diff_traces(args: &[String]) -> i32 {
+    let (paths, context) = match args {
+        [a, b] => ([a, b], diff::DEFAULT_CONTEXT),
+        [a, b, flag, n] if flag == "--context" => match n.parse() {
+            Ok(context) => ([a, b], context),
+            Err(_) => {
+                eprintln!("Invalid context length '{}'", n);
+                return 1;
+            }
+        },
+        _ => {
+            eprintln!("diff expects two trace files");
+            return 1;
+        }
+    };
+
+    let mut traces = Vec::new();
+    for path in paths {
+        let trace = fs::read(path)
+            .map_err(|err| err.to_string())
+            .and_then(|data| trace::read(&data));
+
+        match trace {
+            Ok(trace) => traces.push(trace),
+            Err(msg) => {
+                eprintln!("Can't read {}: {}", path, msg);
+                return 1;
+            }
+        }
+    }
+
+    match diff::compare_traces(&traces[0], &traces[1], context) {
+        Some(mismatch) => {
+            println!("{}", mismatch);
+            3
+        }
+        None => {
+            println!("The traces agree on all {} instructions", traces[0].len());
+            0
+        }
+    }
+}
+
+// Save states live next to the ROM, one file per slot: pong.ch8 -> pong.ch8.ss1
+#[cfg(feature = "gui")]
+fn state_path(rom_path: &str, slot: u8) -> String {
+    format!("{}.ss{}", rom_path, slot)
+}
+
+#[cfg(feature = "gui")]
+fn save_state(cpu: &chip8::CHIP8, rom_path: &str, slot: u8) {
+    let path = state_path(rom_path, slot);
+
+    match fs::write(&path, cpu.save_state()) {
+        Ok(()) => println!("Saved state to slot {} ({})", slot, path),
+        Err(err) => eprintln!("Can't save state to {}: {}", path, err),
+    }
+}
+
+#[cfg(feature = "gui")]
+fn load_state(cpu: &mut chip8::CHIP8, rom_path: &str, slot: u8) -> bool {
+    let path = state_path(rom_path, slot);
+
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("Can't read state from {}: {}", path, err);
+            return false;
+        }
+    };
+
+    match cpu.load_state(&data) {
+        Ok(()) => {
+            println!("Loaded state from slot {} ({})", slot, path);
+            true
+        }
+
+        Err(err) => {
+            eprintln!("Can't load state from {}: {}", path, err);
+            false
+        }
     }
 }
 
-fn get_program_bytes(args: Vec<String>) -> Result<Vec<u8>, String> {
-    if args.len() != 2 {
-        return Err(String::from("Invalid argument length"));
+struct Options {
+    rom_path: String,
+    quirks: Quirks,
+    cycles_per_frame: u32,
+    #[cfg(feature = "gui")]
+    audio: AudioSettings,
+    seed: Option<u64>,
+    debug: bool,
+    breakpoints: Vec<String>, // Addresses or labels, resolved once the symbols are loaded
+    symbols_path: Option<String>, // Defaults to the ROM's name with .sym
+    #[cfg(feature = "gui")]
+    watch: bool,
+    #[cfg(feature = "gui")]
+    watch_source: Option<String>, // Assembled into the ROM when it changes
+    #[cfg(feature = "gui")]
+    keep_registers: bool, // Whether a reload carries on or starts over
+    rewind_seconds: u32,
+    movie_path: Option<String>, // Where --record or --play saves the movie
+    movie: Option<Movie>,       // The one --play read
+    trace_path: Option<String>,
+    trace_format: Option<TraceFormat>, // Guessed from the file name when missing
+    trace_filter: TraceFilter,
+    headless: Option<HeadlessOptions>, // Set when running without a window
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut rom_path = None;
+    let mut quirks = Quirks::default();
+    let mut cycles_per_frame = chip8::DEFAULT_CYCLES_PER_FRAME;
+    #[cfg(feature = "gui")]
+    let mut audio = AudioSettings::default();
+    let mut seed = None;
+    let mut debug = false;
+    let mut breakpoints = Vec::new();
+    let mut symbols_path = None;
+    #[cfg(feature = "gui")]
+    let mut watch = false;
+    #[cfg(feature = "gui")]
+    let mut watch_source = None;
+    #[cfg(feature = "gui")]
+    let mut keep_registers = false;
+    let mut rewind_seconds = rewind::DEFAULT_SECONDS;
+    let mut movie_path = None;
+    let mut movie = None;
+    let mut trace_path = None;
+    let mut trace_format = None;
+    let mut trace_filter = TraceFilter::default();
+    let mut headless = !cfg!(feature = "gui");
+    let mut limit = None;
+    let mut keys = KeyScript::default();
+    let mut dump_path = None;
+    let mut format = None;
+    let mut compare = None;
+
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--quirks" => {
+                let name = iter.next().ok_or("--quirks expects a preset name")?;
+                quirks = Quirks::from_name(name)
+                    .ok_or_else(|| format!("Unknown quirks preset '{}'", name))?;
+            }
+
+            "--cycles-per-frame" => {
+                let value = iter.next().ok_or("--cycles-per-frame expects a number")?;
+                cycles_per_frame = value.parse()
+                    .map_err(|_| format!("Invalid cycles per frame '{}'", value))?;
+            }
+
+            #[cfg(feature = "gui")]
+            "--tone" => {
+                let value = iter.next().ok_or("--tone expects a frequency in Hz")?;
+                audio.frequency = value.parse::<f32>().ok()
+                    .filter(|hz| *hz > 0.0)
+                    .ok_or_else(|| format!("Invalid tone frequency '{}'", value))?;
+            }
+
+            #[cfg(feature = "gui")]
+            "--volume" => {
+                let value = iter.next().ok_or("--volume expects a percentage")?;
+                let percent = value.parse::<f32>().ok()
+                    .filter(|percent| (0.0..=100.0).contains(percent))
+                    .ok_or_else(|| format!("Invalid volume '{}'", value))?;
+                audio.volume = percent / 100.0;
+            }
+
+            #[cfg(feature = "gui")]
+            "--mute" => audio.muted = true,
+
+            "--seed" => {
+                let value = iter.next().ok_or("--seed expects a number")?;
+                seed = Some(value.parse().map_err(|_| format!("Invalid seed '{}'", value))?);
+            }
+
+            "--debug" => debug = true,
+
+            "--break" => {
+                breakpoints.push(iter.next().ok_or("--break expects an address")?.clone());
+            }
+
+            "--symbols" => {
+                symbols_path = Some(iter.next().ok_or("--symbols expects a file name")?.clone());
+            }
+
+            #[cfg(feature = "gui")]
+            "--watch" => watch = true,
+
+            #[cfg(feature = "gui")]
+            "--watch-source" => {
+                watch_source = Some(iter.next().ok_or("--watch-source expects a file name")?.clone());
+                watch = true;
+            }
+
+            #[cfg(feature = "gui")]
+            "--keep-registers" => keep_registers = true,
+
+            "--rewind-seconds" => {
+                let value = iter.next().ok_or("--rewind-seconds expects a number")?;
+                rewind_seconds = value.parse().map_err(|_| format!("Invalid rewind length '{}'", value))?;
+            }
+
+            "--record" => {
+                movie_path = Some(iter.next().ok_or("--record expects a file name")?.clone());
+                movie = None;
+            }
+
+            "--play" => {
+                let path = iter.next().ok_or("--play expects a movie file")?;
+                let text = fs::read_to_string(path).map_err(|err| format!("Can't read {}: {}", path, err))?;
+                movie = Some(Movie::parse(&text).map_err(|msg| format!("{}: {}", path, msg))?);
+                movie_path = Some(path.clone());
+            }
+
+            "--trace" => {
+                trace_path = Some(iter.next().ok_or("--trace expects a file name")?.clone());
+            }
+
+            "--trace-format" => {
+                let name = iter.next().ok_or("--trace-format expects text or binary")?;
+                trace_format = Some(TraceFormat::from_name(name)
+                    .ok_or_else(|| format!("Unknown trace format '{}'", name))?);
+            }
+
+            "--trace-addr" => {
+                let value = iter.next().ok_or("--trace-addr expects an address or a range")?;
+                let (start, end) = match value.split_once('-') {
+                    Some((start, end)) => (parse_hex(start)?, parse_hex(end)?),
+                    None => (parse_hex(value)?, parse_hex(value)?),
+                };
+                trace_filter.ranges.push((start, end));
+            }
+
+            "--trace-class" => {
+                let value = iter.next().ok_or("--trace-class expects instruction classes")?;
+                for name in value.split(',') {
+                    trace_filter.classes.push(Class::from_name(name)
+                        .ok_or_else(|| format!("Unknown instruction class '{}'", name))?);
+                }
+            }
+
+            "--trace-frames" => {
+                let value = iter.next().ok_or("--trace-frames expects a frame or a range")?;
+                let frame = |text: &str| text.parse::<u64>().map_err(|_| format!("Invalid frame range '{}'", value));
+                trace_filter.frames = Some(match value.split_once('-') {
+                    Some((first, last)) => (frame(first)?, frame(last)?),
+                    None => (frame(value)?, frame(value)?),
+                });
+            }
+
+            "--compare" => {
+                let name = iter.next().ok_or("--compare expects a preset name")?;
+                compare = Some(Quirks::from_name(name)
+                    .ok_or_else(|| format!("Unknown quirks preset '{}'", name))?);
+            }
+
+            "--headless" => headless = true,
+
+            "--frames" => {
+                let value = iter.next().ok_or("--frames expects a number")?;
+                limit = Some(Limit::Frames(value.parse().map_err(|_| format!("Invalid frame count '{}'", value))?));
+            }
+
+            "--cycles" => {
+                let value = iter.next().ok_or("--cycles expects a number")?;
+                limit = Some(Limit::Cycles(value.parse().map_err(|_| format!("Invalid cycle count '{}'", value))?));
+            }
+
+            // Either a file holding the script or the script itself.
+            "--keys" => {
+                let value = iter.next().ok_or("--keys expects a key script")?;
+                let script = match fs::read_to_string(value) {
+                    Ok(contents) => contents,
+                    Err(_) => value.clone(),
+                };
+                keys = KeyScript::parse(&script)?;
+            }
+
+            "--dump" => {
+                dump_path = Some(iter.next().ok_or("--dump expects a file name")?.clone());
+            }
+
+            "--dump-format" => {
+                let name = iter.next().ok_or("--dump-format expects ascii, pbm or png")?;
+                format = Some(DumpFormat::from_name(name)
+                    .ok_or_else(|| format!("Unknown dump format '{}'", name))?);
+            }
+
+            _ if rom_path.is_none() => rom_path = Some(arg.clone()),
+
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        }
     }
 
-    let mut file = File::open(&args[1]).expect("File not found.");
-    let meta = fs::metadata(&args[1]).expect("Can't read file metadata");
+    let headless = if headless {
+        Some(HeadlessOptions {
+            // A movie plays to its end unless told otherwise.
+            limit: limit.unwrap_or_else(|| {
+                Limit::Frames(movie.as_ref().map_or(headless::DEFAULT_FRAMES, |movie| movie.len() as u64))
+            }),
+            keys,
+            dump_path,
+            format,
+            compare,
+        })
+    } else if limit.is_some() || dump_path.is_some() || format.is_some() || compare.is_some() {
+        return Err(String::from("--frames, --cycles, --keys, --dump and --compare only work with --headless"));
+    } else {
+        None
+    };
 
-    let mut bytes = vec![0; meta.len() as usize];
+    #[cfg(feature = "gui")]
+    if watch && (headless.is_some() || movie_path.is_some()) {
+        return Err(String::from("--watch only works in the window, without a movie"));
+    } else if keep_registers && !watch {
+        return Err(String::from("--keep-registers only works with --watch"));
+    }
 
-    match file.read(&mut bytes) {
-        Err(msg) => return Err(msg.to_string()),
-        _ => ()
+    match rom_path {
+        Some(rom_path) => Ok(Options {
+            rom_path,
+            quirks,
+            cycles_per_frame,
+            #[cfg(feature = "gui")]
+            audio,
+            seed,
+            debug,
+            breakpoints,
+            symbols_path,
+            #[cfg(feature = "gui")]
+            watch,
+            #[cfg(feature = "gui")]
+            watch_source,
+            #[cfg(feature = "gui")]
+            keep_registers,
+            rewind_seconds,
+            movie_path,
+            movie,
+            trace_path,
+            trace_format,
+            trace_filter,
+            headless,
+        }),
+        None => Err(String::from("No ROM file given")),
     }
+}
 
-    //println!("{:x?}", &bytes[..]);
+fn read_rom(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|err| format!("Can't read {}: {}", path, err))
+}
 
-    Ok(bytes)
-}
\ No newline at end of file
+fn parse_hex(text: &str) -> Result<u16, String> {
+    prompt::parse_addr(text, &Symbols::new())
+}
+
+// The file given with --symbols, or the one the assembler left next to the ROM. A ROM
+// without one just has no names.
+fn load_symbols(rom_path: &str, path: Option<&str>) -> Result<Symbols, String> {
+    let default_path = Path::new(rom_path).with_extension("sym");
+    let path = match path {
+        Some(path) => Path::new(path),
+        None if default_path.exists() => default_path.as_path(),
+        None => return Ok(Symbols::new()),
+    };
+
+    let text = fs::read_to_string(path).map_err(|err| format!("Can't read {}: {}", path.display(), err))?;
+    Symbols::parse(&text).map_err(|msg| format!("{}: {}", path.display(), msg))
+}
@@ -0,0 +1,328 @@
+//! Input movies: the keypad state of every frame, along with everything else a run
+//! depends on (the ROM, the RNG seed, the quirks and the speed). Playing one back
+//! repeats the recorded run exactly.
+//!
+//! Movies are plain text so they can be attached to bug reports, diffed and edited
+//! by hand:
+//!
+//! ```text
+//! chipper8-movie 2
+//! rom 5a1e2bd1c0a7f4e3
+//! seed 1234
+//! quirks 0x0301
+//! cycles-per-frame 15
+//! ................
+//! ....4...........
+//! ....4.......c...
+//! ```
+//!
+//! After the header comes one line per frame, where column `k` holds the hex digit
+//! `k` while that key is held down and a `.` otherwise. Lines starting with `#` are
+//! comments.
+
+use crate::quirks::Quirks;
+
+use std::fmt;
+
+const HEADER: &str = "chipper8-movie";
+const VERSION: u32 = 2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_hash: u64,
+    pub seed: u64,
+    pub quirks: Quirks,
+    pub cycles_per_frame: u32,
+    frames: Vec<[bool; 16]>,
+    read_only: bool,
+    modified: bool,
+    latched: Option<u64>, // The frame the last input was handed out for
+}
+
+impl Movie {
+    /// An empty movie for recording a run of `rom`.
+    pub fn new(rom: &[u8], seed: u64, quirks: Quirks, cycles_per_frame: u32) -> Self {
+        Self {
+            rom_hash: rom_hash(rom),
+            seed,
+            quirks,
+            cycles_per_frame,
+            frames: Vec::new(),
+            read_only: false,
+            modified: false,
+            latched: None,
+        }
+    }
+
+    /// Reads a movie written by to_string. It starts out read-only.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines()
+            .enumerate()
+            .map(|(idx, line)| (idx + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+
+        match lines.next() {
+            Some((_, line)) if line == format!("{} {}", HEADER, VERSION) => (),
+            Some((_, line)) if line.starts_with(HEADER) => {
+                return Err(format!("Unsupported movie version '{}' (expected {})", line, VERSION));
+            }
+            _ => return Err(String::from("Not a chipper8 movie")),
+        }
+
+        let (mut rom_hash, mut seed, mut quirks, mut cycles_per_frame) = (None, None, None, None);
+        let mut frames = Vec::new();
+
+        for (num, line) in lines {
+            match line.split_once(' ') {
+                Some((key, value)) if frames.is_empty() => {
+                    let value = value.trim();
+                    let invalid = || format!("Invalid {} '{}' on line {}", key, value, num);
+
+                    match key {
+                        "rom" => rom_hash = Some(u64::from_str_radix(value, 16).map_err(|_| invalid())?),
+                        "seed" => seed = Some(value.parse().map_err(|_| invalid())?),
+                        "quirks" => {
+                            let bits = value.trim_start_matches("0x");
+                            quirks = Some(Quirks::from_bits(u16::from_str_radix(bits, 16).map_err(|_| invalid())?));
+                        }
+                        "cycles-per-frame" => cycles_per_frame = Some(value.parse().map_err(|_| invalid())?),
+                        _ => return Err(format!("Unknown movie setting '{}' on line {}", key, num)),
+                    }
+                }
+
+                _ => frames.push(parse_keypad(line).ok_or_else(|| format!("Invalid keys '{}' on line {}", line, num))?),
+            }
+        }
+
+        let missing = |name| format!("The movie has no {} line", name);
+
+        Ok(Self {
+            rom_hash: rom_hash.ok_or_else(|| missing("rom"))?,
+            seed: seed.ok_or_else(|| missing("seed"))?,
+            quirks: quirks.ok_or_else(|| missing("quirks"))?,
+            cycles_per_frame: cycles_per_frame.ok_or_else(|| missing("cycles-per-frame"))?,
+            frames,
+            read_only: true,
+            modified: false,
+            latched: None,
+        })
+    }
+
+    /// Frames recorded so far.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Whether the movie was recorded from this ROM.
+    pub fn matches_rom(&self, rom: &[u8]) -> bool {
+        self.rom_hash == rom_hash(rom)
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// A read-only movie feeds its input to the machine. Switching to read-write in
+    /// the middle of one keeps what came before and records over the rest.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+        self.latched = None;
+    }
+
+    /// Whether anything was recorded since the movie was created or read.
+    pub fn modified(&self) -> bool {
+        self.modified
+    }
+
+    /// Whether a read-only movie has run out of input by this frame.
+    pub fn finished(&self, frame: u64) -> bool {
+        self.read_only && frame >= self.frames.len() as u64
+    }
+
+    /// What was recorded for `frame`, if the movie goes that far.
+    pub fn keypad(&self, frame: u64) -> Option<[bool; 16]> {
+        self.frames.get(frame as usize).copied()
+    }
+
+    /// The keypad the machine should see during `frame`, given what's actually held
+    /// down. A read-only movie replaces it with the recorded input, until it runs out.
+    /// Otherwise `keypad` is recorded for that frame and everything recorded after it
+    /// is dropped.
+    ///
+    /// Asking again during the same frame hands out the same input, so a frame that
+    /// gets split up (by the debugger, for one) still sees a single keypad state.
+    pub fn input(&mut self, frame: u64, keypad: [bool; 16]) -> [bool; 16] {
+        if self.latched == Some(frame) {
+            if let Some(&recorded) = self.frames.get(frame as usize) {
+                return recorded;
+            }
+        }
+        self.latched = Some(frame);
+
+        if self.read_only {
+            return self.frames.get(frame as usize).copied().unwrap_or(keypad);
+        }
+
+        let frame = frame as usize;
+        self.frames.truncate(frame);
+        self.frames.resize(frame, [false; 16]);
+        self.frames.push(keypad);
+        self.modified = true;
+
+        keypad
+    }
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} {}", HEADER, VERSION)?;
+        writeln!(f, "rom {:016x}", self.rom_hash)?;
+        writeln!(f, "seed {}", self.seed)?;
+        writeln!(f, "quirks {:#06x}", self.quirks.to_bits())?;
+        writeln!(f, "cycles-per-frame {}", self.cycles_per_frame)?;
+
+        for keypad in &self.frames {
+            let line: String = keypad.iter()
+                .enumerate()
+                .map(|(key, &down)| if down { std::char::from_digit(key as u32, 16).unwrap() } else { '.' })
+                .collect();
+            writeln!(f, "{}", line)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// 64 bit FNV-1a of the ROM, enough to tell ROMs apart without pulling in a
+/// cryptographic hash.
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    rom.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3)
+    })
+}
+
+fn parse_keypad(line: &str) -> Option<[bool; 16]> {
+    if line.chars().count() != 16 {
+        return None;
+    }
+
+    let mut keypad = [false; 16];
+
+    for (key, c) in line.chars().enumerate() {
+        match c {
+            '.' => (),
+            _ if c.to_digit(16) == Some(key as u32) => keypad[key] = true,
+            _ => return None,
+        }
+    }
+
+    Some(keypad)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(down: &[usize]) -> [bool; 16] {
+        let mut keypad = [false; 16];
+        for &key in down {
+            keypad[key] = true;
+        }
+        keypad
+    }
+
+    fn recorded() -> Movie {
+        let mut movie = Movie::new(&[0x12, 0x00], 1234, Quirks::xochip(), 15);
+        for (frame, down) in [&[][..], &[4], &[4, 0xC], &[0, 0xF], &[]].iter().enumerate() {
+            movie.input(frame as u64, keys(down));
+        }
+        movie
+    }
+
+    #[test]
+    fn round_trips_through_text() {
+        let movie = recorded();
+        let parsed = Movie::parse(&movie.to_string()).unwrap();
+
+        assert_eq!(parsed.rom_hash, movie.rom_hash);
+        assert_eq!(parsed.seed, 1234);
+        assert_eq!(parsed.quirks, Quirks::xochip());
+        assert_eq!(parsed.cycles_per_frame, 15);
+        assert_eq!(parsed.len(), 5);
+        for frame in 0..5 {
+            assert_eq!(parsed.keypad(frame), movie.keypad(frame));
+        }
+        assert_eq!(parsed.to_string(), movie.to_string());
+    }
+
+    #[test]
+    fn parses_the_documented_format() {
+        let text = "\
+chipper8-movie 2
+# recorded by hand
+rom 5a1e2bd1c0a7f4e3
+seed 1234
+quirks 0x0301
+cycles-per-frame 15
+
+................
+....4...........
+....4.......c...
+";
+        let movie = Movie::parse(text).unwrap();
+        assert_eq!(movie.rom_hash, 0x5a1e_2bd1_c0a7_f4e3);
+        assert_eq!(movie.quirks, Quirks::modern());
+        assert_eq!(movie.keypad(2), Some(keys(&[4, 0xC])));
+        assert_eq!(movie.keypad(3), None);
+        assert!(movie.read_only());
+    }
+
+    #[test]
+    fn rejects_bad_movies() {
+        let movie = recorded().to_string();
+
+        assert!(Movie::parse("hello").unwrap_err().starts_with("Not a chipper8 movie"));
+        assert!(Movie::parse(&movie.replace("movie 2", "movie 1")).unwrap_err().starts_with("Unsupported movie version"));
+        assert_eq!(Movie::parse(&movie.replace("seed 1234\n", "")).unwrap_err(), "The movie has no seed line");
+        assert_eq!(Movie::parse(&movie.replace("seed 1234", "seed x")).unwrap_err(), "Invalid seed 'x' on line 3");
+        assert_eq!(Movie::parse(&(movie + "....5...........\n")).unwrap_err(),
+                   "Invalid keys '....5...........' on line 11");
+    }
+
+    #[test]
+    fn read_only_plays_back() {
+        let mut movie = Movie::parse(&recorded().to_string()).unwrap();
+
+        assert_eq!(movie.input(1, keys(&[9])), keys(&[4]));
+        assert!(!movie.finished(4));
+        assert!(movie.finished(5));
+        // Past the end the real keypad goes through.
+        assert_eq!(movie.input(5, keys(&[9])), keys(&[9]));
+        assert!(!movie.modified());
+    }
+
+    #[test]
+    fn recording_overwrites_the_rest() {
+        let mut movie = Movie::parse(&recorded().to_string()).unwrap();
+        movie.set_read_only(false);
+
+        assert_eq!(movie.input(2, keys(&[7])), keys(&[7]));
+        assert_eq!(movie.len(), 3);
+        assert_eq!(movie.keypad(1), Some(keys(&[4])));
+        assert!(movie.modified());
+
+        // The same frame asked for again keeps what was recorded first.
+        assert_eq!(movie.input(2, keys(&[8])), keys(&[7]));
+    }
+
+    #[test]
+    fn hashes_tell_roms_apart() {
+        let movie = Movie::new(&[0x12, 0x00], 0, Quirks::default(), 10);
+        assert!(movie.matches_rom(&[0x12, 0x00]));
+        assert!(!movie.matches_rom(&[0x12, 0x02]));
+    }
+}
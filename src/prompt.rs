@@ -0,0 +1,270 @@
+// Debugger commands typed at the terminal. In a --headless run the prompt blocks
+// whenever the debugger is paused, next to the window the lines are read on their
+// own thread and picked up between frames.
+use chipper8::chip8::AccessKind;
+use chipper8::condition::{Condition, Register};
+use chipper8::debugger::{self, Watch};
+use chipper8::rewind::Rewind;
+use chipper8::{Debugger, Symbols, CHIP8};
+
+use std::io::{self, BufRead, Write};
+#[cfg(feature = "gui")]
+use std::sync::mpsc::{self, Receiver};
+
+pub const HELP: &str = "\
+c, continue         run until a breakpoint or until paused
+p, pause            stop before the next instruction
+s, step             run one instruction
+n, next             step over CALL
+f, finish           run until the current subroutine returns
+rs, reverse-step    undo the last instruction
+b, break <addr>     set a breakpoint
+b <addr> if <cond>  set a breakpoint that only stops when the condition holds
+b if <cond>         stop as soon as the condition becomes true
+d, delete <addr>    remove a breakpoint
+watch <addr>[-end]  stop after a write to memory
+rwatch, awatch      the same, for reads and for any access
+watch <reg>         stop after a register (V0-VF, I, DT, ST, SP) changes
+unwatch <n>         remove a watch or a 'b if'
+bl, breakpoints     list the breakpoints and watches
+r, regs             show registers, timers and the stack
+x <addr> [len]      dump memory (len defaults to 64 bytes)
+q, quit             stop the emulator
+An empty line repeats the last command. Addresses are hex, or a label from the
+symbol file like 'draw' or 'draw+4'.
+Conditions look like 'V3 == 0x10 && I > 0x300', numbers in them are decimal unless
+they start with 0x. [addr] is the byte at addr.";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Continue,
+    Pause,
+    Step,
+    Next,
+    Finish,
+    StepBack,
+    Break(u16, Option<Condition>),
+    Delete(u16),
+    Watch(Watch),
+    Unwatch(usize),
+    Breakpoints,
+    Regs,
+    Memory(u16, usize),
+    Help,
+    Quit,
+}
+
+impl Command {
+    pub fn parse(line: &str, symbols: &Symbols) -> Result<Self, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        // Conditions are free-form, everything after the 'if' belongs to them.
+        if let Some(idx) = words.iter().position(|&word| word == "if") {
+            let condition = Condition::parse(&words[idx + 1..].join(" "))?;
+
+            return match words[..idx] {
+                ["b"] | ["break"] => Ok(Command::Watch(Watch::Condition(condition))),
+                ["b", addr] | ["break", addr] => Ok(Command::Break(parse_addr(addr, symbols)?, Some(condition))),
+                _ => Err(String::from("Only breakpoints take a condition")),
+            };
+        }
+
+        let command = match words.as_slice() {
+            ["c"] | ["continue"] => Command::Continue,
+            ["p"] | ["pause"] => Command::Pause,
+            ["s"] | ["step"] => Command::Step,
+            ["n"] | ["next"] => Command::Next,
+            ["f"] | ["finish"] => Command::Finish,
+            ["rs"] | ["reverse-step"] => Command::StepBack,
+            ["b", addr] | ["break", addr] => Command::Break(parse_addr(addr, symbols)?, None),
+            ["d", addr] | ["delete", addr] => Command::Delete(parse_addr(addr, symbols)?),
+            ["watch", what] => match Register::from_name(what) {
+                Some(reg) => Command::Watch(Watch::Register(reg)),
+                None => Command::Watch(parse_range(what, Some(AccessKind::Write), symbols)?),
+            },
+            ["rwatch", range] => Command::Watch(parse_range(range, Some(AccessKind::Read), symbols)?),
+            ["awatch", range] => Command::Watch(parse_range(range, None, symbols)?),
+            ["unwatch", id] => Command::Unwatch(id.parse().map_err(|_| format!("Invalid watch number '{}'", id))?),
+            ["bl"] | ["breakpoints"] => Command::Breakpoints,
+            ["r"] | ["regs"] => Command::Regs,
+            ["x", addr] => Command::Memory(parse_addr(addr, symbols)?, 64),
+            ["x", addr, len] => {
+                let len = len.parse().map_err(|_| format!("Invalid length '{}'", len))?;
+                Command::Memory(parse_addr(addr, symbols)?, len)
+            }
+            ["h"] | ["help"] | ["?"] => Command::Help,
+            ["q"] | ["quit"] => Command::Quit,
+            _ => return Err(format!("Unknown command '{}', try 'help'", line.trim())),
+        };
+
+        Ok(command)
+    }
+}
+
+// Hex, with or without the 0x, or a label.
+pub fn parse_addr(text: &str, symbols: &Symbols) -> Result<u16, String> {
+    if let Some(addr) = symbols.resolve(text) {
+        return Ok(addr);
+    }
+
+    let digits = text.trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid address '{}'", text))
+}
+
+// <addr> or <start>-<end>
+fn parse_range(text: &str, kind: Option<AccessKind>, symbols: &Symbols) -> Result<Watch, String> {
+    let (start, end) = match text.split_once('-') {
+        Some((start, end)) => (parse_addr(start, symbols)?, parse_addr(end, symbols)?),
+        None => (parse_addr(text, symbols)?, parse_addr(text, symbols)?),
+    };
+
+    if start > end {
+        return Err(format!("Range '{}' ends before it starts", text));
+    }
+
+    Ok(Watch::Memory { start, end, kind })
+}
+
+// Runs a command against the debugger. Returns false when asked to quit.
+pub fn execute(command: Command, dbg: &mut Debugger, cpu: &mut CHIP8, rewind: &mut Rewind) -> bool {
+    match command {
+        Command::Continue => dbg.resume(),
+        Command::Pause => dbg.pause(),
+        Command::Step => dbg.step(),
+        Command::Next => dbg.step_over(cpu),
+
+        Command::Finish => {
+            if !dbg.step_out(cpu) {
+                eprintln!("Not inside a subroutine");
+            }
+        }
+
+        Command::StepBack => step_back(dbg, cpu, rewind),
+
+        Command::Break(addr, None) => {
+            dbg.add_breakpoint(addr);
+            eprintln!("Breakpoint at {}", dbg.symbols().describe(addr));
+        }
+
+        Command::Break(addr, Some(condition)) => {
+            eprintln!("Breakpoint at {} if {}", dbg.symbols().describe(addr), condition);
+            dbg.add_conditional_breakpoint(addr, condition);
+        }
+
+        Command::Delete(addr) => {
+            if !dbg.remove_breakpoint(addr) {
+                eprintln!("No breakpoint at {}", dbg.symbols().describe(addr));
+            }
+        }
+
+        Command::Watch(watch) => {
+            let text = watch.to_string();
+            let id = dbg.add_watch(watch, cpu);
+            eprintln!("Watch {}: {}", id, text);
+        }
+
+        Command::Unwatch(id) => {
+            if !dbg.remove_watch(id) {
+                eprintln!("No watch {}", id);
+            }
+        }
+
+        Command::Breakpoints => {
+            for (addr, condition) in dbg.breakpoints() {
+                let addr = dbg.symbols().describe(addr);
+
+                match condition {
+                    Some(condition) => eprintln!("{} if {}", addr, condition),
+                    None => eprintln!("{}", addr),
+                }
+            }
+
+            for (id, watch) in dbg.watches() {
+                eprintln!("Watch {}: {}", id, watch);
+            }
+        }
+
+        Command::Regs => eprintln!("{}", debugger::inspect(cpu, dbg.symbols())),
+        Command::Memory(addr, len) => eprintln!("{}", debugger::hex_dump(cpu, addr, len)),
+        Command::Help => eprintln!("{}", HELP),
+        Command::Quit => return false,
+    }
+
+    true
+}
+
+// Takes the machine back one instruction and pauses there.
+pub fn step_back(dbg: &mut Debugger, cpu: &mut CHIP8, rewind: &mut Rewind) {
+    if rewind.step_back(cpu) {
+        dbg.pause();
+        dbg.take_stop(); // The registers below say where it stopped
+        dbg.resync(cpu);
+        eprintln!("{}", debugger::inspect(cpu, dbg.symbols()));
+    } else {
+        eprintln!("Can't go back any further");
+    }
+}
+
+// Tells the user why the machine stopped, if it just did.
+pub fn report_stop(dbg: &mut Debugger, cpu: &CHIP8) {
+    if let Some(stop) = dbg.take_stop() {
+        eprintln!("-- {} --", stop.describe(dbg.symbols()));
+        eprintln!("{}", debugger::inspect(cpu, dbg.symbols()));
+    }
+}
+
+// Remembers the last command so an empty line can repeat it.
+#[derive(Default)]
+pub struct Prompt {
+    last: Option<Command>,
+}
+
+impl Prompt {
+    // Turns a line typed by the user into a command. Errors are reported right away.
+    pub fn interpret(&mut self, line: &str, symbols: &Symbols) -> Option<Command> {
+        if line.trim().is_empty() {
+            return self.last.clone();
+        }
+
+        match Command::parse(line, symbols) {
+            Ok(command) => {
+                self.last = Some(command.clone());
+                Some(command)
+            }
+
+            Err(msg) => {
+                eprintln!("{}", msg);
+                None
+            }
+        }
+    }
+}
+
+// Blocks until the user types a line. None once standard input is closed.
+pub fn read_line() -> Option<String> {
+    eprint!("(chipper8) ");
+    io::stderr().flush().ok();
+
+    let mut line = String::new();
+    match io::stdin().lock().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line),
+    }
+}
+
+// Reads standard input on a separate thread, so the window keeps running while
+// nobody is typing.
+#[cfg(feature = "gui")]
+pub fn spawn_reader() -> Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    rx
+}
@@ -0,0 +1,172 @@
+/// The original COSMAC VIP interpreter and the ones that came after it (CHIP-48,
+/// SUPER-CHIP, ...) disagree on how a handful of instructions behave. Each flag
+/// picks one interpretation so a ROM can be run the way its platform expected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    /// 8xy6/8xyE shift Vx in place instead of shifting Vy into Vx
+    pub shift: bool,
+    /// How far Fx55/Fx65 move I
+    pub load_store: LoadStore,
+    /// Bnnn acts as Bxnn and jumps to xnn + Vx instead of nnn + V0
+    pub jump: bool,
+    /// 8xy1/8xy2/8xy3 reset VF to 0
+    pub vf_reset: bool,
+    /// Dxyn clips sprites at the screen edges instead of wrapping them
+    pub clipping: bool,
+    /// Dxyn ends the frame early, as if waiting for the vertical blank
+    pub display_wait: bool,
+    /// Fx1E sets VF when I goes past 0x0F00, like the Amiga interpreter Spacefight 2091! needs
+    pub index_overflow: bool,
+    /// SUPER-CHIP extensions: 128x64 high resolution, scrolling, the big font, RPL flags, 00FD
+    pub super_chip: bool,
+    /// XO-CHIP extensions: 64 KiB of memory, bitplanes, audio patterns, F000 nnnn
+    pub xo_chip: bool,
+}
+
+/// What Fx55/Fx65 do to I after storing or loading V0 - Vx.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadStore {
+    Unchanged, // I stays where it was
+    PastLast,  // I points past the last register (I += x + 1), like the COSMAC VIP
+    Last,      // I points at the last register (I += x), like CHIP-48
+}
+
+pub const PRESET_NAMES: [&str; 5] = ["modern", "vip", "chip48", "schip", "xochip"];
+
+impl Quirks {
+    /// What most modern interpreters (and Cowgod's reference) do.
+    pub fn modern() -> Self {
+        Self {
+            shift: true,
+            load_store: LoadStore::Unchanged,
+            jump: false,
+            vf_reset: false,
+            clipping: false,
+            display_wait: false,
+            index_overflow: true,
+            super_chip: true,
+            xo_chip: false,
+        }
+    }
+
+    /// The original COSMAC VIP interpreter.
+    pub fn vip() -> Self {
+        Self {
+            shift: false,
+            load_store: LoadStore::PastLast,
+            jump: false,
+            vf_reset: true,
+            clipping: true,
+            display_wait: true,
+            index_overflow: false,
+            super_chip: false,
+            xo_chip: false,
+        }
+    }
+
+    /// CHIP-48 on the HP-48 calculators. Its Fx55/Fx65 leave I at the last register
+    /// instead of past it, SUPER-CHIP later stopped moving I at all.
+    pub fn chip48() -> Self {
+        Self {
+            shift: true,
+            load_store: LoadStore::Last,
+            jump: true,
+            vf_reset: false,
+            clipping: true,
+            display_wait: false,
+            index_overflow: false,
+            super_chip: false,
+            xo_chip: false,
+        }
+    }
+
+    /// SUPER-CHIP 1.1.
+    pub fn schip() -> Self {
+        Self {
+            shift: true,
+            load_store: LoadStore::Unchanged,
+            jump: true,
+            vf_reset: false,
+            clipping: true,
+            display_wait: false,
+            index_overflow: false,
+            super_chip: true,
+            xo_chip: false,
+        }
+    }
+
+    /// XO-CHIP, as implemented by Octo.
+    pub fn xochip() -> Self {
+        Self {
+            shift: false,
+            load_store: LoadStore::PastLast,
+            jump: false,
+            vf_reset: false,
+            clipping: false,
+            display_wait: false,
+            index_overflow: false,
+            super_chip: true,
+            xo_chip: true,
+        }
+    }
+
+    /// XO-CHIP programs get the whole 16 bit address space.
+    pub fn memory_size(&self) -> usize {
+        if self.xo_chip {
+            0x10000
+        } else {
+            0x1000
+        }
+    }
+
+    /// Packs the flags into a word, one bit each. load_store takes bit 1 for moving I at
+    /// all and bit 7 for CHIP-48's shorter move, super_chip and index_overflow came
+    /// later and got bits 8 and 9.
+    pub fn to_bits(self) -> u16 {
+        (self.shift as u16)
+            | ((self.load_store != LoadStore::Unchanged) as u16) << 1
+            | (self.jump as u16) << 2
+            | (self.vf_reset as u16) << 3
+            | (self.clipping as u16) << 4
+            | (self.display_wait as u16) << 5
+            | (self.xo_chip as u16) << 6
+            | ((self.load_store == LoadStore::Last) as u16) << 7
+            | (self.super_chip as u16) << 8
+            | (self.index_overflow as u16) << 9
+    }
+
+    pub fn from_bits(bits: u16) -> Self {
+        Self {
+            shift: bits & 1 != 0,
+            load_store: match (bits & (1 << 1) != 0, bits & (1 << 7) != 0) {
+                (false, _) => LoadStore::Unchanged,
+                (true, false) => LoadStore::PastLast,
+                (true, true) => LoadStore::Last,
+            },
+            jump: bits & (1 << 2) != 0,
+            vf_reset: bits & (1 << 3) != 0,
+            clipping: bits & (1 << 4) != 0,
+            display_wait: bits & (1 << 5) != 0,
+            index_overflow: bits & (1 << 9) != 0,
+            super_chip: bits & (1 << 8) != 0,
+            xo_chip: bits & (1 << 6) != 0,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "modern" => Some(Self::modern()),
+            "vip" | "cosmac" | "chip8" => Some(Self::vip()),
+            "chip48" => Some(Self::chip48()),
+            "schip" | "superchip" => Some(Self::schip()),
+            "xochip" | "xo-chip" => Some(Self::xochip()),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self::modern()
+    }
+}
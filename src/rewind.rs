@@ -0,0 +1,340 @@
+//! A ring buffer of machine snapshots for playing a game backwards and for stepping
+//! back in the debugger.
+//!
+//! Only the newest snapshot is kept whole. Every older one is stored as the XOR of
+//! itself with the snapshot after it, run length encoded. From one frame to the next
+//! most of memory and the screen stay the same, so a snapshot usually takes a few
+//! dozen bytes instead of a few kilobytes.
+
+use crate::chip8::CHIP8;
+
+use std::collections::VecDeque;
+
+/// How far back the window's rewind key goes by default.
+pub const DEFAULT_SECONDS: u32 = 10;
+
+// A snapshot, relative to the one after it.
+struct Entry {
+    delta: Vec<u8>,
+    full: bool, // The sizes differed (the screen changed resolution), delta is the whole state
+    keypad: [bool; 16],
+    cycles: u64,
+}
+
+pub struct Rewind {
+    head: Option<Vec<u8>>, // The newest snapshot
+    head_keypad: [bool; 16],
+    head_cycles: u64,
+    history: VecDeque<Entry>, // Oldest first
+    capacity: usize,
+}
+
+impl Rewind {
+    /// Keeps up to capacity snapshots. Taking one per frame, that's capacity / 60 seconds.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            head: None,
+            head_keypad: [false; 16],
+            head_cycles: 0,
+            history: VecDeque::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.history.len() + self.head.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    pub fn clear(&mut self) {
+        self.head = None;
+        self.history.clear();
+    }
+
+    /// Takes a snapshot of the machine, along with the keypad it's about to run with so
+    /// the instructions after it can be replayed. Taking another one before the machine
+    /// has moved on only updates the keypad.
+    pub fn push(&mut self, cpu: &CHIP8, keypad: [bool; 16]) {
+        let state = cpu.save_state();
+
+        if let Some(head) = self.head.take() {
+            if cpu.cycles() != self.head_cycles {
+                let full = head.len() != state.len();
+                let delta = if full { compress(&head) } else { compress(&xor(&head, &state)) };
+
+                self.history.push_back(Entry { delta, full, keypad: self.head_keypad, cycles: self.head_cycles });
+
+                while self.history.len() >= self.capacity {
+                    self.history.pop_front();
+                }
+            }
+        }
+
+        self.head = Some(state);
+        self.head_keypad = keypad;
+        self.head_cycles = cpu.cycles();
+    }
+
+    /// Puts the machine back at the newest snapshot and forgets it, so the next call goes
+    /// back one more. Returns false once the buffer is empty.
+    pub fn pop(&mut self, cpu: &mut CHIP8) -> bool {
+        if let Some(head) = &self.head {
+            cpu.load_state(head).expect("rewind snapshots are always valid");
+        } else {
+            return false;
+        }
+
+        self.drop_head();
+        true
+    }
+
+    /// Takes the machine back by one tick: restores the last snapshot before it and
+    /// replays the ticks since then. Returns false if that's further back than the buffer goes.
+    pub fn step_back(&mut self, cpu: &mut CHIP8) -> bool {
+        let target = match cpu.cycles().checked_sub(1) {
+            Some(target) => target,
+            None => return false,
+        };
+
+        while self.head.is_some() && self.head_cycles > target {
+            self.drop_head();
+        }
+
+        let head = match &self.head {
+            Some(head) => head,
+            None => return false,
+        };
+
+        cpu.load_state(head).expect("rewind snapshots are always valid");
+
+        // Frames are ended as soon as they're complete, the same way the frontends do it.
+        // The hooks saw these ticks the first time round, they shouldn't get them twice.
+        let keypad = self.head_keypad;
+        cpu.without_hooks(|cpu| {
+            while cpu.cycles() < target {
+                if cpu.frame_complete() {
+                    cpu.end_frame();
+                }
+
+                if cpu.tick(keypad).is_err() {
+                    break;
+                }
+            }
+        });
+
+        if cpu.frame_complete() {
+            cpu.end_frame();
+        }
+
+        true
+    }
+
+    fn drop_head(&mut self) {
+        let head = self.head.take();
+
+        if let (Some(head), Some(entry)) = (head, self.history.pop_back()) {
+            let delta = decompress(&entry.delta);
+            let previous = if entry.full { delta } else { xor(&head, &delta) };
+
+            self.head = Some(previous);
+            self.head_keypad = entry.keypad;
+            self.head_cycles = entry.cycles;
+        }
+    }
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b).map(|(x, y)| x ^ y).collect()
+}
+
+// Alternating runs: a count of zero bytes, then a count of literal bytes followed by
+// the bytes themselves. Counts are LEB128 varints.
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let zeros = data[pos..].iter().take_while(|&&byte| byte == 0).count();
+        pos += zeros;
+
+        // A single zero between two literals costs more to encode as a run than to copy.
+        let literal_end = (pos..data.len())
+            .find(|&idx| data[idx] == 0 && data.get(idx + 1).is_none_or(|&next| next == 0))
+            .unwrap_or(data.len());
+
+        write_varint(&mut out, zeros);
+        write_varint(&mut out, literal_end - pos);
+        out.extend_from_slice(&data[pos..literal_end]);
+        pos = literal_end;
+    }
+
+    out
+}
+
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let zeros = read_varint(data, &mut pos);
+        out.resize(out.len() + zeros, 0);
+
+        let literal = read_varint(data, &mut pos);
+        out.extend_from_slice(&data[pos..pos + literal]);
+        pos += literal;
+    }
+
+    out
+}
+
+fn write_varint(out: &mut Vec<u8>, mut val: usize) {
+    while val >= 0x80 {
+        out.push(val as u8 | 0x80);
+        val >>= 7;
+    }
+    out.push(val as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut val = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        val |= ((byte & 0x7F) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return val;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Quirks;
+
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // Counts up in V0 and stores it through I, so memory changes every few ticks.
+    const ROM: [u8; 8] = [0xA3, 0x00, 0x70, 0x01, 0xF0, 0x55, 0x12, 0x02];
+
+    fn machine() -> CHIP8 {
+        CHIP8::new(ROM.to_vec(), Quirks::default()).unwrap()
+    }
+
+    #[test]
+    fn compress_round_trips() {
+        let mut long = vec![0; 300];
+        long.extend((0..=255).cycle().take(1000));
+        long.extend(vec![0; 5]);
+
+        let inputs: [&[u8]; 6] = [&[], &[0; 10], &[1, 2, 3], &[1, 0, 2, 0, 0, 3], &[0, 0, 7, 0], &long];
+        for input in inputs.iter() {
+            assert_eq!(decompress(&compress(input)), *input);
+        }
+    }
+
+    #[test]
+    fn compress_shrinks_small_deltas() {
+        let mut delta = vec![0; 4096];
+        delta[0x300] = 1;
+        delta[0x800] = 0xFF;
+
+        let compressed = compress(&delta);
+        assert!(compressed.len() < 16, "{} bytes", compressed.len());
+        assert_eq!(decompress(&compressed), delta);
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        let mut out = Vec::new();
+        for &val in [0, 1, 0x7F, 0x80, 0x3FFF, 0x4000, 1 << 20].iter() {
+            write_varint(&mut out, val);
+        }
+
+        let mut pos = 0;
+        for &val in [0, 1, 0x7F, 0x80, 0x3FFF, 0x4000, 1 << 20].iter() {
+            assert_eq!(read_varint(&out, &mut pos), val);
+        }
+        assert_eq!(pos, out.len());
+    }
+
+    #[test]
+    fn pop_goes_back_frame_by_frame() {
+        let mut cpu = machine();
+        let mut rewind = Rewind::new(100);
+        let mut states = Vec::new();
+
+        for _ in 0..10 {
+            states.push(cpu.save_state());
+            rewind.push(&cpu, [false; 16]);
+            cpu.run_frame([false; 16]).unwrap();
+        }
+        assert_eq!(rewind.len(), 10);
+
+        for state in states.iter().rev() {
+            assert!(rewind.pop(&mut cpu));
+            assert_eq!(&cpu.save_state(), state);
+        }
+        assert!(!rewind.pop(&mut cpu));
+        assert!(rewind.is_empty());
+    }
+
+    #[test]
+    fn keeps_only_capacity_snapshots() {
+        let mut cpu = machine();
+        let mut rewind = Rewind::new(4);
+
+        for _ in 0..10 {
+            rewind.push(&cpu, [false; 16]);
+            cpu.run_frame([false; 16]).unwrap();
+        }
+        assert_eq!(rewind.len(), 4);
+    }
+
+    #[test]
+    fn step_back_undoes_one_tick() {
+        let mut cpu = machine();
+        let mut rewind = Rewind::new(100);
+        let mut states = Vec::new();
+
+        for _ in 0..3 {
+            rewind.push(&cpu, [false; 16]);
+            cpu.run_frame([false; 16]).unwrap();
+        }
+        for _ in 0..5 {
+            states.push(cpu.save_state());
+            cpu.tick([false; 16]).unwrap();
+        }
+
+        for state in states.iter().rev() {
+            assert!(rewind.step_back(&mut cpu));
+            assert_eq!(&cpu.save_state(), state);
+        }
+    }
+
+    #[test]
+    fn step_back_replays_without_hooks() {
+        let mut cpu = machine();
+        let mut rewind = Rewind::new(100);
+        rewind.push(&cpu, [false; 16]);
+        cpu.run_frame([false; 16]).unwrap();
+
+        let executed = Rc::new(Cell::new(0));
+        let counter = Rc::clone(&executed);
+        cpu.set_instruction_hook(move |_| counter.set(counter.get() + 1));
+
+        assert!(rewind.step_back(&mut cpu));
+        assert_eq!(executed.get(), 0);
+
+        cpu.tick([false; 16]).unwrap();
+        assert_eq!(executed.get(), 1);
+    }
+}
@@ -0,0 +1,42 @@
+/// The random number generator behind Cxkk. It's owned by the machine and seeded
+/// explicitly, so the same ROM with the same seed and inputs always does the same
+/// thing. The algorithm is xorshift64*, the seed is run through splitmix64 first so
+/// that small or similar seeds still give unrelated sequences.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+
+        Self::from_state(z)
+    }
+
+    /// xorshift gets stuck on zero, so that one state is nudged away.
+    pub fn from_state(state: u64) -> Self {
+        Self {
+            state: if state == 0 { 0x9E37_79B9_7F4A_7C15 } else { state },
+        }
+    }
+
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        // The high bits are the best ones.
+        (self.next_u64() >> 56) as u8
+    }
+}
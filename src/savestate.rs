@@ -0,0 +1,240 @@
+use std::fmt;
+
+/// Save state files start with this magic and a version number. The version goes up
+/// whenever the layout changes, so an old file is rejected instead of misread.
+pub const MAGIC: &[u8; 4] = b"C8ST";
+pub const VERSION: u16 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::BadMagic => write!(f, "not a Chipper 8 save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "save state version {} is not supported (expected {})", version, VERSION)
+            }
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::Corrupt(what) => write!(f, "save state is corrupt ({})", what),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// All values are stored little endian.
+pub(crate) struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut writer = Self { data: Vec::new() };
+        writer.bytes(MAGIC);
+        writer.u16(VERSION);
+        writer
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.data.push(val);
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.u8(val as u8);
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub(crate) struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, StateError> {
+        let mut reader = Self { data, pos: 0 };
+
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        Ok(reader)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupt("invalid flag")),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() - self.pos < len {
+            return Err(StateError::Truncated);
+        }
+
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Trailing garbage means the file wasn't written by the version we think it was.
+    pub fn finish(self) -> Result<(), StateError> {
+        if self.pos == self.data.len() {
+            Ok(())
+        } else {
+            Err(StateError::Corrupt("trailing data"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Quirks, CHIP8};
+
+    // Draws a digit, calls a subroutine and keeps the RNG and delay timer busy.
+    const ROM: [u8; 18] = [
+        0x60, 0x05, 0xF0, 0x15, 0xC1, 0xFF, 0xA0, 0x00, 0xD0, 0x15,
+        0x22, 0x0E, 0x12, 0x04, 0x71, 0x01, 0x00, 0xEE,
+    ];
+
+    fn machine() -> CHIP8 {
+        let mut cpu = CHIP8::new(ROM.to_vec(), Quirks::default()).unwrap();
+        for _ in 0..3 {
+            cpu.run_frame([false; 16]).unwrap();
+        }
+        // Stop in the middle of a frame, inside the subroutine.
+        for _ in 0..4 {
+            cpu.tick([false; 16]).unwrap();
+        }
+        cpu
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let mut w = StateWriter::new();
+        w.u8(0xAB);
+        w.bool(true);
+        w.u16(0x1234);
+        w.u32(0xDEAD_BEEF);
+        w.u64(u64::MAX - 1);
+        w.bytes(b"xyz");
+        let data = w.finish();
+
+        let mut r = StateReader::new(&data).unwrap();
+        assert_eq!(r.u8(), Ok(0xAB));
+        assert_eq!(r.bool(), Ok(true));
+        assert_eq!(r.u16(), Ok(0x1234));
+        assert_eq!(r.u32(), Ok(0xDEAD_BEEF));
+        assert_eq!(r.u64(), Ok(u64::MAX - 1));
+        assert_eq!(r.bytes(3), Ok(&b"xyz"[..]));
+        assert_eq!(r.finish(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert_eq!(StateReader::new(b"NOPE\x04\x00").err(), Some(StateError::BadMagic));
+        assert_eq!(StateReader::new(b"C8ST\x01\x00").err(), Some(StateError::UnsupportedVersion(1)));
+        assert_eq!(StateReader::new(b"C8").err(), Some(StateError::Truncated));
+    }
+
+    #[test]
+    fn machine_round_trips() {
+        let cpu = machine();
+        let state = cpu.save_state();
+
+        // The quirks come from the state, not from the machine it's loaded into.
+        let mut loaded = CHIP8::new(Vec::new(), Quirks::vip()).unwrap();
+        loaded.load_state(&state).unwrap();
+        assert_eq!(loaded.save_state(), state);
+        assert_eq!(loaded.pc(), cpu.pc());
+        assert_eq!(loaded.screen().pixels(), cpu.screen().pixels());
+    }
+
+    #[test]
+    fn loaded_machine_runs_the_same() {
+        let mut cpu = machine();
+        let mut loaded = CHIP8::new(Vec::new(), Quirks::default()).unwrap();
+        loaded.load_state(&cpu.save_state()).unwrap();
+
+        for _ in 0..5 {
+            cpu.run_frame([false; 16]).unwrap();
+            loaded.run_frame([false; 16]).unwrap();
+        }
+        assert_eq!(loaded.save_state(), cpu.save_state());
+    }
+
+    #[test]
+    fn bad_state_leaves_the_machine_alone() {
+        let mut cpu = machine();
+        let before = cpu.save_state();
+
+        let mut truncated = CHIP8::new(ROM.to_vec(), Quirks::default()).unwrap().save_state();
+        truncated.pop();
+        assert_eq!(cpu.load_state(&truncated), Err(StateError::Truncated));
+
+        let mut trailing = before.clone();
+        trailing.push(0);
+        assert_eq!(cpu.load_state(&trailing), Err(StateError::Corrupt("trailing data")));
+
+        assert_eq!(cpu.save_state(), before);
+    }
+}
@@ -0,0 +1,122 @@
+//! The CHIP-8 display. Plain CHIP-8 programs only ever see the 64x32 low resolution
+//! mode, SUPER-CHIP programs can switch to 128x64 and back at any time.
+//!
+//! XO-CHIP adds a second bitplane, so every pixel is stored as a plane mask: bit 0
+//! is the first plane, bit 1 the second. Drawing, clearing and scrolling only touch
+//! the planes they are asked to, which lets a pixel take one of four colors.
+
+pub const LORES_WIDTH: usize = 64;
+pub const LORES_HEIGHT: usize = 32;
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+
+pub const PLANE_COUNT: usize = 2;
+pub const ALL_PLANES: u8 = 0b11;
+
+/// RGB colors for each combination of lit planes. Plain CHIP-8 and SUPER-CHIP
+/// programs only use the first plane, so they come out black and white.
+pub const PALETTE: [(u8, u8, u8); 4] = [
+    (0, 0, 0),       // Off
+    (255, 255, 255), // Plane 1
+    (255, 102, 0),   // Plane 2
+    (102, 34, 0),    // Both planes
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Screen {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>, // Row major, width * height plane masks
+}
+
+impl Screen {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; width * height],
+        }
+    }
+
+    /// Rebuilds a screen from the plane masks returned by pixels().
+    pub fn from_pixels(width: usize, height: usize, pixels: Vec<u8>) -> Option<Self> {
+        if pixels.len() == width * height {
+            Some(Self { width, height, pixels })
+        } else {
+            None
+        }
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Returns the planes lit at (x, y). On a single plane display that's 0 or 1.
+    pub fn get(&self, x: usize, y: usize) -> u8 {
+        self.pixels[y * self.width + x]
+    }
+
+    /// XORs a pixel of one plane onto the screen. Returns true if a lit pixel got turned off.
+    pub fn flip(&mut self, x: usize, y: usize, plane: u8) -> bool {
+        let pixel = &mut self.pixels[y * self.width + x];
+        let collision = *pixel & plane != 0;
+        *pixel ^= plane;
+        collision
+    }
+
+    pub fn clear(&mut self, planes: u8) {
+        for pixel in self.pixels.iter_mut() {
+            *pixel &= !planes;
+        }
+    }
+
+    /// Switching resolution throws away whatever was on screen, on every plane.
+    pub fn resize(&mut self, width: usize, height: usize) {
+        *self = Self::new(width, height);
+    }
+
+    pub fn scroll_down(&mut self, rows: usize, planes: u8) {
+        self.shift(0, rows as isize, planes);
+    }
+
+    pub fn scroll_up(&mut self, rows: usize, planes: u8) {
+        self.shift(0, -(rows as isize), planes);
+    }
+
+    pub fn scroll_left(&mut self, cols: usize, planes: u8) {
+        self.shift(-(cols as isize), 0, planes);
+    }
+
+    pub fn scroll_right(&mut self, cols: usize, planes: u8) {
+        self.shift(cols as isize, 0, planes);
+    }
+
+    // Moves the selected planes by (dx, dy). Pixels scrolled in from outside are blank.
+    fn shift(&mut self, dx: isize, dy: isize, planes: u8) {
+        let old = self.pixels.clone();
+        let (width, height) = (self.width as isize, self.height as isize);
+
+        for y in 0..height {
+            for x in 0..width {
+                let (src_x, src_y) = (x - dx, y - dy);
+
+                let moved = if src_x >= 0 && src_x < width && src_y >= 0 && src_y < height {
+                    old[(src_y * width + src_x) as usize] & planes
+                } else {
+                    0
+                };
+
+                let idx = (y * width + x) as usize;
+                self.pixels[idx] = (old[idx] & !planes) | moved;
+            }
+        }
+    }
+}
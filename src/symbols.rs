@@ -0,0 +1,133 @@
+//! Symbol files: the labels of an assembled program with their addresses, so the
+//! debugger, the trace and the disassembler can say `draw_player+0x4` instead of
+//! `0x2a8`.
+//!
+//! The assembler writes one next to the ROM (`game.sym` for `game.ch8`):
+//!
+//! ```text
+//! label 0x0202 ball
+//! label 0x0206 main
+//! breakpoint 0x0220 here
+//! ```
+//!
+//! Breakpoints come from Octo's `:breakpoint` and are set when the ROM is debugged.
+//! Lines starting with `#` are comments.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Symbols {
+    labels: BTreeMap<u16, String>, // When an address has more than one name, the first one
+    addrs: BTreeMap<String, u16>,
+    breakpoints: Vec<(u16, String)>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads a symbol file written by to_string.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut symbols = Self::new();
+
+        let lines = text.lines()
+            .enumerate()
+            .map(|(idx, line)| (idx + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+
+        for (num, line) in lines {
+            let (kind, addr, name) = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                &[kind, addr, name] => (kind, addr, name),
+                _ => return Err(format!("Invalid symbol '{}' on line {}", line, num)),
+            };
+
+            let digits = addr.trim_start_matches("0x");
+            let addr = u16::from_str_radix(digits, 16)
+                .map_err(|_| format!("Invalid address '{}' on line {}", addr, num))?;
+
+            match kind {
+                "label" => symbols.add_label(name, addr),
+                "breakpoint" => symbols.add_breakpoint(name, addr),
+                _ => return Err(format!("Unknown symbol kind '{}' on line {}", kind, num)),
+            }
+        }
+
+        Ok(symbols)
+    }
+
+    pub fn add_label(&mut self, name: &str, addr: u16) {
+        self.labels.entry(addr).or_insert_with(|| name.to_string());
+        self.addrs.insert(name.to_string(), addr);
+    }
+
+    pub fn add_breakpoint(&mut self, name: &str, addr: u16) {
+        self.breakpoints.push((addr, name.to_string()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty() && self.breakpoints.is_empty()
+    }
+
+    /// Every address with a name, lowest first.
+    pub fn labels(&self) -> impl Iterator<Item = (u16, &str)> + '_ {
+        self.labels.iter().map(|(&addr, name)| (addr, name.as_str()))
+    }
+
+    pub fn breakpoints(&self) -> &[(u16, String)] {
+        &self.breakpoints
+    }
+
+    /// The name of exactly this address.
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(String::as_str)
+    }
+
+    /// The address as an offset from the closest label before it, like `draw+0x4`.
+    /// None if there's no label before it.
+    pub fn lookup(&self, addr: u16) -> Option<String> {
+        let (&start, name) = self.labels.range(..=addr).next_back()?;
+
+        if start == addr {
+            Some(name.clone())
+        } else {
+            Some(format!("{}+{:#x}", name, addr - start))
+        }
+    }
+
+    /// lookup, or the plain address when there's no label before it.
+    pub fn describe(&self, addr: u16) -> String {
+        self.lookup(addr).unwrap_or_else(|| format!("{:#05x}", addr))
+    }
+
+    /// The address of `name` or `name+offset`, the offset in hex.
+    pub fn resolve(&self, text: &str) -> Option<u16> {
+        let (name, offset) = match text.split_once('+') {
+            Some((name, offset)) => {
+                let digits = offset.trim_start_matches("0x").trim_start_matches("0X");
+                (name, u16::from_str_radix(digits, 16).ok()?)
+            }
+            None => (text, 0),
+        };
+
+        self.addrs.get(name)?.checked_add(offset)
+    }
+}
+
+impl fmt::Display for Symbols {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut labels: Vec<(&u16, &String)> = self.addrs.iter().map(|(name, addr)| (addr, name)).collect();
+        labels.sort();
+
+        for (addr, name) in labels {
+            writeln!(f, "label {:#06x} {}", addr, name)?;
+        }
+
+        for (addr, name) in &self.breakpoints {
+            writeln!(f, "breakpoint {:#06x} {}", addr, name)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,508 @@
+//! Instruction traces: one record per executed instruction, with its address, opcode,
+//! mnemonic and the registers it changed.
+//!
+//! The text format is one line per instruction, in fixed columns so it can be diffed
+//! against traces from other emulators (cut away the frame and cycle columns if they
+//! count differently):
+//!
+//! ```text
+//! #frame cycle    addr op    instruction            changes
+//! 000003 00000031 0202 7001  ADD V0, #01            V0:04->05
+//! ```
+//!
+//! With symbols from the assembler every line inside a label also gets the name, like
+//! `  ; draw+0x4` at the end.
+//!
+//! The binary format holds the same records in a fraction of the space. After the
+//! magic `C8TR` and a little endian u16 version, every record is: frame (u32), cycle
+//! (u64), address (u16), opcode (u16), then a u32 mask of the changed registers (bits
+//! 0-15 for V0-VF, then I, SP, DT and ST) followed by the old and new value of each,
+//! a u16 each for I and a byte each for the rest.
+
+use crate::chip8::{Executed, RegisterFile, CHIP8};
+use crate::condition::Register;
+use crate::disassembler::Disassembler;
+use crate::instruction::{Class, Instruction};
+use crate::symbols::Symbols;
+
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+pub const MAGIC: &[u8; 4] = b"C8TR";
+pub const VERSION: u16 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    Text,
+    Binary,
+}
+
+impl TraceFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "text" | "txt" => Some(TraceFormat::Text),
+            "binary" | "bin" => Some(TraceFormat::Binary),
+            _ => None,
+        }
+    }
+
+    /// Guesses the format from a file name, falling back to text.
+    pub fn from_path(path: &str) -> Self {
+        match path.rsplit('.').next() {
+            Some("bin") | Some("c8t") => TraceFormat::Binary,
+            _ => TraceFormat::Text,
+        }
+    }
+}
+
+/// Which instructions make it into the trace. Every part that's left empty lets
+/// everything through.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    pub ranges: Vec<(u16, u16)>, // Inclusive address ranges
+    pub classes: Vec<Class>,
+    pub frames: Option<(u64, u64)>, // Inclusive
+}
+
+impl TraceFilter {
+    pub fn matches(&self, executed: &Executed) -> bool {
+        let in_range = self.ranges.is_empty() ||
+            self.ranges.iter().any(|&(start, end)| (start..=end).contains(&executed.addr));
+
+        let in_class = self.classes.is_empty() ||
+            Instruction::decode(executed.opcode).is_ok_and(|instruction| self.classes.contains(&instruction.class()));
+
+        let in_frames = self.frames.is_none_or(|(start, end)| (start..=end).contains(&executed.frame));
+
+        in_range && in_class && in_frames
+    }
+}
+
+/// A register an instruction changed, with its value before and after.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Change {
+    pub reg: Register,
+    pub old: u16,
+    pub new: u16,
+}
+
+/// One line of a trace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub frame: u64,
+    pub cycle: u64,
+    pub addr: u16,
+    pub opcode: u16,
+    pub changes: Vec<Change>,
+}
+
+impl TraceRecord {
+    pub fn new(executed: &Executed) -> Self {
+        let changes = TRACED.iter()
+            .filter_map(|&reg| {
+                let old = value(&executed.before, reg);
+                let new = value(&executed.after, reg);
+                (old != new).then_some(Change { reg, old, new })
+            })
+            .collect();
+
+        Self {
+            frame: executed.frame,
+            cycle: executed.cycle,
+            addr: executed.addr,
+            opcode: executed.opcode,
+            changes,
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mnemonic = match Instruction::decode(self.opcode) {
+            Ok(instruction) => Disassembler::format(instruction),
+            Err(_) => String::from("???"),
+        };
+        // Only the instruction, not the explanation after it.
+        let mnemonic = mnemonic.split(" ;").next().unwrap_or_default();
+
+        let changes: Vec<String> = self.changes.iter()
+            .map(|change| match change.reg {
+                Register::I => format!("{}:{:04X}->{:04X}", change.reg, change.old, change.new),
+                _ => format!("{}:{:02X}->{:02X}", change.reg, change.old, change.new),
+            })
+            .collect();
+
+        let line = format!(
+            "{:06} {:08} {:04X} {:04X}  {:<22} {}",
+            self.frame, self.cycle, self.addr, self.opcode, mnemonic, changes.join(" ")
+        );
+
+        line.trim_end().to_string()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(24);
+        out.extend_from_slice(&(self.frame as u32).to_le_bytes());
+        out.extend_from_slice(&self.cycle.to_le_bytes());
+        out.extend_from_slice(&self.addr.to_le_bytes());
+        out.extend_from_slice(&self.opcode.to_le_bytes());
+
+        let mask = self.changes.iter()
+            .fold(0u32, |mask, change| mask | 1 << bit(change.reg));
+        out.extend_from_slice(&mask.to_le_bytes());
+
+        for change in &self.changes {
+            match change.reg {
+                Register::I => {
+                    out.extend_from_slice(&change.old.to_le_bytes());
+                    out.extend_from_slice(&change.new.to_le_bytes());
+                }
+                _ => out.extend_from_slice(&[change.old as u8, change.new as u8]),
+            }
+        }
+
+        out
+    }
+
+    /// Reads back a line written by to_text. A symbol name after a `;` is ignored.
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid trace line '{}'", line);
+        let line = line.split(';').next().unwrap_or_default();
+        let mut words = line.split_whitespace();
+
+        let mut number = |radix| {
+            words.next().and_then(|word| u64::from_str_radix(word, radix).ok()).ok_or_else(invalid)
+        };
+
+        let frame = number(10)?;
+        let cycle = number(10)?;
+        let addr = number(16)? as u16;
+        let opcode = number(16)? as u16;
+
+        // The mnemonic is made of several words, the changes are the ones with an arrow.
+        let changes = line.split_whitespace()
+            .filter(|word| word.contains("->"))
+            .map(|word| {
+                let (reg, values) = word.split_once(':')?;
+                let (old, new) = values.split_once("->")?;
+
+                Some(Change {
+                    reg: Register::from_name(reg)?,
+                    old: u16::from_str_radix(old, 16).ok()?,
+                    new: u16::from_str_radix(new, 16).ok()?,
+                })
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+
+        Ok(Self { frame, cycle, addr, opcode, changes })
+    }
+
+    // One record of a binary trace, None if it's cut short.
+    fn from_bytes(data: &[u8], pos: &mut usize) -> Option<Self> {
+        let mut take = |len: usize| {
+            let bytes = data.get(*pos..*pos + len)?;
+            *pos += len;
+            Some(bytes)
+        };
+
+        let frame = u32::from_le_bytes(take(4)?.try_into().ok()?) as u64;
+        let cycle = u64::from_le_bytes(take(8)?.try_into().ok()?);
+        let addr = u16::from_le_bytes(take(2)?.try_into().ok()?);
+        let opcode = u16::from_le_bytes(take(2)?.try_into().ok()?);
+        let mask = u32::from_le_bytes(take(4)?.try_into().ok()?);
+
+        let mut changes = Vec::new();
+        let changed = TRACED.iter().enumerate().filter(|&(bit, _)| mask & 1 << bit != 0);
+
+        for (_, &reg) in changed {
+            let change = match reg {
+                Register::I => {
+                    let values = take(4)?;
+                    Change {
+                        reg,
+                        old: u16::from_le_bytes([values[0], values[1]]),
+                        new: u16::from_le_bytes([values[2], values[3]]),
+                    }
+                }
+                _ => {
+                    let values = take(2)?;
+                    Change { reg, old: values[0] as u16, new: values[1] as u16 }
+                }
+            };
+
+            changes.push(change);
+        }
+
+        Some(Self { frame, cycle, addr, opcode, changes })
+    }
+}
+
+/// Reads a whole trace file, text or binary.
+pub fn read(data: &[u8]) -> Result<Vec<TraceRecord>, String> {
+    if let Some(rest) = data.strip_prefix(MAGIC) {
+        let version = rest.get(..2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+        if version != Some(VERSION) {
+            return Err(String::from("Unsupported binary trace version"));
+        }
+
+        let mut records = Vec::new();
+        let mut pos = MAGIC.len() + 2;
+
+        while pos < data.len() {
+            let record = TraceRecord::from_bytes(data, &mut pos).ok_or("The binary trace is truncated")?;
+            records.push(record);
+        }
+
+        return Ok(records);
+    }
+
+    let text = std::str::from_utf8(data).map_err(|_| String::from("Not a trace file"))?;
+
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(TraceRecord::parse)
+        .collect()
+}
+
+// The registers a trace keeps track of, in mask bit order.
+const TRACED: [Register; 20] = [
+    Register::V(0x0), Register::V(0x1), Register::V(0x2), Register::V(0x3),
+    Register::V(0x4), Register::V(0x5), Register::V(0x6), Register::V(0x7),
+    Register::V(0x8), Register::V(0x9), Register::V(0xA), Register::V(0xB),
+    Register::V(0xC), Register::V(0xD), Register::V(0xE), Register::V(0xF),
+    Register::I, Register::Sp, Register::Dt, Register::St,
+];
+
+fn bit(reg: Register) -> u32 {
+    TRACED.iter().position(|&traced| traced == reg).unwrap_or(0) as u32
+}
+
+fn value(regs: &RegisterFile, reg: Register) -> u16 {
+    match reg {
+        Register::V(x) => regs.v[x as usize & 0xF] as u16,
+        Register::I => regs.i,
+        Register::Sp => regs.sp as u16,
+        Register::Dt => regs.dt as u16,
+        Register::St => regs.st as u16,
+        Register::Pc => 0,
+    }
+}
+
+struct Output {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    symbols: Symbols,
+    records: u64,
+    error: Option<io::Error>, // The first write that failed, nothing is written after it
+}
+
+impl Output {
+    fn write(&mut self, bytes: &[u8]) {
+        if self.error.is_none() {
+            if let Err(err) = self.out.write_all(bytes) {
+                self.error = Some(err);
+            }
+        }
+    }
+}
+
+/// Writes a trace of the machine it's attached to.
+pub struct Tracer {
+    output: Rc<RefCell<Output>>,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>, format: TraceFormat, filter: TraceFilter) -> Self {
+        let mut output = Output { out, format, filter, symbols: Symbols::new(), records: 0, error: None };
+
+        match format {
+            TraceFormat::Text => output.write(b"#frame cycle    addr op    instruction            changes\n"),
+            TraceFormat::Binary => {
+                output.write(MAGIC);
+                output.write(&VERSION.to_le_bytes());
+            }
+        }
+
+        Self { output: Rc::new(RefCell::new(output)) }
+    }
+
+    /// Names the addresses in the text format.
+    pub fn set_symbols(&self, symbols: Symbols) {
+        self.output.borrow_mut().symbols = symbols;
+    }
+
+    /// Starts tracing every instruction the machine runs. Takes over its instruction hook.
+    pub fn attach(&self, cpu: &mut CHIP8) {
+        let output = Rc::clone(&self.output);
+
+        cpu.set_instruction_hook(move |executed| {
+            let mut output = output.borrow_mut();
+
+            if !output.filter.matches(executed) {
+                return;
+            }
+
+            let record = TraceRecord::new(executed);
+            let bytes = match output.format {
+                TraceFormat::Text => match output.symbols.lookup(record.addr) {
+                    Some(name) => format!("{}  ; {}\n", record.to_text(), name).into_bytes(),
+                    None => format!("{}\n", record.to_text()).into_bytes(),
+                },
+                TraceFormat::Binary => record.to_bytes(),
+            };
+
+            output.write(&bytes);
+            output.records += 1;
+        });
+    }
+
+    /// Flushes the trace. Returns how many instructions it holds, or the first error
+    /// writing it.
+    pub fn finish(self) -> io::Result<u64> {
+        let mut output = self.output.borrow_mut();
+
+        if let Some(err) = output.error.take() {
+            return Err(err);
+        }
+
+        output.out.flush()?;
+        Ok(output.records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Quirks;
+
+    // A writer the test can still read after handing it to a Tracer.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Sets I and the timers, then counts up V0 in a loop.
+    const ROM: [u8; 10] = [0xA2, 0x34, 0x60, 0x80, 0xF0, 0x15, 0x70, 0x01, 0x12, 0x06];
+
+    fn trace(format: TraceFormat, filter: TraceFilter) -> (Vec<u8>, u64) {
+        let buf = Shared::default();
+        let tracer = Tracer::new(Box::new(buf.clone()), format, filter);
+        let mut cpu = CHIP8::new(ROM.to_vec(), Quirks::default()).unwrap();
+        tracer.attach(&mut cpu);
+
+        for _ in 0..3 {
+            cpu.run_frame([false; 16]).unwrap();
+        }
+
+        let count = tracer.finish().unwrap();
+        let data = buf.0.borrow().clone();
+        (data, count)
+    }
+
+    #[test]
+    fn text_and_binary_agree() {
+        let (text, count) = trace(TraceFormat::Text, TraceFilter::default());
+        let (binary, _) = trace(TraceFormat::Binary, TraceFilter::default());
+
+        let records = read(&text).unwrap();
+        assert_eq!(records.len() as u64, count);
+        assert_eq!(read(&binary).unwrap(), records);
+
+        assert_eq!(records[0].addr, 0x200);
+        assert_eq!(records[0].changes, vec![Change { reg: Register::I, old: 0, new: 0x234 }]);
+        assert_eq!(records[1].changes, vec![Change { reg: Register::V(0), old: 0, new: 0x80 }]);
+    }
+
+    #[test]
+    fn text_lines_round_trip() {
+        let record = TraceRecord {
+            frame: 3,
+            cycle: 31,
+            addr: 0x202,
+            opcode: 0x7001,
+            changes: vec![Change { reg: Register::V(0), old: 4, new: 5 }],
+        };
+
+        let line = record.to_text();
+        assert_eq!(line, "000003 00000031 0202 7001  ADD V0, #01            V0:04->05");
+        assert_eq!(TraceRecord::parse(&line), Ok(record.clone()));
+        assert_eq!(TraceRecord::parse(&format!("{}  ; draw+0x2", line)), Ok(record));
+    }
+
+    #[test]
+    fn binary_records_round_trip() {
+        let record = TraceRecord {
+            frame: 1,
+            cycle: 12,
+            addr: 0x2F0,
+            opcode: 0xF21E,
+            changes: vec![
+                Change { reg: Register::V(0xF), old: 1, new: 0 },
+                Change { reg: Register::I, old: 0x0FFF, new: 0x1002 },
+            ],
+        };
+
+        let bytes = record.to_bytes();
+        let mut pos = 0;
+        assert_eq!(TraceRecord::from_bytes(&bytes, &mut pos), Some(record));
+        assert_eq!(pos, bytes.len());
+        assert_eq!(TraceRecord::from_bytes(&bytes[..bytes.len() - 1], &mut 0), None);
+    }
+
+    #[test]
+    fn rejects_broken_traces() {
+        let (binary, _) = trace(TraceFormat::Binary, TraceFilter::default());
+
+        assert_eq!(read(&binary[..binary.len() - 1]), Err(String::from("The binary trace is truncated")));
+        assert_eq!(read(b"C8TR\x09\x00"), Err(String::from("Unsupported binary trace version")));
+        assert!(read(b"not a trace").is_err());
+    }
+
+    #[test]
+    fn filters_instructions() {
+        let filter = TraceFilter { classes: vec![Class::Timer], ..Default::default() };
+        let (text, count) = trace(TraceFormat::Text, filter);
+        assert_eq!(count, 1);
+        assert_eq!(read(&text).unwrap()[0].opcode, 0xF015);
+
+        let filter = TraceFilter { ranges: vec![(0x206, 0x206)], frames: Some((1, 1)), ..Default::default() };
+        let (text, _) = trace(TraceFormat::Text, filter);
+        let records = read(&text).unwrap();
+        assert!(!records.is_empty());
+        assert!(records.iter().all(|record| record.addr == 0x206 && record.frame == 1));
+    }
+
+    #[test]
+    fn names_addresses_with_symbols() {
+        let buf = Shared::default();
+        let tracer = Tracer::new(Box::new(buf.clone()), TraceFormat::Text, TraceFilter::default());
+        let mut symbols = Symbols::new();
+        symbols.add_label("count", 0x206);
+        tracer.set_symbols(symbols);
+
+        let mut cpu = CHIP8::new(ROM.to_vec(), Quirks::default()).unwrap();
+        tracer.attach(&mut cpu);
+        for _ in 0..4 {
+            cpu.tick([false; 16]).unwrap();
+        }
+        tracer.finish().unwrap();
+
+        let text = String::from_utf8(buf.0.borrow().clone()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[1].contains(" 0200 A234 "));
+        assert!(!lines[1].contains(';'));
+        assert!(lines[4].ends_with("; count"));
+    }
+}
@@ -0,0 +1,89 @@
+// --watch: notices when the ROM, or the source it's assembled from, changes on disk
+// and builds the new program so the window can carry on with it. The file is polled a
+// few times a second, and only picked up once it stopped changing between two polls so
+// an editor that's halfway through saving doesn't get a truncated program loaded.
+use chipper8::Symbols;
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// A program built from the changed file.
+pub struct Program {
+    pub bytes: Vec<u8>,
+    pub symbols: Symbols,
+}
+
+pub struct Watcher {
+    rom_path: String,
+    source_path: Option<String>,  // Assembled into the ROM before it's read
+    symbols_path: Option<String>, // --symbols, when it isn't next to the ROM
+    modified: Option<SystemTime>, // The version that's running
+    settling: Option<SystemTime>, // A newer one, seen on the last poll
+    next_poll: Instant,
+}
+
+impl Watcher {
+    pub fn new(rom_path: &str, source_path: Option<&str>, symbols_path: Option<&str>) -> Self {
+        let mut watcher = Self {
+            rom_path: rom_path.to_string(),
+            source_path: source_path.map(str::to_string),
+            symbols_path: symbols_path.map(str::to_string),
+            modified: None,
+            settling: None,
+            next_poll: Instant::now() + POLL_INTERVAL,
+        };
+
+        watcher.modified = watcher.last_change();
+        watcher
+    }
+
+    // The file that decides when to reload.
+    pub fn path(&self) -> &str {
+        self.source_path.as_deref().unwrap_or(&self.rom_path)
+    }
+
+    fn last_change(&self) -> Option<SystemTime> {
+        fs::metadata(self.path()).and_then(|metadata| metadata.modified()).ok()
+    }
+
+    // The new program once the file changed, None most of the time. An error is for
+    // the user to fix, the next save tries again.
+    pub fn poll(&mut self) -> Option<Result<Program, String>> {
+        let now = Instant::now();
+        if now < self.next_poll {
+            return None;
+        }
+        self.next_poll = now + POLL_INTERVAL;
+
+        let modified = self.last_change();
+        if modified.is_none() || modified == self.modified {
+            return None;
+        }
+
+        if modified != self.settling {
+            self.settling = modified;
+            return None;
+        }
+
+        self.modified = modified;
+        Some(self.build())
+    }
+
+    fn build(&self) -> Result<Program, String> {
+        if let Some(source_path) = &self.source_path {
+            let symbols_path = self.symbols_path.clone()
+                .unwrap_or_else(|| Path::new(&self.rom_path).with_extension("sym").display().to_string());
+            let listing_path = Path::new(&self.rom_path).with_extension("lst").display().to_string();
+
+            crate::assemble_file(source_path, &self.rom_path, &listing_path, &symbols_path, false)?;
+        }
+
+        Ok(Program {
+            bytes: crate::read_rom(&self.rom_path)?,
+            symbols: crate::load_symbols(&self.rom_path, self.symbols_path.as_deref())?,
+        })
+    }
+}